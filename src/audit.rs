@@ -0,0 +1,321 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::rotating_file::RotatingFile;
+
+/// Settings for the audit log – a JSON-lines record of every connection and what came of it.
+///
+/// This is deliberately separate from the debug log: it's always written, regardless of log level.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct AuditConfiguration {
+    pub enabled: bool,
+    pub path: PathBuf,
+
+    /// Rotate once the file grows past this size. `0` disables size-based rotation.
+    pub max_size_bytes: u64,
+
+    /// Rotate once the file is older than this many days. `0` disables age-based rotation.
+    pub max_age_days: u32,
+
+    /// The number of rotated files to keep around.
+    pub max_files: u32,
+}
+
+impl Default for AuditConfiguration {
+    fn default() -> Self {
+        AuditConfiguration {
+            enabled: true,
+            path: default_audit_log_path(),
+            max_size_bytes: 10 * 1024 * 1024,
+            max_age_days: 30,
+            max_files: 5,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn default_audit_log_path() -> PathBuf {
+    PathBuf::from("/var/log/shutdown-on-lan.audit.log")
+}
+
+#[cfg(target_os = "macos")]
+fn default_audit_log_path() -> PathBuf {
+    PathBuf::from("/var/log/shutdownonlan.audit.log")
+}
+
+#[cfg(windows)]
+fn default_audit_log_path() -> PathBuf {
+    let program_data = std::env::var_os("ProgramData").unwrap_or_else(|| "C:\\ProgramData".into());
+
+    PathBuf::from(program_data)
+        .join("ShutdownOnLan")
+        .join("audit.log")
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Connection {
+        peer: SocketAddr,
+        local: SocketAddr,
+    },
+    Authentication {
+        peer: SocketAddr,
+        success: bool,
+    },
+    Action {
+        #[serde(flatten)]
+        origin: Origin,
+        action: String,
+        success: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Rejection {
+        #[serde(flatten)]
+        origin: Origin,
+        reason: String,
+    },
+    /// Someone at the machine postponed a pending action
+    Postponement {
+        #[serde(flatten)]
+        origin: Origin,
+        action: String,
+        minutes: u64,
+    },
+}
+
+/// Where a request to perform an action came from. Recorded as a `peer`, `schedule` or `idle`
+/// field.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Origin {
+    /// A remote client
+    Peer(SocketAddr),
+    /// The local scheduler, with the name of the entry
+    Schedule(String),
+    /// The idle monitor, with how many minutes the machine had been idle for
+    Idle(u64),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Peer(peer) => write!(f, "{peer}"),
+            Origin::Schedule(name) => write!(f, "schedule {name:?}"),
+            Origin::Idle(minutes) => write!(f, "idle for {minutes} minutes"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+pub struct AuditLog {
+    file: Option<Mutex<RotatingFile>>,
+}
+
+impl AuditLog {
+    /// Opens the audit log described by `configuration`. If the file can't be opened, the error is
+    /// logged and auditing is disabled rather than preventing the service from starting.
+    pub fn open(configuration: &AuditConfiguration) -> AuditLog {
+        if !configuration.enabled {
+            log::info!("Audit logging is disabled");
+            return AuditLog::disabled();
+        }
+
+        let max_age = match configuration.max_age_days {
+            0 => None,
+            days => Some(Duration::from_secs(u64::from(days) * 24 * 60 * 60)),
+        };
+
+        match RotatingFile::open(
+            &configuration.path,
+            configuration.max_size_bytes,
+            max_age,
+            configuration.max_files,
+        ) {
+            Ok(file) => {
+                log::info!("Writing audit log to {:?}", file.path());
+                AuditLog {
+                    file: Some(Mutex::new(file)),
+                }
+            }
+            Err(error) => {
+                log::error!(
+                    "Unable to open audit log at {:?} – auditing is disabled: {}",
+                    configuration.path,
+                    error
+                );
+                AuditLog::disabled()
+            }
+        }
+    }
+
+    pub fn disabled() -> AuditLog {
+        AuditLog { file: None }
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+
+        let line = match serde_json::to_string(&AuditRecord {
+            timestamp,
+            event: &event,
+        }) {
+            Ok(line) => line,
+            Err(error) => {
+                log::error!("Unable to serialize audit event {:?}: {}", event, error);
+                return;
+            }
+        };
+
+        let mut file = file.lock().unwrap_or_else(|error| error.into_inner());
+
+        if let Err(error) = file.write_line(&line) {
+            log::error!("Unable to write to audit log: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use tempfile::TempDir;
+
+    fn open(directory: &TempDir) -> (AuditLog, PathBuf) {
+        let path = directory.path().join("audit.log");
+        let configuration = AuditConfiguration {
+            path: path.clone(),
+            ..Default::default()
+        };
+
+        (AuditLog::open(&configuration), path)
+    }
+
+    /// Each line of the log, without its timestamp (which is checked separately)
+    fn records(path: &PathBuf) -> Vec<Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let mut record: Value = serde_json::from_str(line).unwrap();
+                let timestamp = record.as_object_mut().unwrap().remove("timestamp").unwrap();
+                assert!(OffsetDateTime::parse(timestamp.as_str().unwrap(), &Rfc3339).is_ok());
+                record
+            })
+            .collect()
+    }
+
+    #[test]
+    fn events_are_written_as_json_lines() {
+        let directory = TempDir::new().unwrap();
+        let (log, path) = open(&directory);
+        let peer: SocketAddr = "10.0.1.10:50000".parse().unwrap();
+
+        log.record(AuditEvent::Connection {
+            peer,
+            local: "10.0.1.100:53632".parse().unwrap(),
+        });
+        log.record(AuditEvent::Authentication {
+            peer,
+            success: false,
+        });
+
+        assert_eq!(
+            records(&path),
+            vec![
+                json!({"event": "connection", "peer": "10.0.1.10:50000", "local": "10.0.1.100:53632"}),
+                json!({"event": "authentication", "peer": "10.0.1.10:50000", "success": false}),
+            ]
+        );
+    }
+
+    #[test]
+    fn origins_are_recorded_as_their_own_field() {
+        let directory = TempDir::new().unwrap();
+        let (log, path) = open(&directory);
+
+        log.record(AuditEvent::Action {
+            origin: Origin::Peer("10.0.1.10:50000".parse().unwrap()),
+            action: "reboot".to_string(),
+            success: true,
+            error: None,
+        });
+        log.record(AuditEvent::Action {
+            origin: Origin::Schedule("Evening power-off".to_string()),
+            action: "shutdown".to_string(),
+            success: false,
+            error: Some("inhibited".to_string()),
+        });
+        log.record(AuditEvent::Rejection {
+            origin: Origin::Idle(30),
+            reason: "outside the maintenance windows".to_string(),
+        });
+        log.record(AuditEvent::Postponement {
+            origin: Origin::Peer("10.0.1.10:50000".parse().unwrap()),
+            action: "shutdown".to_string(),
+            minutes: 10,
+        });
+
+        assert_eq!(
+            records(&path),
+            vec![
+                json!({"event": "action", "peer": "10.0.1.10:50000", "action": "reboot", "success": true}),
+                json!({"event": "action", "schedule": "Evening power-off", "action": "shutdown", "success": false, "error": "inhibited"}),
+                json!({"event": "rejection", "idle": 30, "reason": "outside the maintenance windows"}),
+                json!({"event": "postponement", "peer": "10.0.1.10:50000", "action": "shutdown", "minutes": 10}),
+            ]
+        );
+    }
+
+    #[test]
+    fn disabled_logs_write_nothing() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("audit.log");
+        let log = AuditLog::open(&AuditConfiguration {
+            enabled: false,
+            path: path.clone(),
+            ..Default::default()
+        });
+
+        log.record(AuditEvent::Rejection {
+            origin: Origin::Idle(30),
+            reason: "test".to_string(),
+        });
+
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn logs_that_cant_be_opened_are_disabled() {
+        let directory = TempDir::new().unwrap();
+
+        // A directory can't be opened for appending
+        let log = AuditLog::open(&AuditConfiguration {
+            path: directory.path().to_path_buf(),
+            ..Default::default()
+        });
+
+        assert!(log.file.is_none());
+    }
+}
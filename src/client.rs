@@ -0,0 +1,392 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::power::PowerAction;
+use crate::protocol::{ErrorKind, Request, Response, MIN_HEARTBEAT_INTERVAL};
+use crate::system_info::SystemInfo;
+
+/// Where to send a request, and what to ask for
+#[derive(Debug, Clone)]
+pub struct SendRequest {
+    pub host: String,
+    pub port: u16,
+    pub secret: String,
+    pub action: PowerAction,
+    /// Ask the listener to skip its inhibitor checks
+    pub force: bool,
+    pub timeout: Duration,
+}
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("Unable to resolve {host}: {source}")]
+    UnresolvableHost { host: String, source: io::Error },
+
+    #[error("Unable to connect to {host}: {source}")]
+    Unreachable { host: String, source: io::Error },
+
+    #[error("Lost the connection to {host}: {source}")]
+    ConnectionLost { host: String, source: io::Error },
+
+    #[error("{host} closed the connection without replying – it may be running an older version that doesn't send responses")]
+    NoResponse { host: String },
+
+    #[error("{host} sent a response that couldn't be understood: {message}")]
+    MalformedResponse { host: String, message: String },
+
+    #[error("{host} accepted the connection but went {} seconds without a heartbeat – it may have hung", .silence.as_secs())]
+    Hung { host: String, silence: Duration },
+
+    #[error("There's no secret for {host} – give it one in the inventory, or pass --secret")]
+    NoSecret { host: String },
+
+    #[error("{host} refused the request ({}): {reason}", kind_description(.kind))]
+    Refused {
+        host: String,
+        kind: ErrorKind,
+        reason: String,
+    },
+}
+
+fn kind_description(kind: &ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::Unauthorized => "not authorized",
+        ErrorKind::Invalid => "the request is invalid",
+        ErrorKind::Failed => "the action failed",
+        ErrorKind::Rejected => "it isn't allowed right now",
+    }
+}
+
+impl ClientError {
+    /// The process exit code that best describes this error
+    pub fn exit_code(&self) -> exitcode::ExitCode {
+        match self {
+            ClientError::UnresolvableHost { .. } => exitcode::NOHOST,
+            ClientError::NoSecret { .. } => exitcode::USAGE,
+            ClientError::Unreachable { .. } => exitcode::UNAVAILABLE,
+            ClientError::ConnectionLost { .. } | ClientError::Hung { .. } => exitcode::IOERR,
+            ClientError::NoResponse { .. } | ClientError::MalformedResponse { .. } => {
+                exitcode::PROTOCOL
+            }
+            ClientError::Refused { kind, .. } => match kind {
+                ErrorKind::Unauthorized => exitcode::NOPERM,
+                ErrorKind::Invalid => exitcode::PROTOCOL,
+                ErrorKind::Failed => exitcode::OSERR,
+                ErrorKind::Rejected => exitcode::TEMPFAIL,
+            },
+        }
+    }
+
+    /// Whether it's safe to try again – only when the request never reached the listener, such
+    /// as a machine that's still booting or briefly off the network. Anything that went wrong
+    /// after the request was sent might have happened after the listener acted on it, and a
+    /// second shutdown or reboot mustn't be sent to a machine that's already carrying out the
+    /// first.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ClientError::Unreachable { .. })
+    }
+}
+
+/// What a listener agreed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Accepted {
+    pub action: PowerAction,
+    /// How long the listener is waiting before performing the action, if it's counting down
+    pub delay: Option<Duration>,
+    /// Whether the listener said so. Older versions close the connection without replying, so
+    /// all that's known is that the request was sent.
+    pub confirmed: bool,
+}
+
+impl fmt::Display for Accepted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.confirmed {
+            return write!(
+                f,
+                "{} sent, unconfirmed (the listener didn't reply – older versions don't)",
+                self.action
+            );
+        }
+
+        write!(f, "{} accepted", self.action)?;
+
+        if let Some(delay) = self.delay {
+            write!(f, " (in {} seconds)", delay.as_secs())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// What a listener said about itself in reply to a `PING`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Presence {
+    pub hostname: Option<String>,
+    pub uptime: Option<Duration>,
+}
+
+/// Sends `request` to a listener and waits for its reply. A listener that closes the connection
+/// without replying is taken to have accepted it, unconfirmed.
+pub fn send(request: &SendRequest) -> Result<Accepted, ClientError> {
+    let host = format!("{}:{}", request.host, request.port);
+    let stream = connect(&request.host, request.port, request.timeout)?;
+
+    let line = Request::Action {
+        action: request.action,
+        secret: request.secret.clone(),
+        force: request.force,
+    };
+
+    let response = match exchange(&stream, &host, &line) {
+        Ok(response) => response,
+        Err(ClientError::NoResponse { .. }) => {
+            return Ok(Accepted {
+                action: request.action,
+                delay: None,
+                confirmed: false,
+            })
+        }
+        Err(error) => return Err(error),
+    };
+
+    match response {
+        Response::Ok { action } => Ok(Accepted {
+            action,
+            delay: None,
+            confirmed: true,
+        }),
+        Response::Pending { action, delay } => Ok(Accepted {
+            action,
+            delay: Some(delay),
+            confirmed: true,
+        }),
+        Response::Error { kind, reason } => Err(ClientError::Refused { host, kind, reason }),
+        other => Err(ClientError::MalformedResponse {
+            host,
+            message: format!("Unexpected response {:?}", other.to_string()),
+        }),
+    }
+}
+
+/// Checks whether a listener is up, without needing its secret
+pub fn ping(host: &str, port: u16, timeout: Duration) -> Result<Presence, ClientError> {
+    let address = format!("{host}:{port}");
+    let stream = connect(host, port, timeout)?;
+
+    match exchange(&stream, &address, &Request::Ping { keepalive: None })? {
+        Response::Pong { hostname, uptime } => Ok(Presence { hostname, uptime }),
+        other => Err(ClientError::MalformedResponse {
+            host: address,
+            message: format!("Expected PONG, got {:?}", other.to_string()),
+        }),
+    }
+}
+
+/// Asks a listener to describe the machine's state. `secret` is only needed if the listener is
+/// configured to require it.
+pub fn info(
+    host: &str,
+    port: u16,
+    secret: Option<String>,
+    timeout: Duration,
+) -> Result<SystemInfo, ClientError> {
+    let address = format!("{host}:{port}");
+    let stream = connect(host, port, timeout)?;
+
+    match exchange(&stream, &address, &Request::Info { secret })? {
+        Response::Info(info) => Ok(*info),
+        Response::Error { kind, reason } => Err(ClientError::Refused {
+            host: address,
+            kind,
+            reason,
+        }),
+        other => Err(ClientError::MalformedResponse {
+            host: address,
+            message: format!("Expected INFO, got {:?}", other.to_string()),
+        }),
+    }
+}
+
+/// Asks a listener for a heartbeat every `interval`, calling `on_heartbeat` for each one, until
+/// something goes wrong. A listener that misses three heartbeats in a row is reported as hung.
+/// Listeners close keepalive connections after a while, in which case this reconnects.
+pub fn watch(
+    host: &str,
+    port: u16,
+    interval: Duration,
+    timeout: Duration,
+    mut on_heartbeat: impl FnMut(&Presence),
+) -> ClientError {
+    // The listener won't send them any more often than this
+    let interval = interval.max(MIN_HEARTBEAT_INTERVAL);
+
+    loop {
+        let mut heartbeats = 0;
+
+        let error = watch_connection(host, port, interval, timeout, |presence| {
+            heartbeats += 1;
+            on_heartbeat(presence)
+        });
+
+        match error {
+            // The listener only refuses a keepalive it's already sent heartbeats on once the
+            // connection has been open for as long as it allows
+            ClientError::Refused {
+                kind: ErrorKind::Rejected,
+                ..
+            } if heartbeats > 0 => log::debug!("{}, reconnecting", error),
+            error => return error,
+        }
+    }
+}
+
+fn watch_connection(
+    host: &str,
+    port: u16,
+    interval: Duration,
+    timeout: Duration,
+    mut on_heartbeat: impl FnMut(&Presence),
+) -> ClientError {
+    let address = format!("{host}:{port}");
+
+    let stream = match connect(host, port, timeout) {
+        Ok(stream) => stream,
+        Err(error) => return error,
+    };
+
+    let connection_lost = |source| ClientError::ConnectionLost {
+        host: address.clone(),
+        source,
+    };
+
+    let silence = interval * 3;
+
+    if let Err(error) = stream.set_read_timeout(Some(silence)) {
+        return connection_lost(error);
+    }
+
+    let request = Request::Ping {
+        keepalive: Some(Some(interval)),
+    };
+
+    // The connection stays open, so unlike `send` this doesn't shut down the write half
+    if let Err(error) = (&stream).write_all(format!("{request}\n").as_bytes()) {
+        return connection_lost(error);
+    }
+
+    let mut reader = BufReader::new(&stream);
+
+    loop {
+        let mut line = String::new();
+
+        match reader.read_line(&mut line) {
+            Ok(0) => return ClientError::NoResponse { host: address },
+            Ok(_) => {}
+            Err(error)
+                if error.kind() == io::ErrorKind::WouldBlock
+                    || error.kind() == io::ErrorKind::TimedOut =>
+            {
+                return ClientError::Hung {
+                    host: address,
+                    silence,
+                }
+            }
+            Err(error) => return connection_lost(error),
+        }
+
+        match line.parse::<Response>() {
+            Ok(Response::Pong { hostname, uptime }) => on_heartbeat(&Presence { hostname, uptime }),
+            Ok(Response::Error { kind, reason }) => {
+                return ClientError::Refused {
+                    host: address,
+                    kind,
+                    reason,
+                }
+            }
+            Ok(other) => {
+                return ClientError::MalformedResponse {
+                    host: address,
+                    message: format!("Expected PONG, got {:?}", other.to_string()),
+                }
+            }
+            Err(message) => {
+                return ClientError::MalformedResponse {
+                    host: address,
+                    message,
+                }
+            }
+        }
+    }
+}
+
+fn connect(host: &str, port: u16, timeout: Duration) -> Result<TcpStream, ClientError> {
+    let addresses: Vec<_> = (host, port)
+        .to_socket_addrs()
+        .map_err(|source| ClientError::UnresolvableHost {
+            host: host.to_string(),
+            source,
+        })?
+        .collect();
+
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses found");
+
+    for address in addresses {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => {
+                log::debug!("Connected to {}", address);
+
+                stream.set_read_timeout(Some(timeout)).map_err(|source| {
+                    ClientError::ConnectionLost {
+                        host: format!("{host}:{port}"),
+                        source,
+                    }
+                })?;
+
+                return Ok(stream);
+            }
+            Err(error) => last_error = error,
+        }
+    }
+
+    Err(ClientError::Unreachable {
+        host: format!("{host}:{port}"),
+        source: last_error,
+    })
+}
+
+/// Sends a single request line and reads the single line that comes back
+fn exchange(stream: &TcpStream, host: &str, request: &Request) -> Result<Response, ClientError> {
+    let connection_lost = |source| ClientError::ConnectionLost {
+        host: host.to_string(),
+        source,
+    };
+
+    let mut writer = stream;
+    writer
+        .write_all(format!("{request}\n").as_bytes())
+        .map_err(connection_lost)?;
+
+    // Older listeners read until the end of the stream before acting
+    stream.shutdown(Shutdown::Write).map_err(connection_lost)?;
+
+    let mut response = String::new();
+    BufReader::new(stream)
+        .read_line(&mut response)
+        .map_err(connection_lost)?;
+
+    if response.trim().is_empty() {
+        return Err(ClientError::NoResponse {
+            host: host.to_string(),
+        });
+    }
+
+    response
+        .parse::<Response>()
+        .map_err(|message| ClientError::MalformedResponse {
+            host: host.to_string(),
+            message,
+        })
+}
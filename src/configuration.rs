@@ -1,300 +1,282 @@
 extern crate exitcode;
 
-#[cfg(target_os = "macos")]
-extern crate plist;
-
+use rand::distributions::Alphanumeric;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
 use std::net::{IpAddr, Ipv4Addr};
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::path::Path;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::vec;
 use thiserror::Error;
 
-#[cfg(not(windows))]
-use std::io::Read;
-use std::io::Write;
-
-#[cfg(windows)]
-use winreg::RegKey;
-
-#[cfg(windows)]
-use winreg::enums::RegDisposition;
-
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
-pub struct AppConfiguration {
-    pub port_number: u16,
-    pub addresses: Vec<IpAddr>,
-    pub secret: String,
-}
-
-pub trait AppConfigurationStorage {
-    fn fetch() -> Result<AppConfiguration, ConfigurationError>;
-    fn save(&self) -> Result<(), ConfigurationError>;
-    fn delete(&self) -> Result<(), ConfigurationError>;
-
-    fn configuration_storage_path() -> String;
-    fn configuration_file_path() -> String;
-
-    fn create_configuration_if_not_exists() -> Result<(), ConfigurationError>;
-    fn create_configuration_storage_if_not_exists() -> Result<(), ConfigurationError>;
-}
-
-impl AppConfiguration {
-    pub fn validate() -> Result<(), ConfigurationError> {
-        Self::create_configuration_if_not_exists()?;
-        AppConfiguration::fetch()?;
-
-        Ok(())
-    }
-
-    pub fn set_addresses(&mut self, string: String) {
-        let ips_list: Vec<&str> = string.split(',').collect();
-
-        self.addresses = ips_list.iter().filter_map(|&ip| ip.parse().ok()).collect();
-    }
+use crate::audit::AuditConfiguration;
+use crate::control::ControlConfiguration;
+use crate::idle::IdleConfiguration;
+use crate::inhibitors::InhibitorConfiguration;
+use crate::logging::LoggingConfiguration;
+use crate::maintenance::MaintenanceConfiguration;
+use crate::notifications::NotificationConfiguration;
+use crate::power::PowerConfiguration;
+use crate::protocol::ConnectionConfiguration;
+use crate::reload::ReloadConfiguration;
+use crate::scheduler::SchedulerConfiguration;
+use crate::system_info::InfoConfiguration;
+
+/// The configuration layout this version of the tool writes. Bump it, and add a step to
+/// `configuration_migration`, whenever a change needs older configurations to be upgraded.
+pub const CONFIGURATION_VERSION: u32 = 1;
+
+/// The secret in the default configuration, which older versions saved as it was (new
+/// configurations get a generated secret instead). The listener refuses to start with it, unless
+/// `insecure_allow_default_secret` is set, since anyone who's read the documentation knows it.
+pub const DEFAULT_SECRET: &str = "Super Secret String";
+
+/// How long the secrets generated for new configurations are
+const GENERATED_SECRET_LENGTH: usize = 32;
+
+/// Where systemd puts the service's credentials (see `systemd.exec(5)`)
+const CREDENTIALS_DIRECTORY_VARIABLE: &str = "CREDENTIALS_DIRECTORY";
+
+/// A secret that's kept somewhere other than the configuration. It's written in place of the
+/// secret as `env:NAME`, `file:PATH` or `credential:NAME`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecretReference {
+    /// An environment variable
+    Environment(String),
+
+    /// A file, such as a Docker or Kubernetes secret. A trailing newline is ignored.
+    File(PathBuf),
+
+    /// A systemd credential, passed to the service with `LoadCredential=` or `SetCredential=`
+    Credential(String),
 }
 
-#[cfg(target_os = "macos")]
-impl AppConfiguration {
-    pub fn fetch() -> Result<AppConfiguration, ConfigurationError> {
-        log::debug!("Fetching App Configuration");
-        Self::create_configuration_if_not_exists()?;
-
-        let path = PathBuf::from(Self::configuration_file_path());
-        Plist::read_configuration(&path)
-    }
-
-    pub fn save(&self) -> Result<(), ConfigurationError> {
-        let path = PathBuf::from(Self::configuration_file_path());
-        log::debug!("Writing configuration to {:?}", path);
-        Plist::write_configuration(self, path)
-    }
-
-    fn configuration_storage_path() -> String {
-        extern crate dirs;
-
-        let username = whoami::username();
-
-        if username == "root" {
-            let path = Path::new("/Library/Application Support/ShutdownOnLan").to_path_buf();
-            log::info!("Detected Configuration Path: {:?}", path);
-            return path.into_os_string().into_string().unwrap();
+impl SecretReference {
+    pub fn parse(secret: &str) -> Option<SecretReference> {
+        if let Some(name) = secret.strip_prefix("env:") {
+            Some(SecretReference::Environment(name.to_string()))
+        } else if let Some(path) = secret.strip_prefix("file:") {
+            Some(SecretReference::File(PathBuf::from(path)))
+        } else {
+            secret
+                .strip_prefix("credential:")
+                .map(|name| SecretReference::Credential(name.to_string()))
         }
-
-        let path = dirs::home_dir()
-            .expect("failed to find home directory")
-            .join("Library")
-            .join("Application Support")
-            .join("ShutdownOnLan")
-            .as_path()
-            .to_owned();
-
-        log::info!("Detected Configuration Path: {:?}", path);
-
-        path.into_os_string().into_string().unwrap()
-    }
-
-    fn configuration_file_path() -> String {
-        PathBuf::from(Self::configuration_storage_path())
-            .join("ShutDownOnLan.plist")
-            .into_os_string()
-            .into_string()
-            .unwrap()
     }
 
-    fn create_configuration_storage_if_not_exists() -> Result<(), ConfigurationError> {
-        let path = PathBuf::from(Self::configuration_storage_path());
-
-        if path.exists() && path.is_dir() {
-            return Ok(());
+    /// Checks that the reference could be read, without reading it – where the secret is kept
+    /// may only be available to the service
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            SecretReference::Environment(name) => {
+                if name.is_empty() || name.contains(['=', '\0']) {
+                    return Err(format!("{name:?} isn't an environment variable name"));
+                }
+            }
+            SecretReference::File(path) => {
+                if path.as_os_str().is_empty() {
+                    return Err("no file was given".to_string());
+                }
+            }
+            SecretReference::Credential(name) => {
+                if name.is_empty() || name.contains('/') {
+                    return Err(format!("{name:?} isn't a credential name"));
+                }
+            }
         }
 
-        log::debug!("Creating configuration storage at {:?}", path);
-
-        std::fs::create_dir(&path).map_err(|error| {
-            ConfigurationError::ConfigurationStorageUnwritable {
-                source: error,
-                path: path.into_os_string().into_string().unwrap(),
-            }
-        })
+        Ok(())
     }
 
-    fn create_configuration_if_not_exists() -> Result<(), ConfigurationError> {
-        log::debug!("Checking whether configuration needs to be created");
-
-        Self::create_configuration_storage_if_not_exists()?;
+    /// Reads the secret, returning why it couldn't be if it can't
+    pub fn resolve(&self) -> Result<String, String> {
+        self.validate()?;
 
-        let path = PathBuf::from(Self::configuration_file_path());
+        match self {
+            SecretReference::Environment(name) => {
+                std::env::var(name).map_err(|error| format!("${name}: {error}"))
+            }
+            SecretReference::File(path) => read_secret_file(path),
+            SecretReference::Credential(name) => {
+                let directory = std::env::var_os(CREDENTIALS_DIRECTORY_VARIABLE).ok_or_else(|| {
+                    format!("${CREDENTIALS_DIRECTORY_VARIABLE} isn't set – is the service started with `LoadCredential=`?")
+                })?;
 
-        if path.exists() {
-            log::debug!("Configuration Exists");
-            return Ok(());
+                read_secret_file(&Path::new(&directory).join(name))
+            }
         }
-
-        log::info!("Creating Configuration File from Defaults");
-
-        let configuration = AppConfiguration::default();
-        let configuration_file_path = Self::configuration_file_path();
-
-        log::debug!(
-            "Creating configuration for {:?} at {:?}",
-            whoami::username(),
-            configuration_file_path
-        );
-
-        configuration.save()
     }
 }
 
-#[cfg(target_os = "linux")]
-impl AppConfiguration {
-    pub fn fetch() -> Result<AppConfiguration, ConfigurationError> {
-        extern crate serde_ini;
-
-        let path = Self::configuration_file_path();
+fn read_secret_file(path: &Path) -> Result<String, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|error| format!("{}: {}", path.display(), error))?;
 
-        let string = std::fs::read_to_string(path)
-            .map_err(|error| ConfigurationError::InvalidConfigurationFile { source: error })?;
-
-        let config: AppConfiguration =
-            serde_ini::from_str(&string).expect("Invalid app configuration");
+    let secret = contents.strip_suffix('\n').unwrap_or(&contents);
+    Ok(secret.strip_suffix('\r').unwrap_or(secret).to_string())
+}
 
-        Ok(config)
+impl fmt::Display for SecretReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretReference::Environment(name) => write!(f, "env:{name}"),
+            SecretReference::File(path) => write!(f, "file:{}", path.display()),
+            SecretReference::Credential(name) => write!(f, "credential:{name}"),
+        }
     }
+}
 
-    pub fn save(&self) -> Result<(), ConfigurationError> {
-        extern crate serde_ini;
-        let string =
-            serde_ini::to_string(self).map_err(|_e| ConfigurationError::InvalidConfiguration)?;
+/// The listener's settings. Where they're stored is up to an `AppConfigurationStorage` – see
+/// `configuration_storage`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AppConfiguration {
+    /// The layout the configuration was written in. Configurations from before it was added
+    /// don't have one, and read as `0`. See `configuration_migration`.
+    #[serde(default)]
+    pub version: u32,
 
-        let path = PathBuf::from(Self::configuration_file_path());
-        std::fs::write(&path, string).map_err(|error| {
-            ConfigurationError::ConfigurationStorageUnwritable {
-                source: error,
-                path: path.into_os_string().into_string().unwrap(),
-            }
-        })
-    }
+    pub port_number: u16,
+    pub addresses: Vec<IpAddr>,
 
-    fn configuration_storage_path() -> String {
-        Path::new("/etc").to_str().unwrap().to_string()
-    }
+    /// The secret itself, or a `SecretReference` to where it's kept
+    pub secret: String,
 
-    fn configuration_file_path() -> String {
-        PathBuf::from(Self::configuration_storage_path())
-            .join("ShutDownOnLan")
-            .into_os_string()
-            .to_str()
-            .unwrap()
-            .to_string()
-    }
+    /// Serve even though the secret is `DEFAULT_SECRET`. Only for trying the tool out – anyone
+    /// can shut the machine down.
+    #[serde(default)]
+    pub insecure_allow_default_secret: bool,
 
-    fn create_configuration_storage_if_not_exists() -> Result<(), ConfigurationError> {
-        let path = PathBuf::from(Self::configuration_storage_path());
+    #[serde(default)]
+    pub audit: AuditConfiguration,
 
-        if path.exists() && path.is_dir() {
-            return Ok(());
-        }
+    #[serde(default)]
+    pub logging: LoggingConfiguration,
 
-        log::debug!("Creating configuration storage at {:?}", path);
+    #[serde(default)]
+    pub control: ControlConfiguration,
 
-        std::fs::create_dir(&path).map_err(|error| {
-            ConfigurationError::ConfigurationStorageUnwritable {
-                source: error,
-                path: path.into_os_string().into_string().unwrap(),
-            }
-        })
-    }
+    #[serde(default)]
+    pub connection: ConnectionConfiguration,
 
-    fn create_configuration_if_not_exists() -> Result<(), ConfigurationError> {
-        log::debug!("Checking whether configuration needs to be created");
+    #[serde(default)]
+    pub info: InfoConfiguration,
 
-        Self::create_configuration_storage_if_not_exists()?;
+    #[serde(default)]
+    pub inhibitors: InhibitorConfiguration,
 
-        let path = PathBuf::from(Self::configuration_file_path());
+    #[serde(default)]
+    pub power: PowerConfiguration,
 
-        if path.exists() {
-            log::debug!("Configuration Exists");
-            return Ok(());
-        }
+    #[serde(default)]
+    pub maintenance: MaintenanceConfiguration,
 
-        log::info!("Creating Configuration File from Defaults");
+    #[serde(default)]
+    pub scheduler: SchedulerConfiguration,
 
-        let configuration = AppConfiguration::default();
+    #[serde(default)]
+    pub notifications: NotificationConfiguration,
 
-        log::debug!("Creating configuration at {:?}", path);
+    #[serde(default)]
+    pub idle: IdleConfiguration,
 
-        configuration.save()
-    }
+    #[serde(default)]
+    pub reload: ReloadConfiguration,
 }
 
-#[cfg(windows)]
 impl AppConfiguration {
-    pub fn fetch() -> Result<AppConfiguration, ConfigurationError> {
-        log::info!("Looking up configuration");
-
-        let registry = Registry::with_default_root_key()?;
-        let ips_string = registry.read_string(ConfigurationRegistryKeys::IpAddress)?;
-        let ips_list: Vec<&str> = ips_string.split(',').collect();
+    /// A copy of this configuration that's safe to display – the secret is replaced
+    pub fn redacted(&self) -> AppConfiguration {
+        AppConfiguration {
+            secret: "********".to_string(),
+            ..self.clone()
+        }
+    }
 
-        let ip_addresses: Vec<IpAddr> = ips_list.iter().filter_map(|&ip| ip.parse().ok()).collect();
+    /// The secret, read from wherever it's kept if the configuration holds a reference to it
+    pub fn resolved_secret(&self) -> Result<String, ConfigurationError> {
+        match SecretReference::parse(&self.secret) {
+            None => Ok(self.secret.clone()),
+            Some(reference) => {
+                reference
+                    .resolve()
+                    .map_err(|reason| ConfigurationError::UnresolvableSecret {
+                        reference: reference.to_string(),
+                        reason,
+                    })
+            }
+        }
+    }
 
+    /// A copy of this configuration with the secret read from wherever it's kept, for the
+    /// listener to run with. It mustn't be saved, or shown to anyone.
+    pub fn with_resolved_secret(&self) -> Result<AppConfiguration, ConfigurationError> {
         Ok(AppConfiguration {
-            port_number: registry.read_u16(ConfigurationRegistryKeys::Port)?,
-            addresses: ip_addresses,
-            secret: registry.read_string(ConfigurationRegistryKeys::Secret)?,
+            secret: self.resolved_secret()?,
+            ..self.clone()
         })
     }
 
-    pub fn save(self) -> Result<(), ConfigurationError> {
-        let registry = Registry::with_default_root_key()?;
-
-        let addresses: Vec<String> = self.addresses.iter().map(|ip| ip.to_string()).collect();
-
-        let joined_addresses = addresses.join(",");
-        registry.write_string(ConfigurationRegistryKeys::IpAddress, &joined_addresses)?;
-        log::debug!("Set IP Addresses to {}", &joined_addresses);
-
-        let u32_port_number = self.port_number as u32;
-        registry.write_u32(ConfigurationRegistryKeys::Port, u32_port_number)?;
-        log::debug!("Set Port to {}", u32_port_number);
-
-        registry.write_string(ConfigurationRegistryKeys::Secret, &self.secret)?;
-        log::debug!("Set secret to {}", self.secret);
-
-        Ok(())
-    }
-
-    pub fn create_configuration_storage_if_not_exists() -> Result<(), ConfigurationError> {
-        Registry::with_default_root_key()?;
-        Ok(())
+    /// A random secret, for new configurations
+    pub fn generate_secret() -> String {
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(GENERATED_SECRET_LENGTH)
+            .map(char::from)
+            .collect()
     }
 
-    pub fn create_configuration_if_not_exists() -> Result<(), ConfigurationError> {
-        log::info!("Checking whether configuration needs to be created");
+    /// Sets the addresses from a comma-separated list, as stored in INI files and the registry.
+    /// If any of them can't be parsed, the addresses are left as they were.
+    pub fn set_addresses(&mut self, string: String) -> Result<(), ConfigurationError> {
+        let mut addresses = Vec::new();
+        let mut unparseable = Vec::new();
 
-        if let Ok(existing_configuration) = Self::fetch() {
-            log::info!("Found existing configuration – skipping creation");
-            log::info!("Configuration: {:?}", existing_configuration);
-            return Ok(());
+        for address in string.split(',').map(str::trim).filter(|ip| !ip.is_empty()) {
+            match address.parse() {
+                Ok(address) => addresses.push(address),
+                Err(_) => unparseable.push(address.to_string()),
+            }
         }
 
-        log::info!("Writing default configuration to registry");
-        let configuration = AppConfiguration::default();
-        configuration.save()?;
-        log::info!("Default configuration written to registry");
+        if !unparseable.is_empty() {
+            return Err(ConfigurationError::UnparseableAddresses {
+                addresses: unparseable,
+            });
+        }
 
+        self.addresses = addresses;
         Ok(())
     }
+
+    /// The addresses as stored in INI files and the registry – comma-separated
+    pub fn joined_addresses(&self) -> String {
+        let addresses: Vec<String> = self.addresses.iter().map(|ip| ip.to_string()).collect();
+        addresses.join(",")
+    }
 }
 
 impl Default for AppConfiguration {
     fn default() -> Self {
         AppConfiguration {
+            version: CONFIGURATION_VERSION,
             port_number: 53632,
             addresses: [IpAddr::from(Ipv4Addr::new(127, 0, 0, 1))].to_vec(),
-            secret: "Super Secret String".to_string(),
+            secret: DEFAULT_SECRET.to_string(),
+            insecure_allow_default_secret: false,
+            audit: AuditConfiguration::default(),
+            logging: LoggingConfiguration::default(),
+            control: ControlConfiguration::default(),
+            connection: ConnectionConfiguration::default(),
+            info: InfoConfiguration::default(),
+            inhibitors: InhibitorConfiguration::default(),
+            power: PowerConfiguration::default(),
+            maintenance: MaintenanceConfiguration::default(),
+            scheduler: SchedulerConfiguration::default(),
+            notifications: NotificationConfiguration::default(),
+            idle: IdleConfiguration::default(),
+            reload: ReloadConfiguration::default(),
         }
     }
 }
@@ -319,193 +301,276 @@ impl ToSocketAddrs for AppConfiguration {
     }
 }
 
-#[cfg(windows)]
-#[derive(Debug, Clone, Copy)]
-enum ConfigurationRegistryKeys {
-    IpAddress,
-    Port,
-    Secret,
-}
+#[derive(Error, Debug)]
+pub enum ConfigurationError {
+    #[error("There's no configuration at {location}")]
+    MissingConfiguration { location: String },
 
-#[cfg(windows)]
-impl ConfigurationRegistryKeys {
-    fn as_str(&self) -> &'static str {
-        match self {
-            ConfigurationRegistryKeys::IpAddress => "ip_addresses",
-            ConfigurationRegistryKeys::Port => "port",
-            ConfigurationRegistryKeys::Secret => "secret",
-        }
-    }
-}
+    #[error("Unable to read the configuration at {location}: {source}")]
+    UnreadableConfiguration { location: String, source: io::Error },
 
-#[cfg(windows)]
-impl AsRef<std::ffi::OsStr> for ConfigurationRegistryKeys {
-    fn as_ref(&self) -> &std::ffi::OsStr {
-        std::ffi::OsStr::new(self.as_str())
-    }
-}
+    #[error("Unable to parse the configuration at {location}: {message}")]
+    UnparseableConfiguration { location: String, message: String },
 
-#[cfg(windows)]
-struct Registry {
-    root_key: RegKey,
-}
+    #[error("The configuration can't be converted to its on-disk representation: {0}")]
+    InvalidConfiguration(String),
 
-#[cfg(windows)]
-impl Registry {
-    fn with_default_root_key() -> Result<Registry, ConfigurationError> {
-        Registry::with_root_key(Path::new("SOFTWARE").join("ShutdownOnLan"))
-    }
+    #[error("Unable to write the configuration to {location}: {source}")]
+    ConfigurationStorageUnwritable { location: String, source: io::Error },
 
-    fn with_root_key(path: PathBuf) -> Result<Registry, ConfigurationError> {
-        let (key, disposition) = RegKey::predef(winreg::enums::HKEY_LOCAL_MACHINE)
-            .create_subkey(&path)
-            .expect("Failed to read app configuration");
+    #[error("Not valid IP addresses: {}", .addresses.join(", "))]
+    UnparseableAddresses { addresses: Vec<String> },
 
-        match disposition {
-            RegDisposition::REG_CREATED_NEW_KEY => {
-                log::info!("Created New Registry Key");
-            }
-            RegDisposition::REG_OPENED_EXISTING_KEY => {
-                log::info!("Using Existing Registry Key");
-            }
-        }
+    #[error("{address} isn't assigned to any of this machine's network interfaces, so requests would never arrive on it")]
+    UnassignedAddress { address: IpAddr },
 
-        Ok(Registry { root_key: key })
-    }
+    #[error("There are no addresses to accept requests on")]
+    NoAddresses,
 
-    fn read_string(&self, key: ConfigurationRegistryKeys) -> Result<String, ConfigurationError> {
-        self.root_key
-            .get_value(key)
-            .map_err(|_error| ConfigurationError::RegistryKeyNotReadable(key))
-            .map(|regval: String| regval as String)
-    }
+    #[error("Port {port} can't be listened on")]
+    InvalidPort { port: u16 },
 
-    fn read_u16(&self, key: ConfigurationRegistryKeys) -> Result<u16, ConfigurationError> {
-        use std::convert::TryFrom;
-        let value = self.read_u32(key)?;
-        u16::try_from(value).map_err(|_error| ConfigurationError::RegistryKeyNotReadable(key))
-    }
+    #[error("Port {port} is a privileged port (below 1024) – it may clash with a system service, and can only be listened on as root")]
+    PrivilegedPort { port: u16 },
 
-    fn read_u32(&self, key: ConfigurationRegistryKeys) -> Result<u32, ConfigurationError> {
-        self.root_key
-            .get_value(key)
-            .map_err(|_error| ConfigurationError::RegistryKeyNotReadable(key))
-            .map(|regval: u32| regval as u32)
-    }
+    #[error("The secret is still the default, {DEFAULT_SECRET:?} – anyone who's read the documentation can shut this machine down")]
+    DefaultSecret,
 
-    fn write_string(
-        &self,
-        key: ConfigurationRegistryKeys,
-        value: &String,
-    ) -> Result<(), ConfigurationError> {
-        match self.root_key.set_value(key, value) {
-            Ok(()) => Ok(()),
-            Err(error) => Err(ConfigurationError::RegistryKeyNotWritable(key)),
-        }
-    }
+    #[error("The secret is only {length} characters long – use at least {minimum}")]
+    WeakSecret { length: usize, minimum: usize },
 
-    fn write_u32(
-        &self,
-        key: ConfigurationRegistryKeys,
-        value: u32,
-    ) -> Result<(), ConfigurationError> {
-        match self.root_key.set_value(key, &value) {
-            Ok(()) => Ok(()),
-            Err(error) => Err(ConfigurationError::RegistryKeyNotWritable(key)),
-        }
-    }
-}
+    #[error("The secret is {length} characters long, but can't be longer than {maximum}")]
+    SecretTooLong { length: usize, maximum: usize },
 
-#[cfg(target_os = "macos")]
-use std::convert::TryFrom;
+    #[error("Unable to read the secret from {reference}: {reason}")]
+    UnresolvableSecret { reference: String, reason: String },
 
-#[cfg(target_os = "macos")]
-impl TryFrom<Vec<u8>> for AppConfiguration {
-    type Error = ConfigurationError;
+    #[error("The {setting} at {} can't be written to: {source}", .path.display())]
+    UnwritablePath {
+        setting: String,
+        path: PathBuf,
+        source: io::Error,
+    },
 
-    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
-        plist::from_bytes(&value).map_err(ConfigurationError::CorruptConfigurationFile)
-    }
+    #[cfg(unix)]
+    #[error("The configuration at {location} can be read by other users (its permissions are {mode:o}) – run `chmod 600` on it to keep the secret private")]
+    InsecurePermissions { location: String, mode: u32 },
+
+    #[cfg(unix)]
+    #[error("The configuration at {location} belongs to user {owner}, who can change the secret – run `chown root` on it")]
+    InsecureOwner { location: String, owner: u32 },
+
+    #[error("The configuration at {location} is version {version}, which is newer than this version of shutdown-on-lan understands (up to {CONFIGURATION_VERSION})")]
+    UnsupportedConfigurationVersion { location: String, version: u32 },
 }
 
-#[cfg(target_os = "macos")]
-struct Plist {}
+impl ConfigurationError {
+    /// The process exit code that best describes this error
+    pub fn exit_code(&self) -> exitcode::ExitCode {
+        match self {
+            ConfigurationError::MissingConfiguration { .. } => exitcode::NOINPUT,
+            ConfigurationError::UnreadableConfiguration { .. } => exitcode::IOERR,
+            ConfigurationError::UnparseableConfiguration { .. } => exitcode::DATAERR,
+            ConfigurationError::InvalidConfiguration(_) => exitcode::SOFTWARE,
+            ConfigurationError::ConfigurationStorageUnwritable { .. }
+            | ConfigurationError::UnwritablePath { .. } => exitcode::CANTCREAT,
+            ConfigurationError::UnparseableAddresses { .. } => exitcode::DATAERR,
+            ConfigurationError::UnassignedAddress { .. } => exitcode::NOHOST,
+            ConfigurationError::DefaultSecret | ConfigurationError::WeakSecret { .. } => {
+                exitcode::NOPERM
+            }
+            #[cfg(unix)]
+            ConfigurationError::InsecurePermissions { .. }
+            | ConfigurationError::InsecureOwner { .. } => exitcode::NOPERM,
+            ConfigurationError::UnresolvableSecret { .. } => exitcode::NOINPUT,
+            ConfigurationError::NoAddresses
+            | ConfigurationError::InvalidPort { .. }
+            | ConfigurationError::PrivilegedPort { .. }
+            | ConfigurationError::SecretTooLong { .. }
+            | ConfigurationError::UnsupportedConfigurationVersion { .. } => exitcode::CONFIG,
+        }
+    }
+}
 
-#[cfg(target_os = "macos")]
-impl Plist {
-    pub fn read_configuration(path: &Path) -> Result<AppConfiguration, ConfigurationError> {
-        let file =
-            std::fs::File::open(path).map_err(ConfigurationError::MissingConfigurationFile)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
 
-        let mut reader = std::io::BufReader::new(file);
-        let mut bytes = Vec::new();
+    #[test]
+    fn references_are_parsed_by_their_prefix() {
+        assert_eq!(
+            SecretReference::parse("env:SHUTDOWN_SECRET"),
+            Some(SecretReference::Environment("SHUTDOWN_SECRET".to_string()))
+        );
+        assert_eq!(
+            SecretReference::parse("file:/run/secrets/shutdown-on-lan"),
+            Some(SecretReference::File(PathBuf::from(
+                "/run/secrets/shutdown-on-lan"
+            )))
+        );
+        assert_eq!(
+            SecretReference::parse("credential:secret"),
+            Some(SecretReference::Credential("secret".to_string()))
+        );
+    }
 
-        // Read the file into `bytes`
-        reader
-            .read_to_end(&mut bytes)
-            .map_err(|error| ConfigurationError::InvalidConfigurationFile { source: error })?;
+    #[test]
+    fn anything_else_is_the_secret_itself() {
+        for secret in &[
+            "Super Secret String",
+            "ENV:NOT_A_REFERENCE",
+            "environment:x",
+            "",
+        ] {
+            assert_eq!(SecretReference::parse(secret), None, "{:?}", secret);
+        }
 
-        AppConfiguration::try_from(bytes)
+        let configuration = AppConfiguration {
+            secret: "a literal secret".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(configuration.resolved_secret().unwrap(), "a literal secret");
+    }
+
+    #[test]
+    fn references_are_displayed_as_they_were_written() {
+        for reference in &[
+            "env:SHUTDOWN_SECRET",
+            "file:/run/secret",
+            "credential:secret",
+        ] {
+            assert_eq!(
+                SecretReference::parse(reference).unwrap().to_string(),
+                *reference
+            );
+        }
     }
 
-    pub fn write_configuration(
-        configuration: &AppConfiguration,
-        path: PathBuf,
-    ) -> Result<(), ConfigurationError> {
-        // `open `doesn't create the file if needed, so we need to
-        if !path.exists() {
-            let _ = std::fs::File::create(&path)?;
+    #[test]
+    fn environment_variables_are_read() {
+        std::env::set_var("SHUTDOWN_ON_LAN_TEST_SECRET", "from the environment");
+
+        assert_eq!(
+            SecretReference::parse("env:SHUTDOWN_ON_LAN_TEST_SECRET")
+                .unwrap()
+                .resolve(),
+            Ok("from the environment".to_string())
+        );
+        assert!(SecretReference::parse("env:SHUTDOWN_ON_LAN_TEST_UNSET")
+            .unwrap()
+            .resolve()
+            .is_err());
+    }
+
+    #[test]
+    fn files_lose_one_trailing_newline() {
+        let directory = TempDir::new().unwrap();
+        let cases = [
+            ("unix", "from a file\n", "from a file"),
+            ("windows", "from a file\r\n", "from a file"),
+            ("none", "from a file", "from a file"),
+            ("two", "from a file\n\n", "from a file\n"),
+            ("spaces", " from a file \n", " from a file "),
+        ];
+
+        for (name, contents, expected) in &cases {
+            let path = directory.path().join(name);
+            std::fs::write(&path, contents).unwrap();
+
+            assert_eq!(
+                SecretReference::File(path).resolve(),
+                Ok(expected.to_string()),
+                "{}",
+                name
+            );
         }
+    }
 
-        let mut file_handle = std::fs::File::options()
-            .write(true)
-            .open(path)
-            .map_err(|_e| ConfigurationError::ConfigurationFileUnwritable)?;
+    #[test]
+    fn missing_files_say_which_file() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("missing");
 
-        let mut buf = std::io::BufWriter::new(Vec::new());
-        plist::to_writer_xml(&mut buf, &configuration)
-            .map_err(|_e| ConfigurationError::InvalidConfiguration)?;
+        let reason = SecretReference::File(path.clone()).resolve().unwrap_err();
 
-        let bytes = buf.into_inner().unwrap();
-        file_handle
-            .write_all(&bytes)
-            .map_err(|_e| ConfigurationError::InvalidConfiguration)?;
+        assert!(reason.contains(&path.display().to_string()), "{}", reason);
+    }
 
-        Ok(())
+    #[test]
+    fn credentials_are_read_from_the_credentials_directory() {
+        let directory = TempDir::new().unwrap();
+        std::fs::write(directory.path().join("secret"), "from a credential\n").unwrap();
+        let reference = SecretReference::Credential("secret".to_string());
+
+        // The only test that changes the variable, so that they can't race
+        std::env::set_var(CREDENTIALS_DIRECTORY_VARIABLE, directory.path());
+        assert_eq!(reference.resolve(), Ok("from a credential".to_string()));
+        assert!(SecretReference::Credential("other".to_string())
+            .resolve()
+            .is_err());
+
+        std::env::remove_var(CREDENTIALS_DIRECTORY_VARIABLE);
+        let reason = reference.resolve().unwrap_err();
+        assert!(
+            reason.contains(CREDENTIALS_DIRECTORY_VARIABLE),
+            "{}",
+            reason
+        );
     }
-}
 
-#[derive(Error, Debug)]
-pub enum ConfigurationError {
-    #[error("No Configuration File at Path")]
-    MissingConfigurationFile(#[from] std::io::Error),
+    #[test]
+    fn invalid_references_are_refused_without_being_read() {
+        for reference in &[
+            "credential:",
+            "credential:../secret",
+            "env:",
+            "env:A=B",
+            "file:",
+        ] {
+            let reference = SecretReference::parse(reference).unwrap();
 
-    #[error("Contents of Configuration File Are Invalid")]
-    InvalidConfigurationFile { source: std::io::Error },
+            assert!(reference.validate().is_err(), "{}", reference);
+            assert!(reference.resolve().is_err(), "{}", reference);
+        }
+    }
 
-    #[cfg(target_os = "macos")]
-    #[error("Contents of Configuration File Are Invalid")]
-    CorruptConfigurationFile(#[from] plist::Error),
+    #[test]
+    fn unresolvable_secrets_name_the_reference() {
+        let configuration = AppConfiguration {
+            secret: "env:SHUTDOWN_ON_LAN_TEST_UNSET".to_string(),
+            ..Default::default()
+        };
 
-    #[error("The configuration file in memory can't be converted to an on-disk representation")]
-    InvalidConfiguration,
+        match configuration.with_resolved_secret() {
+            Err(ConfigurationError::UnresolvableSecret { reference, .. }) => {
+                assert_eq!(reference, "env:SHUTDOWN_ON_LAN_TEST_UNSET")
+            }
+            result => panic!("Expected UnresolvableSecret, got {:?}", result),
+        }
+    }
 
-    #[cfg(windows)]
-    #[error("Unable to read to a registry key")]
-    RegistryKeyNotReadable(ConfigurationRegistryKeys),
+    #[test]
+    fn only_the_secret_is_resolved() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("secret");
+        std::fs::write(&path, "from a file\n").unwrap();
 
-    #[cfg(windows)]
-    #[error("Unable to write to a registry key")]
-    RegistryKeyNotWritable(ConfigurationRegistryKeys),
+        let configuration = AppConfiguration {
+            secret: format!("file:{}", path.display()),
+            port_number: 50000,
+            ..Default::default()
+        };
 
-    #[error("Unable to write to configuration storage directory")]
-    ConfigurationStorageUnwritable {
-        source: std::io::Error,
-        path: String,
-    },
+        let resolved = configuration.with_resolved_secret().unwrap();
 
-    #[error("Unable to write to configuration file: {}", exitcode::CANTCREAT)]
-    ConfigurationFileUnwritable,
-    // #[error("Unable to write configuration – it is not valid")]
-    // ConfigurationSerializationError,
+        assert_eq!(resolved.secret, "from a file");
+        assert_eq!(
+            AppConfiguration {
+                secret: configuration.secret.clone(),
+                ..resolved
+            },
+            configuration
+        );
+    }
 }
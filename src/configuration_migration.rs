@@ -0,0 +1,63 @@
+//! Upgrades configurations written by older versions of the tool. Each change to the layout that
+//! older configurations need upgrading for gets a `Migration`, and bumps `CONFIGURATION_VERSION`.
+
+use crate::configuration::{AppConfiguration, ConfigurationError, CONFIGURATION_VERSION};
+
+/// One step in upgrading a configuration, from the version before `version` to `version`
+struct Migration {
+    version: u32,
+
+    /// What the step changes, for explaining the upgrade
+    description: &'static str,
+
+    apply: fn(&mut AppConfiguration),
+}
+
+/// Every step, oldest first
+const MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    description: "recorded the configuration's version, so later upgrades know where to start",
+    apply: |_| {},
+}];
+
+/// Whether `configuration` needs upgrading. Configurations from a newer version of the tool are
+/// refused, rather than being read (and saved) without the settings this version doesn't know.
+pub fn needs_migration(
+    configuration: &AppConfiguration,
+    location: &str,
+) -> Result<bool, ConfigurationError> {
+    if configuration.version > CONFIGURATION_VERSION {
+        return Err(ConfigurationError::UnsupportedConfigurationVersion {
+            location: location.to_string(),
+            version: configuration.version,
+        });
+    }
+
+    Ok(configuration.version < CONFIGURATION_VERSION)
+}
+
+/// Brings `configuration` up to `CONFIGURATION_VERSION`, returning a description of each step
+pub fn migrate(configuration: &mut AppConfiguration) -> Vec<String> {
+    let mut changes = Vec::new();
+    let starting_version = configuration.version;
+
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > starting_version)
+    {
+        log::debug!(
+            "Upgrading the configuration from version {} to {}",
+            configuration.version,
+            migration.version
+        );
+
+        (migration.apply)(configuration);
+        configuration.version = migration.version;
+        changes.push(format!(
+            "version {}: {}",
+            migration.version, migration.description
+        ));
+    }
+
+    changes
+}
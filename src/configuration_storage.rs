@@ -0,0 +1,1244 @@
+//! Where the configuration lives. Each backend implements `AppConfigurationStorage`, so the rest
+//! of the tool doesn't need to know whether it's reading an INI file, a plist or the registry.
+
+extern crate plist;
+extern crate serde_ini;
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::configuration::{AppConfiguration, ConfigurationError};
+use crate::configuration_migration;
+use crate::configuration_validation;
+
+#[cfg(unix)]
+use nix::unistd::geteuid;
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+#[cfg(windows)]
+use std::process::Command;
+
+#[cfg(windows)]
+use std::convert::TryFrom;
+#[cfg(windows)]
+use winreg::enums::{HKEY_LOCAL_MACHINE, KEY_SET_VALUE};
+#[cfg(windows)]
+use winreg::RegKey;
+
+pub trait AppConfigurationStorage {
+    fn fetch(&self) -> Result<AppConfiguration, ConfigurationError>;
+    fn save(&self, configuration: &AppConfiguration) -> Result<(), ConfigurationError>;
+
+    /// Nothing deletes the configuration yet – `uninstall` leaves it in place on purpose
+    #[allow(dead_code)]
+    fn delete(&self) -> Result<(), ConfigurationError>;
+
+    fn exists(&self) -> bool;
+
+    /// Where the configuration lives, for messages
+    fn location(&self) -> String;
+
+    /// The file the configuration is stored in, if it's in one, so that it can be watched for
+    /// changes
+    fn path(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Copies the configuration somewhere it won't be overwritten, returning where
+    fn backup(&self) -> Result<String, ConfigurationError>;
+
+    /// Moves the configuration out of the way, as a backup, once it's been copied somewhere
+    /// else. Returns where it went, or `None` if it can't be moved (such as a registry key) and
+    /// was left where it is.
+    fn retire(&self) -> Result<Option<String>, ConfigurationError> {
+        Ok(None)
+    }
+
+    /// Checks that changes to the configuration could be saved, without saving anything
+    fn check_writable(&self) -> Result<(), ConfigurationError> {
+        Ok(())
+    }
+
+    /// Checks that only the owner can read the configuration, since it holds the secret
+    fn check_permissions(&self) -> Result<(), ConfigurationError> {
+        Ok(())
+    }
+
+    /// Upgrades the configuration to the current layout, backing up the original first. Returns
+    /// what changed – nothing, if it was already up to date.
+    fn upgrade(&self) -> Result<Vec<String>, ConfigurationError> {
+        let mut configuration = self.fetch()?;
+
+        if !configuration_migration::needs_migration(&configuration, &self.location())? {
+            return Ok(Vec::new());
+        }
+
+        let backup = self.backup()?;
+        let mut changes = configuration_migration::migrate(&mut configuration);
+        self.save(&configuration)?;
+
+        changes.push(format!("backed up the original to {backup}"));
+        Ok(changes)
+    }
+
+    fn create_if_not_exists(&self) -> Result<(), ConfigurationError> {
+        if self.exists() {
+            log::debug!("Configuration exists at {}", self.location());
+            return Ok(());
+        }
+
+        log::info!(
+            "Creating Configuration from Defaults at {}",
+            self.location()
+        );
+
+        let configuration = AppConfiguration {
+            secret: AppConfiguration::generate_secret(),
+            ..Default::default()
+        };
+
+        self.save(&configuration)?;
+
+        // Printed rather than logged, so it doesn't end up in log files – and only to a terminal,
+        // since a service's output goes to the journal or the event log
+        if io::stdout().is_terminal() {
+            println!(
+                "Generated the secret {:?} for this machine, and saved it in {}. Make a note of it – it won't be shown again.",
+                configuration.secret,
+                self.location()
+            );
+        } else {
+            log::info!(
+                "Generated a secret for this machine – read it from {}",
+                self.location()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Creates the configuration if needed and upgrades it if it's from an older version
+    /// (explaining what changed in the log), then checks that it can be read back
+    fn validate(&self) -> Result<AppConfiguration, ConfigurationError> {
+        self.create_if_not_exists()?;
+
+        let changes = self.upgrade()?;
+
+        if !changes.is_empty() {
+            log::info!("Upgraded the configuration at {}:", self.location());
+
+            for change in &changes {
+                log::info!("  - {}", change);
+            }
+        }
+
+        self.fetch()
+    }
+}
+
+/// The environment variable that points every subcommand at a different configuration file, as
+/// `--config` does
+pub const CONFIGURATION_PATH_VARIABLE: &str = "SHUTDOWN_ON_LAN_CONFIG";
+
+static PATH_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Uses the configuration file at `path` instead of the platform's usual location (or the
+/// registry, on Windows) for the rest of the process. Only the first call has any effect.
+pub fn set_path_override(path: PathBuf) {
+    log::debug!("Using the configuration file at {:?}", path);
+    let _ = PATH_OVERRIDE.set(path);
+}
+
+/// The storage to use – the file given with `--config`, or the platform's usual location
+pub fn storage() -> Box<dyn AppConfigurationStorage> {
+    match PATH_OVERRIDE.get() {
+        Some(path) => storage_for_path(path),
+        None => platform_storage(),
+    }
+}
+
+/// A file backend for `path`, picked by its extension. `.plist`, `.toml` and `.json` files are
+/// read as such, and anything else is INI, like `/etc/ShutDownOnLan`.
+pub fn storage_for_path(path: &Path) -> Box<dyn AppConfigurationStorage> {
+    let extension = path
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_ascii_lowercase);
+
+    match extension.as_deref() {
+        Some("plist") => Box::new(PlistFile::new(path)),
+        Some("toml") => Box::new(TomlFile::new(path)),
+        Some("json") => Box::new(JsonFile::new(path)),
+        _ => Box::new(IniFile::new(path)),
+    }
+}
+
+/// The file name used for the TOML configuration (and for JSON, with its own extension) in each
+/// platform's configuration directory
+const CONFIGURATION_FILE_STEM: &str = "shutdown-on-lan";
+
+/// TOML (or JSON) in the platform's configuration directory, falling back to the format the
+/// platform used before
+fn platform_storage() -> Box<dyn AppConfigurationStorage> {
+    let directory = configuration_directory();
+
+    Box::new(PreferredStorage {
+        preferred: vec![
+            Box::new(TomlFile::new(
+                directory.join(format!("{CONFIGURATION_FILE_STEM}.toml")),
+            )),
+            Box::new(JsonFile::new(
+                directory.join(format!("{CONFIGURATION_FILE_STEM}.json")),
+            )),
+        ],
+        legacy: legacy_storage(),
+    })
+}
+
+/// Where the platform's configuration file would be if `root` were the root directory – for
+/// installing into a staging area
+#[cfg(not(windows))]
+pub fn rooted_storage(root: &Path) -> Box<dyn AppConfigurationStorage> {
+    let path = configuration_directory().join(format!("{CONFIGURATION_FILE_STEM}.toml"));
+    let relative = path.strip_prefix("/").unwrap_or(&path);
+
+    Box::new(TomlFile::new(root.join(relative)))
+}
+
+#[cfg(target_os = "linux")]
+fn configuration_directory() -> PathBuf {
+    PathBuf::from("/etc")
+}
+
+#[cfg(target_os = "macos")]
+fn configuration_directory() -> PathBuf {
+    extern crate dirs;
+
+    let directory = if whoami::username() == "root" {
+        PathBuf::from("/Library/Application Support/ShutdownOnLan")
+    } else {
+        dirs::home_dir()
+            .expect("failed to find home directory")
+            .join("Library")
+            .join("Application Support")
+            .join("ShutdownOnLan")
+    };
+
+    log::debug!("Detected Configuration Path: {:?}", directory);
+
+    directory
+}
+
+#[cfg(windows)]
+fn configuration_directory() -> PathBuf {
+    let program_data = std::env::var_os("ProgramData").unwrap_or_else(|| "C:\\ProgramData".into());
+
+    PathBuf::from(program_data).join("ShutdownOnLan")
+}
+
+#[cfg(target_os = "linux")]
+fn legacy_storage() -> Box<dyn AppConfigurationStorage> {
+    Box::new(IniFile::new("/etc/ShutDownOnLan"))
+}
+
+#[cfg(target_os = "macos")]
+fn legacy_storage() -> Box<dyn AppConfigurationStorage> {
+    Box::new(PlistFile::new(
+        configuration_directory().join("ShutDownOnLan.plist"),
+    ))
+}
+
+#[cfg(windows)]
+fn legacy_storage() -> Box<dyn AppConfigurationStorage> {
+    Box::new(RegistryStorage::new(r"SOFTWARE\ShutdownOnLan"))
+}
+
+/// Reads the first of the `preferred` backends that exists, or `legacy` if none of them do.
+/// Saving always writes to a preferred backend, so a legacy configuration is upgraded the first
+/// time it's changed, and ignored from then on. `upgrade` copies it across straight away.
+pub struct PreferredStorage {
+    preferred: Vec<Box<dyn AppConfigurationStorage>>,
+    legacy: Box<dyn AppConfigurationStorage>,
+}
+
+impl PreferredStorage {
+    /// The backend that's currently in use, if any of them exist
+    fn active(&self) -> Option<&dyn AppConfigurationStorage> {
+        self.preferred
+            .iter()
+            .find(|storage| storage.exists())
+            .or_else(|| Some(&self.legacy).filter(|legacy| legacy.exists()))
+            .map(|storage| storage.as_ref())
+    }
+
+    /// Where saving writes to
+    fn destination(&self) -> &dyn AppConfigurationStorage {
+        self.preferred
+            .iter()
+            .find(|storage| storage.exists())
+            .unwrap_or(&self.preferred[0])
+            .as_ref()
+    }
+}
+
+impl AppConfigurationStorage for PreferredStorage {
+    fn fetch(&self) -> Result<AppConfiguration, ConfigurationError> {
+        let storage = self
+            .active()
+            .ok_or_else(|| ConfigurationError::MissingConfiguration {
+                location: self.location(),
+            })?;
+
+        if !self.preferred.iter().any(|preferred| preferred.exists()) {
+            log::debug!(
+                "Reading the older configuration at {} – saving any changes will move it to {}",
+                storage.location(),
+                self.destination().location()
+            );
+        }
+
+        storage.fetch()
+    }
+
+    fn save(&self, configuration: &AppConfiguration) -> Result<(), ConfigurationError> {
+        self.destination().save(configuration)
+    }
+
+    fn delete(&self) -> Result<(), ConfigurationError> {
+        for storage in &self.preferred {
+            storage.delete()?;
+        }
+
+        self.legacy.delete()
+    }
+
+    fn exists(&self) -> bool {
+        self.active().is_some()
+    }
+
+    fn location(&self) -> String {
+        self.active()
+            .unwrap_or_else(|| self.destination())
+            .location()
+    }
+
+    fn check_writable(&self) -> Result<(), ConfigurationError> {
+        self.destination().check_writable()
+    }
+
+    fn check_permissions(&self) -> Result<(), ConfigurationError> {
+        match self.active() {
+            Some(storage) => storage.check_permissions(),
+            None => Ok(()),
+        }
+    }
+
+    fn path(&self) -> Option<PathBuf> {
+        self.active().unwrap_or_else(|| self.destination()).path()
+    }
+
+    fn backup(&self) -> Result<String, ConfigurationError> {
+        self.active()
+            .ok_or_else(|| ConfigurationError::MissingConfiguration {
+                location: self.location(),
+            })?
+            .backup()
+    }
+
+    /// Copies a legacy configuration to the preferred format, or upgrades the preferred one in
+    /// place. The legacy configuration is kept as a backup – renamed, if it's a file, so that
+    /// it's clear it's no longer read.
+    fn upgrade(&self) -> Result<Vec<String>, ConfigurationError> {
+        if self.preferred.iter().any(|preferred| preferred.exists()) || !self.legacy.exists() {
+            return self.destination().upgrade();
+        }
+
+        let destination = self.destination();
+        let mut configuration = self.legacy.fetch()?;
+        configuration_migration::needs_migration(&configuration, &self.legacy.location())?;
+
+        let mut changes = vec![format!(
+            "copied the configuration from {} to {}",
+            self.legacy.location(),
+            destination.location()
+        )];
+
+        changes.extend(configuration_migration::migrate(&mut configuration));
+        destination.save(&configuration)?;
+
+        changes.push(match self.legacy.retire() {
+            Ok(Some(backup)) => {
+                format!("moved the original to {backup} as a backup – it's no longer read")
+            }
+            Ok(None) => format!(
+                "left the original at {} as a backup – it's no longer read, and can be deleted",
+                self.legacy.location()
+            ),
+            Err(error) => format!(
+                "left the original at {} – it's no longer read, and can be deleted ({})",
+                self.legacy.location(),
+                error
+            ),
+        });
+
+        Ok(changes)
+    }
+}
+
+/// A name for a backup taken now, so that upgrades never overwrite an earlier backup
+fn backup_suffix() -> String {
+    Local::now().format("%Y%m%d%H%M%S.bak").to_string()
+}
+
+/// How a configuration file is written out
+pub trait FileFormat {
+    fn parse(bytes: &[u8]) -> Result<AppConfiguration, String>;
+    fn serialize(configuration: &AppConfiguration) -> Result<Vec<u8>, String>;
+}
+
+/// A configuration stored in a file, in the format `F`
+pub struct ConfigurationFile<F> {
+    path: PathBuf,
+    format: PhantomData<F>,
+}
+
+pub type IniFile = ConfigurationFile<Ini>;
+pub type PlistFile = ConfigurationFile<Plist>;
+pub type TomlFile = ConfigurationFile<Toml>;
+pub type JsonFile = ConfigurationFile<Json>;
+
+impl<F: FileFormat> ConfigurationFile<F> {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        ConfigurationFile {
+            path: path.into(),
+            format: PhantomData,
+        }
+    }
+
+    fn unwritable(&self, source: io::Error) -> ConfigurationError {
+        ConfigurationError::ConfigurationStorageUnwritable {
+            location: self.location(),
+            source,
+        }
+    }
+
+    /// Where a backup taken now goes – alongside the file, with the time in its name
+    fn backup_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}", backup_suffix()));
+        self.path.with_file_name(name)
+    }
+}
+
+impl<F: FileFormat> AppConfigurationStorage for ConfigurationFile<F> {
+    fn fetch(&self) -> Result<AppConfiguration, ConfigurationError> {
+        log::debug!("Reading configuration from {:?}", self.path);
+
+        let bytes = std::fs::read(&self.path).map_err(|error| match error.kind() {
+            io::ErrorKind::NotFound => ConfigurationError::MissingConfiguration {
+                location: self.location(),
+            },
+            _ => ConfigurationError::UnreadableConfiguration {
+                location: self.location(),
+                source: error,
+            },
+        })?;
+
+        F::parse(&bytes).map_err(|message| ConfigurationError::UnparseableConfiguration {
+            location: self.location(),
+            message,
+        })
+    }
+
+    fn save(&self, configuration: &AppConfiguration) -> Result<(), ConfigurationError> {
+        log::debug!("Writing configuration to {:?}", self.path);
+
+        let bytes =
+            F::serialize(configuration).map_err(ConfigurationError::InvalidConfiguration)?;
+
+        if let Some(directory) = self
+            .path
+            .parent()
+            .filter(|directory| !directory.as_os_str().is_empty() && !directory.is_dir())
+        {
+            log::debug!("Creating configuration storage at {:?}", directory);
+            std::fs::create_dir_all(directory).map_err(|error| self.unwritable(error))?;
+        }
+
+        write_privately(&self.path, &bytes).map_err(|error| self.unwritable(error))
+    }
+
+    fn delete(&self) -> Result<(), ConfigurationError> {
+        match std::fs::remove_file(&self.path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(self.unwritable(error)),
+            _ => Ok(()),
+        }
+    }
+
+    fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    fn location(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn backup(&self) -> Result<String, ConfigurationError> {
+        let backup = self.backup_path();
+
+        log::debug!("Backing up {:?} to {:?}", self.path, backup);
+        fs::read(&self.path)
+            .and_then(|bytes| write_privately(&backup, &bytes))
+            .map_err(|error| ConfigurationError::ConfigurationStorageUnwritable {
+                location: backup.display().to_string(),
+                source: error,
+            })?;
+
+        Ok(backup.display().to_string())
+    }
+
+    fn retire(&self) -> Result<Option<String>, ConfigurationError> {
+        let backup = self.backup_path();
+
+        log::debug!("Moving {:?} to {:?}", self.path, backup);
+        fs::rename(&self.path, &backup).map_err(|error| self.unwritable(error))?;
+
+        Ok(Some(backup.display().to_string()))
+    }
+
+    fn check_writable(&self) -> Result<(), ConfigurationError> {
+        configuration_validation::check_writable("configuration file", &self.path)
+    }
+
+    fn path(&self) -> Option<PathBuf> {
+        Some(self.path.clone())
+    }
+
+    #[cfg(unix)]
+    fn check_permissions(&self) -> Result<(), ConfigurationError> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(()),
+        };
+
+        let mode = metadata.permissions().mode() & 0o777;
+
+        if mode & 0o077 != 0 {
+            return Err(ConfigurationError::InsecurePermissions {
+                location: self.location(),
+                mode,
+            });
+        }
+
+        // Whoever owns the file can change its permissions, and the secret – so it should be
+        // root, or whoever's running the tool (such as a user trying it out with `--config`)
+        let owner = metadata.uid();
+
+        if owner != 0 && owner != geteuid().as_raw() {
+            return Err(ConfigurationError::InsecureOwner {
+                location: self.location(),
+                owner,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Replaces the file at `path` with `bytes` in one step, so that it's never left half-written:
+/// they're written to a temporary file alongside it, flushed to disk, then renamed over it. The
+/// file can only be read by its owner, which is whoever wrote it (rather than whoever owned the
+/// file it replaced) – root, when the listener is running as a service.
+fn write_privately(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let directory = path
+        .parent()
+        .filter(|directory| !directory.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}.tmp", std::process::id()));
+    let temporary = directory.join(name);
+
+    let _ = fs::remove_file(&temporary);
+
+    let result = (|| {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        let mut file = options.open(&temporary)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        drop(file);
+
+        #[cfg(windows)]
+        restrict_to_administrators(&temporary);
+
+        fs::rename(&temporary, path)?;
+
+        // Make sure the rename itself survives a crash
+        #[cfg(unix)]
+        fs::File::open(directory)?.sync_all()?;
+
+        Ok(())
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+
+    result
+}
+
+/// Replaces the permissions the file inherited from its folder with full control for SYSTEM and
+/// the Administrators group only. They're given by SID, since the names are translated.
+#[cfg(windows)]
+fn restrict_to_administrators(path: &Path) {
+    let result = Command::new("icacls")
+        .arg(path)
+        .args([
+            "/inheritance:r",
+            "/grant:r",
+            "*S-1-5-18:F",
+            "*S-1-5-32-544:F",
+        ])
+        .output();
+
+    match result {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => log::warn!(
+            "Unable to restrict access to {:?}: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(error) => log::warn!("Unable to restrict access to {:?}: {}", path, error),
+    }
+}
+
+/// INI files can only carry the port, addresses and secret – everything else takes its default
+/// value
+pub struct Ini;
+
+/// `serde_ini` can only represent flat string and number values, so the INI file carries the
+/// core settings (with addresses comma-separated, as in the registry)
+#[derive(Serialize, Deserialize)]
+struct IniConfiguration {
+    #[serde(default)]
+    version: u32,
+    port_number: u16,
+    addresses: String,
+    secret: String,
+}
+
+impl FileFormat for Ini {
+    fn parse(bytes: &[u8]) -> Result<AppConfiguration, String> {
+        let string = std::str::from_utf8(bytes).map_err(|error| error.to_string())?;
+        let ini: IniConfiguration =
+            serde_ini::from_str(string).map_err(|error| error.to_string())?;
+
+        let mut configuration = AppConfiguration {
+            version: ini.version,
+            port_number: ini.port_number,
+            secret: ini.secret,
+            ..Default::default()
+        };
+
+        configuration
+            .set_addresses(ini.addresses)
+            .map_err(|error| error.to_string())?;
+
+        Ok(configuration)
+    }
+
+    fn serialize(configuration: &AppConfiguration) -> Result<Vec<u8>, String> {
+        let ini = IniConfiguration {
+            version: configuration.version,
+            port_number: configuration.port_number,
+            addresses: configuration.joined_addresses(),
+            secret: configuration.secret.clone(),
+        };
+
+        serde_ini::to_string(&ini)
+            .map(String::into_bytes)
+            .map_err(|error| error.to_string())
+    }
+}
+
+pub struct Plist;
+
+impl FileFormat for Plist {
+    fn parse(bytes: &[u8]) -> Result<AppConfiguration, String> {
+        plist::from_bytes(bytes).map_err(|error| error.to_string())
+    }
+
+    fn serialize(configuration: &AppConfiguration) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        plist::to_writer_xml(&mut bytes, configuration).map_err(|error| error.to_string())?;
+        Ok(bytes)
+    }
+}
+
+pub struct Toml;
+
+impl FileFormat for Toml {
+    fn parse(bytes: &[u8]) -> Result<AppConfiguration, String> {
+        let string = std::str::from_utf8(bytes).map_err(|error| error.to_string())?;
+        toml::from_str(string).map_err(|error| error.to_string())
+    }
+
+    fn serialize(configuration: &AppConfiguration) -> Result<Vec<u8>, String> {
+        toml::to_string_pretty(configuration)
+            .map(String::into_bytes)
+            .map_err(|error| error.to_string())
+    }
+}
+
+pub struct Json;
+
+impl FileFormat for Json {
+    fn parse(bytes: &[u8]) -> Result<AppConfiguration, String> {
+        serde_json::from_slice(bytes).map_err(|error| error.to_string())
+    }
+
+    fn serialize(configuration: &AppConfiguration) -> Result<Vec<u8>, String> {
+        serde_json::to_vec_pretty(configuration).map_err(|error| error.to_string())
+    }
+}
+
+/// A configuration held in memory, for tests
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStorage {
+    configuration: std::sync::Mutex<Option<AppConfiguration>>,
+}
+
+#[cfg(test)]
+impl MemoryStorage {
+    pub fn new(configuration: Option<AppConfiguration>) -> MemoryStorage {
+        MemoryStorage {
+            configuration: std::sync::Mutex::new(configuration),
+        }
+    }
+
+    fn configuration(&self) -> std::sync::MutexGuard<'_, Option<AppConfiguration>> {
+        self.configuration
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+#[cfg(test)]
+impl AppConfigurationStorage for MemoryStorage {
+    fn fetch(&self) -> Result<AppConfiguration, ConfigurationError> {
+        self.configuration()
+            .clone()
+            .ok_or_else(|| ConfigurationError::MissingConfiguration {
+                location: self.location(),
+            })
+    }
+
+    fn save(&self, configuration: &AppConfiguration) -> Result<(), ConfigurationError> {
+        *self.configuration() = Some(configuration.clone());
+        Ok(())
+    }
+
+    fn delete(&self) -> Result<(), ConfigurationError> {
+        *self.configuration() = None;
+        Ok(())
+    }
+
+    fn exists(&self) -> bool {
+        self.configuration().is_some()
+    }
+
+    fn location(&self) -> String {
+        "memory".to_string()
+    }
+
+    /// Nothing else can change a configuration in memory, so there's nothing to protect
+    fn backup(&self) -> Result<String, ConfigurationError> {
+        Ok(self.location())
+    }
+}
+
+/// A configuration stored as values of a key under `HKEY_LOCAL_MACHINE`. Like INI files, only
+/// the port, addresses and secret are stored.
+#[cfg(windows)]
+pub struct RegistryStorage {
+    path: PathBuf,
+}
+
+#[cfg(windows)]
+const ADDRESSES_VALUE: &str = "ip_addresses";
+#[cfg(windows)]
+const PORT_VALUE: &str = "port";
+#[cfg(windows)]
+const SECRET_VALUE: &str = "secret";
+#[cfg(windows)]
+const VERSION_VALUE: &str = "version";
+
+#[cfg(windows)]
+impl RegistryStorage {
+    pub fn new(path: impl Into<PathBuf>) -> RegistryStorage {
+        RegistryStorage { path: path.into() }
+    }
+
+    fn open(&self) -> Result<RegKey, ConfigurationError> {
+        RegKey::predef(HKEY_LOCAL_MACHINE)
+            .open_subkey(&self.path)
+            .map_err(|error| match error.kind() {
+                io::ErrorKind::NotFound => ConfigurationError::MissingConfiguration {
+                    location: self.location(),
+                },
+                _ => ConfigurationError::UnreadableConfiguration {
+                    location: self.location(),
+                    source: error,
+                },
+            })
+    }
+
+    fn value_location(&self, name: &str) -> String {
+        format!("{}\\{}", self.location(), name)
+    }
+
+    fn read<T: winreg::types::FromRegValue>(
+        &self,
+        key: &RegKey,
+        name: &str,
+    ) -> Result<T, ConfigurationError> {
+        key.get_value(name)
+            .map_err(|error| ConfigurationError::UnreadableConfiguration {
+                location: self.value_location(name),
+                source: error,
+            })
+    }
+
+    fn write<T: winreg::types::ToRegValue>(
+        &self,
+        key: &RegKey,
+        name: &str,
+        value: &T,
+    ) -> Result<(), ConfigurationError> {
+        key.set_value(name, value).map_err(|error| {
+            ConfigurationError::ConfigurationStorageUnwritable {
+                location: self.value_location(name),
+                source: error,
+            }
+        })
+    }
+}
+
+#[cfg(windows)]
+impl AppConfigurationStorage for RegistryStorage {
+    fn fetch(&self) -> Result<AppConfiguration, ConfigurationError> {
+        log::info!("Looking up configuration in {}", self.location());
+
+        let key = self.open()?;
+        let port: u32 = self.read(&key, PORT_VALUE)?;
+
+        let mut configuration = AppConfiguration {
+            // Keys written before the version was added don't have one
+            version: key.get_value(VERSION_VALUE).unwrap_or(0),
+            port_number: u16::try_from(port).map_err(|_| {
+                ConfigurationError::UnparseableConfiguration {
+                    location: self.value_location(PORT_VALUE),
+                    message: format!("{port} isn't a valid port number"),
+                }
+            })?,
+            secret: self.read(&key, SECRET_VALUE)?,
+            ..Default::default()
+        };
+
+        configuration
+            .set_addresses(self.read(&key, ADDRESSES_VALUE)?)
+            .map_err(|error| ConfigurationError::UnparseableConfiguration {
+                location: self.value_location(ADDRESSES_VALUE),
+                message: error.to_string(),
+            })?;
+
+        Ok(configuration)
+    }
+
+    fn save(&self, configuration: &AppConfiguration) -> Result<(), ConfigurationError> {
+        let (key, _) = RegKey::predef(HKEY_LOCAL_MACHINE)
+            .create_subkey(&self.path)
+            .map_err(|error| ConfigurationError::ConfigurationStorageUnwritable {
+                location: self.location(),
+                source: error,
+            })?;
+
+        let addresses = configuration.joined_addresses();
+        self.write(&key, ADDRESSES_VALUE, &addresses)?;
+        log::debug!("Set IP Addresses to {}", addresses);
+
+        self.write(&key, PORT_VALUE, &u32::from(configuration.port_number))?;
+        log::debug!("Set Port to {}", configuration.port_number);
+
+        self.write(&key, SECRET_VALUE, &configuration.secret)?;
+        log::debug!("Set the secret");
+
+        self.write(&key, VERSION_VALUE, &configuration.version)?;
+
+        Ok(())
+    }
+
+    fn delete(&self) -> Result<(), ConfigurationError> {
+        match RegKey::predef(HKEY_LOCAL_MACHINE).delete_subkey_all(&self.path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                Err(ConfigurationError::ConfigurationStorageUnwritable {
+                    location: self.location(),
+                    source: error,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Only a complete configuration counts – a key with missing values is filled in with the
+    /// defaults
+    fn exists(&self) -> bool {
+        self.fetch().is_ok()
+    }
+
+    fn location(&self) -> String {
+        format!("HKEY_LOCAL_MACHINE\\{}", self.path.display())
+    }
+
+    fn check_writable(&self) -> Result<(), ConfigurationError> {
+        let result = match RegKey::predef(HKEY_LOCAL_MACHINE)
+            .open_subkey_with_flags(&self.path, KEY_SET_VALUE)
+        {
+            // Saving creates the key
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result.map(drop),
+        };
+
+        result.map_err(|error| ConfigurationError::ConfigurationStorageUnwritable {
+            location: self.location(),
+            source: error,
+        })
+    }
+
+    /// Copies the values to a sibling key
+    fn backup(&self) -> Result<String, ConfigurationError> {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", backup_suffix()));
+        let backup = RegistryStorage::new(path);
+
+        log::debug!("Backing up {} to {}", self.location(), backup.location());
+        backup.save(&self.fetch()?)?;
+
+        Ok(backup.location())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::CONFIGURATION_VERSION;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use tempfile::TempDir;
+
+    /// A configuration that differs from the defaults in each of the core settings, and in a
+    /// few of the sections
+    fn configuration() -> AppConfiguration {
+        let mut configuration = AppConfiguration {
+            port_number: 5001,
+            addresses: vec![
+                IpAddr::V4(Ipv4Addr::new(10, 0, 1, 100)),
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ],
+            secret: "a secret, with = and \"quotes\"".to_string(),
+            ..Default::default()
+        };
+
+        configuration.audit.enabled = !configuration.audit.enabled;
+        configuration.info.require_secret = !configuration.info.require_secret;
+        configuration.reload.poll_interval_seconds = 30;
+        configuration
+    }
+
+    fn round_trip<F: FileFormat>(file_name: &str) -> AppConfiguration {
+        let directory = TempDir::new().unwrap();
+        let storage = ConfigurationFile::<F>::new(directory.path().join(file_name));
+        assert!(!storage.exists());
+
+        storage.save(&configuration()).unwrap();
+        assert!(storage.exists());
+
+        storage.fetch().unwrap()
+    }
+
+    #[test]
+    fn toml_files_round_trip() {
+        assert_eq!(round_trip::<Toml>("shutdown-on-lan.toml"), configuration());
+    }
+
+    #[test]
+    fn json_files_round_trip() {
+        assert_eq!(round_trip::<Json>("shutdown-on-lan.json"), configuration());
+    }
+
+    #[test]
+    fn plist_files_round_trip() {
+        assert_eq!(round_trip::<Plist>("ShutDownOnLan.plist"), configuration());
+    }
+
+    #[test]
+    fn ini_files_round_trip_the_core_settings() {
+        let expected = configuration();
+        let configuration = round_trip::<Ini>("ShutDownOnLan");
+
+        assert_eq!(configuration.version, expected.version);
+        assert_eq!(configuration.port_number, expected.port_number);
+        assert_eq!(configuration.addresses, expected.addresses);
+        assert_eq!(configuration.secret, expected.secret);
+        assert_eq!(configuration.audit, AppConfiguration::default().audit);
+    }
+
+    #[test]
+    fn missing_files_are_reported_as_missing() {
+        let directory = TempDir::new().unwrap();
+        let storage = TomlFile::new(directory.path().join("shutdown-on-lan.toml"));
+
+        assert!(matches!(
+            storage.fetch(),
+            Err(ConfigurationError::MissingConfiguration { .. })
+        ));
+    }
+
+    #[test]
+    fn unparseable_files_are_reported_as_such() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("shutdown-on-lan.toml");
+        fs::write(&path, "port_number = \"not a number\"").unwrap();
+
+        assert!(matches!(
+            TomlFile::new(path).fetch(),
+            Err(ConfigurationError::UnparseableConfiguration { .. })
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn files_are_only_readable_by_their_owner() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("shutdown-on-lan.toml");
+        let storage = TomlFile::new(&path);
+
+        storage.save(&configuration()).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+
+        assert_eq!(mode, 0o600);
+        assert!(storage.check_permissions().is_ok());
+    }
+
+    /// Gives the file at `path` to an ordinary user, if the tests are running as root
+    #[cfg(unix)]
+    fn give_away(path: &Path) -> bool {
+        std::os::unix::fs::chown(path, Some(1000), Some(1000)).is_ok() && geteuid().is_root()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn files_belonging_to_someone_else_are_reported() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("shutdown-on-lan.toml");
+        let storage = TomlFile::new(&path);
+        storage.save(&configuration()).unwrap();
+
+        if !give_away(&path) {
+            return;
+        }
+
+        assert!(matches!(
+            storage.check_permissions(),
+            Err(ConfigurationError::InsecureOwner { owner: 1000, .. })
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saving_takes_ownership_of_the_file() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("shutdown-on-lan.toml");
+        let storage = TomlFile::new(&path);
+        storage.save(&configuration()).unwrap();
+
+        if !give_away(&path) {
+            return;
+        }
+
+        storage.save(&configuration()).unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().uid(), geteuid().as_raw());
+        assert!(storage.check_permissions().is_ok());
+    }
+
+    #[test]
+    fn backups_are_copies_of_the_file() {
+        let directory = TempDir::new().unwrap();
+        let storage = TomlFile::new(directory.path().join("shutdown-on-lan.toml"));
+        storage.save(&configuration()).unwrap();
+
+        let backup = storage.backup().unwrap();
+
+        assert_ne!(backup, storage.location());
+        assert_eq!(TomlFile::new(backup).fetch().unwrap(), configuration());
+    }
+
+    fn preferred_storage(
+        preferred: Option<AppConfiguration>,
+        fallback: Option<AppConfiguration>,
+        legacy: Option<AppConfiguration>,
+    ) -> PreferredStorage {
+        PreferredStorage {
+            preferred: vec![
+                Box::new(MemoryStorage::new(preferred)),
+                Box::new(MemoryStorage::new(fallback)),
+            ],
+            legacy: Box::new(MemoryStorage::new(legacy)),
+        }
+    }
+
+    fn with_port(port_number: u16) -> Option<AppConfiguration> {
+        Some(AppConfiguration {
+            port_number,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn the_first_preferred_backend_takes_precedence() {
+        let storage = preferred_storage(with_port(1), with_port(2), with_port(3));
+
+        assert_eq!(storage.fetch().unwrap().port_number, 1);
+    }
+
+    #[test]
+    fn later_preferred_backends_are_read_when_earlier_ones_are_missing() {
+        let storage = preferred_storage(None, with_port(2), with_port(3));
+
+        assert_eq!(storage.fetch().unwrap().port_number, 2);
+    }
+
+    #[test]
+    fn the_legacy_backend_is_read_when_no_preferred_one_exists() {
+        let storage = preferred_storage(None, None, with_port(3));
+
+        assert!(storage.exists());
+        assert_eq!(storage.fetch().unwrap().port_number, 3);
+    }
+
+    #[test]
+    fn nothing_existing_is_reported_as_missing() {
+        let storage = preferred_storage(None, None, None);
+
+        assert!(!storage.exists());
+        assert!(matches!(
+            storage.fetch(),
+            Err(ConfigurationError::MissingConfiguration { .. })
+        ));
+    }
+
+    #[test]
+    fn saving_writes_to_the_preferred_backend_in_use() {
+        let storage = preferred_storage(None, with_port(2), with_port(3));
+
+        storage.save(&with_port(4).unwrap()).unwrap();
+
+        assert!(!storage.preferred[0].exists());
+        assert_eq!(storage.preferred[1].fetch().unwrap().port_number, 4);
+        assert_eq!(storage.legacy.fetch().unwrap().port_number, 3);
+    }
+
+    #[test]
+    fn saving_a_legacy_configuration_writes_to_the_first_preferred_backend() {
+        let storage = preferred_storage(None, None, with_port(3));
+
+        storage.save(&with_port(4).unwrap()).unwrap();
+
+        assert_eq!(storage.fetch().unwrap().port_number, 4);
+        assert_eq!(storage.preferred[0].fetch().unwrap().port_number, 4);
+        assert_eq!(storage.legacy.fetch().unwrap().port_number, 3);
+    }
+
+    #[test]
+    fn upgrading_leaves_a_legacy_configuration_that_cant_be_moved() {
+        let legacy = AppConfiguration {
+            version: 0,
+            ..with_port(3).unwrap()
+        };
+        let storage = preferred_storage(None, None, Some(legacy.clone()));
+
+        assert!(!storage.upgrade().unwrap().is_empty());
+
+        let upgraded = storage.preferred[0].fetch().unwrap();
+        assert_eq!(upgraded.version, CONFIGURATION_VERSION);
+        assert_eq!(upgraded.port_number, 3);
+        assert_eq!(storage.legacy.fetch().unwrap(), legacy);
+    }
+
+    #[test]
+    fn upgrading_moves_a_legacy_file_out_of_the_way() {
+        let directory = TempDir::new().unwrap();
+        let legacy_path = directory.path().join("ShutDownOnLan");
+        let legacy = IniFile::new(&legacy_path);
+        legacy.save(&with_port(3).unwrap()).unwrap();
+
+        let storage = PreferredStorage {
+            preferred: vec![Box::new(TomlFile::new(
+                directory.path().join("shutdown-on-lan.toml"),
+            ))],
+            legacy: Box::new(legacy),
+        };
+
+        let changes = storage.upgrade().unwrap();
+
+        assert_eq!(storage.fetch().unwrap().port_number, 3);
+        assert!(!legacy_path.exists());
+
+        let backups: Vec<_> = fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("ShutDownOnLan.") && name.ends_with(".bak"))
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert!(
+            changes.last().unwrap().contains(&backups[0]),
+            "{:?}",
+            changes
+        );
+    }
+
+    #[test]
+    fn up_to_date_configurations_are_left_alone() {
+        let storage = preferred_storage(with_port(1), None, None);
+
+        assert!(storage.upgrade().unwrap().is_empty());
+    }
+
+    #[test]
+    fn configurations_from_newer_versions_are_refused() {
+        let newer = AppConfiguration {
+            version: CONFIGURATION_VERSION + 1,
+            ..Default::default()
+        };
+        let storage = preferred_storage(None, None, Some(newer));
+
+        assert!(matches!(
+            storage.upgrade(),
+            Err(ConfigurationError::UnsupportedConfigurationVersion { .. })
+        ));
+        assert!(!storage.preferred[0].exists());
+    }
+}
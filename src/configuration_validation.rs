@@ -0,0 +1,428 @@
+//! Looks for problems with a configuration that would stop the listener working, or make it
+//! unsafe, so that they can all be reported at once rather than found one at a time.
+
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::process;
+use systemstat::{Platform, System};
+
+use crate::configuration::{AppConfiguration, ConfigurationError, SecretReference, DEFAULT_SECRET};
+use crate::configuration_migration;
+use crate::configuration_storage::AppConfigurationStorage;
+
+/// Secrets shorter than this are easy to guess
+pub const MIN_SECRET_LENGTH: usize = 12;
+
+/// The longest secret the listener accepts
+pub const MAX_SECRET_LENGTH: usize = 4096;
+
+/// Ports below this can only be listened on as root, and belong to well-known services
+const FIRST_UNPRIVILEGED_PORT: u16 = 1024;
+
+/// Every problem with the configuration in `storage`. If it can't be read at all, that's the only
+/// problem reported.
+pub fn check(storage: &dyn AppConfigurationStorage) -> Vec<ConfigurationError> {
+    let configuration = match storage.fetch() {
+        Ok(configuration) => configuration,
+        Err(error) => return vec![error],
+    };
+
+    let mut problems = problems(&configuration);
+
+    if let Err(error) = storage.check_permissions() {
+        problems.insert(0, error);
+    }
+
+    if let Err(error) = storage.check_writable() {
+        problems.insert(0, error);
+    }
+
+    problems
+}
+
+/// Loads the configuration for the listener to run with – creating or upgrading it if need be,
+/// and reading the secret from wherever it's kept – and logs any problems that don't stop it
+/// from running
+pub fn load(storage: &dyn AppConfigurationStorage) -> Result<AppConfiguration, ConfigurationError> {
+    let configuration = storage.validate()?;
+    prepare(storage, configuration)
+}
+
+/// Reads the configuration again for a running listener. Unlike `load`, nothing is ever written:
+/// a missing configuration is an error rather than being created (it's likely to be halfway
+/// through being saved), and one from an older version is only upgraded in memory.
+pub fn reload(
+    storage: &dyn AppConfigurationStorage,
+) -> Result<AppConfiguration, ConfigurationError> {
+    let mut configuration = storage.fetch()?;
+
+    if configuration_migration::needs_migration(&configuration, &storage.location())? {
+        for change in configuration_migration::migrate(&mut configuration) {
+            log::debug!("Upgraded the configuration in memory: {}", change);
+        }
+    }
+
+    prepare(storage, configuration)
+}
+
+/// Reads the secret from wherever it's kept, and logs any problems that don't stop the listener
+/// from running
+fn prepare(
+    storage: &dyn AppConfigurationStorage,
+    configuration: AppConfiguration,
+) -> Result<AppConfiguration, ConfigurationError> {
+    let resolved = configuration.with_resolved_secret()?;
+
+    if let Err(problem) = storage.check_permissions() {
+        log::warn!("{}", problem);
+    }
+
+    let mut problems = problems(&configuration);
+
+    // `problems` only checks that a reference to the secret makes sense, so check the secret
+    // it led to as well
+    if SecretReference::parse(&configuration.secret).is_some() {
+        check_secret_value(&resolved.secret, &mut problems);
+    }
+
+    for problem in problems {
+        log::warn!("{}", problem);
+    }
+
+    Ok(resolved)
+}
+
+/// Why the listener won't start, when `check_default_secret` fails
+pub const REFUSING_DEFAULT_SECRET: &str = "Refusing to start with the default secret – set a new one with `shutdown-on-lan set --secret`, or set `insecure_allow_default_secret = true` in the configuration to start anyway";
+
+/// The listener refuses to serve with the default secret, unless the configuration explicitly
+/// allows it
+pub fn check_default_secret(configuration: &AppConfiguration) -> Result<(), ConfigurationError> {
+    if configuration.secret == DEFAULT_SECRET && !configuration.insecure_allow_default_secret {
+        return Err(ConfigurationError::DefaultSecret);
+    }
+
+    Ok(())
+}
+
+/// Every problem with `configuration`'s settings
+pub fn problems(configuration: &AppConfiguration) -> Vec<ConfigurationError> {
+    let mut problems = Vec::new();
+
+    check_addresses(&configuration.addresses, &mut problems);
+    check_port(configuration.port_number, &mut problems);
+    check_secret(configuration, &mut problems);
+
+    if configuration.audit.enabled {
+        problems.extend(check_writable("audit log", &configuration.audit.path).err());
+    }
+
+    if let Some(file) = &configuration.logging.file {
+        problems.extend(check_writable("log file", file).err());
+    }
+
+    problems
+}
+
+fn check_addresses(addresses: &[IpAddr], problems: &mut Vec<ConfigurationError>) {
+    if addresses.is_empty() {
+        problems.push(ConfigurationError::NoAddresses);
+        return;
+    }
+
+    let local_addresses = match local_addresses() {
+        Ok(local_addresses) => local_addresses,
+        Err(error) => {
+            log::debug!("Unable to list the network interfaces: {}", error);
+            return;
+        }
+    };
+
+    problems.extend(
+        addresses
+            .iter()
+            .filter(|address| !address.is_unspecified() && !local_addresses.contains(address))
+            .map(|&address| ConfigurationError::UnassignedAddress { address }),
+    );
+}
+
+/// The addresses assigned to this machine's network interfaces
+fn local_addresses() -> io::Result<HashSet<IpAddr>> {
+    let networks = System::new().networks()?;
+
+    Ok(networks
+        .values()
+        .flat_map(|network| &network.addrs)
+        .filter_map(|address| match address.addr {
+            systemstat::IpAddr::V4(address) => Some(IpAddr::V4(address)),
+            systemstat::IpAddr::V6(address) => Some(IpAddr::V6(address)),
+            _ => None,
+        })
+        .collect())
+}
+
+fn check_port(port: u16, problems: &mut Vec<ConfigurationError>) {
+    if port == 0 {
+        problems.push(ConfigurationError::InvalidPort { port });
+    } else if port < FIRST_UNPRIVILEGED_PORT {
+        problems.push(ConfigurationError::PrivilegedPort { port });
+    }
+}
+
+/// A reference to the secret is only checked for whether it makes sense, rather than read – the
+/// environment variable or credential it names may only be there for the service
+fn check_secret(configuration: &AppConfiguration, problems: &mut Vec<ConfigurationError>) {
+    match SecretReference::parse(&configuration.secret) {
+        Some(reference) => {
+            if let Err(reason) = reference.validate() {
+                problems.push(ConfigurationError::UnresolvableSecret {
+                    reference: reference.to_string(),
+                    reason,
+                });
+            }
+        }
+        None => check_secret_value(&configuration.secret, problems),
+    }
+}
+
+fn check_secret_value(secret: &str, problems: &mut Vec<ConfigurationError>) {
+    let length = secret.chars().count();
+
+    if secret == DEFAULT_SECRET {
+        problems.push(ConfigurationError::DefaultSecret);
+    } else if length < MIN_SECRET_LENGTH {
+        problems.push(ConfigurationError::WeakSecret {
+            length,
+            minimum: MIN_SECRET_LENGTH,
+        });
+    }
+
+    if length > MAX_SECRET_LENGTH {
+        problems.push(ConfigurationError::SecretTooLong {
+            length,
+            maximum: MAX_SECRET_LENGTH,
+        });
+    }
+}
+
+/// Checks that the file at `path` can be written to, or created if it doesn't exist yet (along
+/// with any missing directories), without changing it
+pub fn check_writable(setting: &str, path: &Path) -> Result<(), ConfigurationError> {
+    let result = if path.exists() {
+        OpenOptions::new().append(true).open(path).map(drop)
+    } else {
+        let directory = path
+            .ancestors()
+            .skip(1)
+            .find(|ancestor| ancestor.is_dir())
+            .unwrap_or_else(|| Path::new("."));
+
+        let probe = directory.join(format!(".shutdown-on-lan-check-{}", process::id()));
+
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&probe)
+            .and_then(|_| fs::remove_file(&probe))
+    };
+
+    result.map_err(|source| ConfigurationError::UnwritablePath {
+        setting: setting.to_string(),
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::CONFIGURATION_VERSION;
+    use crate::configuration_storage::MemoryStorage;
+    use tempfile::TempDir;
+
+    /// A configuration with nothing wrong with it
+    fn valid() -> AppConfiguration {
+        let mut configuration = AppConfiguration {
+            secret: "a perfectly good secret".to_string(),
+            ..Default::default()
+        };
+        configuration.audit.enabled = false;
+        configuration.logging.file = None;
+        configuration
+    }
+
+    fn check_with(change: impl FnOnce(&mut AppConfiguration)) -> Vec<ConfigurationError> {
+        let mut configuration = valid();
+        change(&mut configuration);
+        check(&MemoryStorage::new(Some(configuration)))
+    }
+
+    #[test]
+    fn valid_configurations_have_no_problems() {
+        assert!(check_with(|_| ()).is_empty());
+    }
+
+    #[test]
+    fn each_problem_is_found() {
+        type Change = fn(&mut AppConfiguration);
+        type Expected = fn(&ConfigurationError) -> bool;
+
+        let cases: Vec<(&str, Change, Expected)> = vec![
+            (
+                "no addresses",
+                |configuration| configuration.addresses.clear(),
+                |problem| matches!(problem, ConfigurationError::NoAddresses),
+            ),
+            (
+                "an address from the documentation range",
+                |configuration| configuration.addresses = vec!["192.0.2.1".parse().unwrap()],
+                |problem| matches!(problem, ConfigurationError::UnassignedAddress { .. }),
+            ),
+            (
+                "port 0",
+                |configuration| configuration.port_number = 0,
+                |problem| matches!(problem, ConfigurationError::InvalidPort { port: 0 }),
+            ),
+            (
+                "port 80",
+                |configuration| configuration.port_number = 80,
+                |problem| matches!(problem, ConfigurationError::PrivilegedPort { port: 80 }),
+            ),
+            (
+                "the default secret",
+                |configuration| configuration.secret = DEFAULT_SECRET.to_string(),
+                |problem| matches!(problem, ConfigurationError::DefaultSecret),
+            ),
+            (
+                "a short secret",
+                |configuration| configuration.secret = "short".to_string(),
+                |problem| {
+                    matches!(
+                        problem,
+                        ConfigurationError::WeakSecret {
+                            length: 5,
+                            minimum: MIN_SECRET_LENGTH
+                        }
+                    )
+                },
+            ),
+            (
+                "a long secret",
+                |configuration| configuration.secret = "x".repeat(MAX_SECRET_LENGTH + 1),
+                |problem| matches!(problem, ConfigurationError::SecretTooLong { .. }),
+            ),
+            (
+                "a credential name with a slash",
+                |configuration| configuration.secret = "credential:../secret".to_string(),
+                |problem| matches!(problem, ConfigurationError::UnresolvableSecret { .. }),
+            ),
+            (
+                "an empty environment variable name",
+                |configuration| configuration.secret = "env:".to_string(),
+                |problem| matches!(problem, ConfigurationError::UnresolvableSecret { .. }),
+            ),
+            (
+                "a log file that's a directory",
+                |configuration| configuration.logging.file = Some(std::env::temp_dir()),
+                |problem| matches!(problem, ConfigurationError::UnwritablePath { .. }),
+            ),
+        ];
+
+        for (description, change, expected) in cases {
+            let problems = check_with(change);
+
+            assert_eq!(problems.len(), 1, "{}: {:?}", description, problems);
+            assert!(expected(&problems[0]), "{}: {:?}", description, problems);
+        }
+    }
+
+    #[test]
+    fn every_problem_is_listed() {
+        let problems = check_with(|configuration| {
+            configuration.port_number = 0;
+            configuration.addresses.clear();
+            configuration.secret = DEFAULT_SECRET.to_string();
+        });
+
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+    }
+
+    #[test]
+    fn a_missing_configuration_is_the_only_problem() {
+        let problems = check(&MemoryStorage::new(None));
+
+        assert!(matches!(
+            problems.as_slice(),
+            [ConfigurationError::MissingConfiguration { .. }]
+        ));
+    }
+
+    #[test]
+    fn check_doesnt_read_secrets_that_only_the_service_can() {
+        for secret in &["env:SHUTDOWN_ON_LAN_TEST_UNSET", "credential:secret"] {
+            let problems = check_with(|configuration| configuration.secret = secret.to_string());
+
+            assert!(problems.is_empty(), "{}: {:?}", secret, problems);
+        }
+    }
+
+    #[test]
+    fn load_reads_the_secret() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("secret");
+        std::fs::write(&path, "a perfectly good secret\n").unwrap();
+
+        let mut configuration = valid();
+        configuration.secret = format!("file:{}", path.display());
+
+        let loaded = load(&MemoryStorage::new(Some(configuration))).unwrap();
+
+        assert_eq!(loaded.secret, "a perfectly good secret");
+    }
+
+    #[test]
+    fn load_fails_when_the_secret_cant_be_read() {
+        let mut configuration = valid();
+        configuration.secret = "env:SHUTDOWN_ON_LAN_TEST_UNSET".to_string();
+
+        let result = load(&MemoryStorage::new(Some(configuration)));
+
+        assert!(matches!(
+            result,
+            Err(ConfigurationError::UnresolvableSecret { .. })
+        ));
+    }
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let mut configuration = valid();
+        configuration.version = CONFIGURATION_VERSION + 1;
+        let storage = MemoryStorage::new(Some(configuration));
+
+        assert!(matches!(
+            load(&storage),
+            Err(ConfigurationError::UnsupportedConfigurationVersion { .. })
+        ));
+        assert!(matches!(
+            reload(&storage),
+            Err(ConfigurationError::UnsupportedConfigurationVersion { .. })
+        ));
+    }
+
+    #[test]
+    fn the_default_secret_is_refused_unless_allowed() {
+        let mut configuration = valid();
+        configuration.secret = DEFAULT_SECRET.to_string();
+
+        assert!(matches!(
+            check_default_secret(&configuration),
+            Err(ConfigurationError::DefaultSecret)
+        ));
+
+        configuration.insecure_allow_default_secret = true;
+        assert!(check_default_secret(&configuration).is_ok());
+    }
+}
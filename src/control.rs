@@ -0,0 +1,237 @@
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+#[cfg(windows)]
+use std::net::{TcpListener, TcpStream};
+
+use crate::status::{DaemonStatus, StatusReport};
+
+/// Settings for the local control channel that `shutdown-on-lan status` talks to. This is a Unix
+/// domain socket on Linux and macOS, and a loopback TCP port on Windows.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ControlConfiguration {
+    pub enabled: bool,
+
+    /// The socket path (or, on Windows, the loopback address and port)
+    pub address: String,
+}
+
+impl Default for ControlConfiguration {
+    fn default() -> Self {
+        ControlConfiguration {
+            enabled: true,
+            address: default_control_address().to_string(),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn default_control_address() -> &'static str {
+    "/run/shutdown-on-lan.sock"
+}
+
+#[cfg(target_os = "macos")]
+fn default_control_address() -> &'static str {
+    "/var/run/shutdownonlan.sock"
+}
+
+#[cfg(windows)]
+fn default_control_address() -> &'static str {
+    "127.0.0.1:53633"
+}
+
+const STATUS_COMMAND: &str = "STATUS";
+
+/// How long a client has to send its command before it's hung up on
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The reply to a control request: either the requested report, or why there isn't one
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum ControlResponse {
+    Status(Box<StatusReport>),
+    Error(String),
+}
+
+/// Starts answering control requests on a background thread
+pub fn serve(configuration: &ControlConfiguration, status: Arc<DaemonStatus>) {
+    if !configuration.enabled {
+        log::info!("The control channel is disabled");
+        return;
+    }
+
+    let listener = match bind(&configuration.address) {
+        Ok(listener) => listener,
+        Err(error) => {
+            log::error!(
+                "Unable to open the control channel at {}: {}",
+                configuration.address,
+                error
+            );
+            return;
+        }
+    };
+
+    log::info!("Control channel listening at {}", configuration.address);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let status = status.clone();
+
+                    // Each request gets its own thread, so a client that never sends its command
+                    // doesn't hold up anyone else until it times out
+                    thread::spawn(move || {
+                        let result = stream
+                            .set_read_timeout(Some(READ_TIMEOUT))
+                            .and_then(|_| handle_request(stream, &status));
+
+                        if let Err(error) = result {
+                            log::warn!("Unable to answer a control request: {}", error);
+                        }
+                    });
+                }
+                Err(error) => log::error!("Error accepting a control connection: {}", error),
+            }
+        }
+    });
+}
+
+fn handle_request<S: Read + Write>(stream: S, status: &DaemonStatus) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut command = String::new();
+    reader.read_line(&mut command)?;
+
+    let response = match command.trim() {
+        STATUS_COMMAND => ControlResponse::Status(Box::new(status.report())),
+        other => ControlResponse::Error(format!("Unknown command {other:?}")),
+    };
+
+    let mut stream = reader.into_inner();
+    serde_json::to_writer(&mut stream, &response)?;
+    stream.write_all(b"\n")
+}
+
+/// Asks the running daemon for its status
+pub fn request_status(configuration: &ControlConfiguration) -> io::Result<StatusReport> {
+    let mut stream = connect(&configuration.address)?;
+    stream.write_all(format!("{STATUS_COMMAND}\n").as_bytes())?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+
+    match serde_json::from_str(&response)? {
+        ControlResponse::Status(report) => Ok(*report),
+        ControlResponse::Error(error) => Err(io::Error::new(io::ErrorKind::InvalidData, error)),
+    }
+}
+
+#[cfg(unix)]
+fn bind(address: &str) -> io::Result<UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A socket left behind by a previous run would make the bind fail, but one that's still
+    // accepting connections belongs to another listener that's running
+    if std::path::Path::new(address).exists() {
+        if UnixStream::connect(address).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another listener is already using it",
+            ));
+        }
+
+        std::fs::remove_file(address)?;
+    }
+
+    let listener = UnixListener::bind(address)?;
+
+    // The status report doesn't include the secret, but it's still nobody else's business
+    std::fs::set_permissions(address, std::fs::Permissions::from_mode(0o600))?;
+
+    Ok(listener)
+}
+
+#[cfg(unix)]
+fn connect(address: &str) -> io::Result<UnixStream> {
+    UnixStream::connect(address)
+}
+
+#[cfg(windows)]
+fn bind(address: &str) -> io::Result<TcpListener> {
+    TcpListener::bind(address)
+}
+
+#[cfg(windows)]
+fn connect(address: &str) -> io::Result<TcpStream> {
+    TcpStream::connect(address)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::configuration::AppConfiguration;
+    use tempfile::TempDir;
+
+    fn configuration(directory: &TempDir) -> ControlConfiguration {
+        ControlConfiguration {
+            enabled: true,
+            address: directory.path().join("control.sock").display().to_string(),
+        }
+    }
+
+    fn status() -> Arc<DaemonStatus> {
+        Arc::new(DaemonStatus::new(&AppConfiguration::default()))
+    }
+
+    #[test]
+    fn status_is_reported_over_the_socket() {
+        let directory = TempDir::new().unwrap();
+        let configuration = configuration(&directory);
+        serve(&configuration, status());
+
+        let report = request_status(&configuration).unwrap();
+
+        assert_eq!(report.process_id, std::process::id());
+        assert_eq!(report.configuration.secret, "********");
+    }
+
+    #[test]
+    fn a_silent_client_doesnt_hold_up_others() {
+        let directory = TempDir::new().unwrap();
+        let configuration = configuration(&directory);
+        serve(&configuration, status());
+
+        let _silent = connect(&configuration.address).unwrap();
+
+        assert!(request_status(&configuration).is_ok());
+    }
+
+    #[test]
+    fn stale_sockets_are_replaced() {
+        let directory = TempDir::new().unwrap();
+        let configuration = configuration(&directory);
+        drop(UnixListener::bind(&configuration.address).unwrap());
+
+        assert!(bind(&configuration.address).is_ok());
+    }
+
+    #[test]
+    fn sockets_in_use_are_left_alone() {
+        let directory = TempDir::new().unwrap();
+        let configuration = configuration(&directory);
+        let _running = bind(&configuration.address).unwrap();
+
+        let error = bind(&configuration.address).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        assert!(connect(&configuration.address).is_ok());
+    }
+}
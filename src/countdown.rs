@@ -0,0 +1,259 @@
+//! Delays remote actions by the configured countdown, warning anyone using the machine first
+//! and, if the configuration allows it, letting them postpone the action.
+
+use chrono::Duration as ChronoDuration;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::audit::{AuditEvent, Origin};
+use crate::listener_service::{self, ListenerContext};
+use crate::notifications::Notifier;
+use crate::power::PowerAction;
+use crate::protocol::{ErrorKind, Response};
+
+/// How long before the action the warning is shown again, besides when the countdown starts
+const MILESTONES: [Duration; 2] = [Duration::from_secs(60), Duration::from_secs(10)];
+
+/// The action currently counting down, if there is one. Only one can be pending at a time.
+#[derive(Default)]
+pub struct Countdown {
+    pending: Mutex<Option<Pending>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Pending {
+    action: PowerAction,
+    deadline: Instant,
+}
+
+impl Countdown {
+    fn pending(&self) -> MutexGuard<'_, Option<Pending>> {
+        self.pending
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+/// Starts counting down to `action`, which the caller has already authorized. The maintenance
+/// windows and inhibitors are checked now, so that the client hears straight away if the action
+/// won't happen, and again once the countdown finishes.
+///
+/// Asking for the pending action again just reports how long is left.
+pub fn start(
+    action: PowerAction,
+    force: bool,
+    origin: Origin,
+    context: &Arc<ListenerContext>,
+) -> Response {
+    let mut pending = context.countdown.pending();
+
+    if let Some(pending) = *pending {
+        return if pending.action == action {
+            Response::Pending {
+                action,
+                delay: pending.deadline.saturating_duration_since(Instant::now()),
+            }
+        } else {
+            Response::error(
+                ErrorKind::Rejected,
+                format!("{} is already pending", pending.action),
+            )
+        };
+    }
+
+    if let Err(response) = listener_service::check_action(action, force, &origin, context) {
+        return response;
+    }
+
+    let delay = Duration::from_secs(context.configuration().notifications.countdown_seconds);
+
+    *pending = Some(Pending {
+        action,
+        deadline: Instant::now() + delay,
+    });
+    record_pending(context, action, delay, 0);
+
+    log::info!(peer_addr:% = origin, action:% = action; "{} asked to {}, which will happen in {:?}", origin, action, delay);
+
+    let context = context.clone();
+    thread::spawn(move || run(action, force, &origin, &context));
+
+    Response::Pending { action, delay }
+}
+
+fn run(action: PowerAction, force: bool, origin: &Origin, context: &ListenerContext) {
+    let configuration = context.configuration();
+    let configuration = &configuration.notifications;
+    let postpone_label = format!("Postpone {}", describe_delay(postpone_delay(context)));
+
+    let mut notifier = Notifier::new(configuration);
+    let (postpone, postponed) = mpsc::channel();
+    let mut postpones = 0;
+    let mut warn = true;
+
+    loop {
+        let deadline = match *context.countdown.pending() {
+            Some(pending) => pending.deadline,
+            None => return,
+        };
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        if remaining.is_zero() {
+            break;
+        }
+
+        if warn {
+            let can_postpone = configuration.can_postpone(postpones);
+
+            notifier.warn(
+                &format!(
+                    "This computer will {} in {}",
+                    action,
+                    describe_delay(remaining)
+                ),
+                &format!("Requested by {origin}. Please save your work."),
+                Some((postpone_label.as_str(), &postpone)).filter(|_| can_postpone),
+            );
+        }
+
+        // Sleep until the next milestone, unless someone presses the postpone button first. The
+        // channel can't disconnect, since `postpone` lives as long as the loop.
+        warn = match postponed.recv_timeout(until_next_warning(remaining)) {
+            Ok(()) if configuration.can_postpone(postpones) => {
+                postpones += 1;
+                postpone_pending(action, postpones, origin, context);
+                true
+            }
+            Ok(()) => {
+                log::info!(action:% = action; "Ignoring a request to postpone {}: it's been postponed {} times already", action, postpones);
+                false
+            }
+            Err(_) => true,
+        };
+    }
+
+    notifier.close();
+    *context.countdown.pending() = None;
+    context.status.clear_pending_action();
+
+    match listener_service::perform_action(action, force, origin, context) {
+        Response::Ok { .. } => {}
+        response => {
+            log::warn!(peer_addr:% = origin, action:% = action; "The countdown to {} finished, but it didn't happen: {}", action, response)
+        }
+    }
+}
+
+fn postpone_pending(
+    action: PowerAction,
+    postpones: u32,
+    origin: &Origin,
+    context: &ListenerContext,
+) {
+    let delay = postpone_delay(context);
+
+    let remaining = match context.countdown.pending().as_mut() {
+        Some(pending) => {
+            pending.deadline += delay;
+            pending.deadline.saturating_duration_since(Instant::now())
+        }
+        None => return,
+    };
+
+    log::info!(peer_addr:% = origin, action:% = action; "{} was postponed by {:?} from the desktop", action, delay);
+
+    record_pending(context, action, remaining, postpones);
+    context.audit_log.record(AuditEvent::Postponement {
+        origin: origin.clone(),
+        action: action.to_string(),
+        minutes: context.configuration().notifications.postpone_minutes,
+    });
+}
+
+fn postpone_delay(context: &ListenerContext) -> Duration {
+    Duration::from_secs(context.configuration().notifications.postpone_minutes * 60)
+}
+
+fn record_pending(
+    context: &ListenerContext,
+    action: PowerAction,
+    remaining: Duration,
+    postpones: u32,
+) {
+    let at = (context.clock)()
+        + ChronoDuration::from_std(remaining).unwrap_or_else(|_| ChronoDuration::zero());
+    context.status.record_pending_action(action, at, postpones);
+}
+
+/// How long until the warning is next shown: at the next milestone, or when the action happens.
+/// A milestone less than a second away is skipped, since the warning was only just shown.
+fn until_next_warning(remaining: Duration) -> Duration {
+    MILESTONES
+        .iter()
+        .copied()
+        .find(|milestone| *milestone + Duration::from_secs(1) < remaining)
+        .map_or(remaining, |milestone| remaining - milestone)
+}
+
+/// Describes a delay for people, such as `5 minutes` or `10 seconds`
+fn describe_delay(delay: Duration) -> String {
+    let seconds = delay.as_secs_f64().round() as u64;
+
+    match seconds {
+        0..=1 => "1 second".to_string(),
+        2..=59 => format!("{seconds} seconds"),
+        60..=89 => "1 minute".to_string(),
+        _ => format!("{} minutes", (seconds + 30) / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_delays_are_described_in_seconds() {
+        assert_eq!(describe_delay(Duration::ZERO), "1 second");
+        assert_eq!(describe_delay(Duration::from_millis(1400)), "1 second");
+        assert_eq!(describe_delay(Duration::from_millis(1600)), "2 seconds");
+        assert_eq!(describe_delay(Duration::from_secs(10)), "10 seconds");
+        assert_eq!(describe_delay(Duration::from_millis(59_400)), "59 seconds");
+    }
+
+    #[test]
+    fn longer_delays_are_rounded_to_minutes() {
+        assert_eq!(describe_delay(Duration::from_millis(59_600)), "1 minute");
+        assert_eq!(describe_delay(Duration::from_secs(89)), "1 minute");
+        assert_eq!(describe_delay(Duration::from_secs(90)), "2 minutes");
+        assert_eq!(
+            describe_delay(Duration::from_secs(5 * 60 + 29)),
+            "5 minutes"
+        );
+        assert_eq!(
+            describe_delay(Duration::from_secs(5 * 60 + 30)),
+            "6 minutes"
+        );
+        assert_eq!(describe_delay(Duration::from_secs(10 * 60)), "10 minutes");
+    }
+
+    #[test]
+    fn warnings_are_shown_again_at_each_milestone() {
+        let seconds = Duration::from_secs;
+
+        assert_eq!(until_next_warning(seconds(300)), seconds(240));
+        assert_eq!(until_next_warning(seconds(60)), seconds(50));
+        assert_eq!(until_next_warning(seconds(30)), seconds(20));
+        assert_eq!(until_next_warning(seconds(10)), seconds(10));
+        assert_eq!(until_next_warning(seconds(5)), seconds(5));
+    }
+
+    #[test]
+    fn milestones_that_just_passed_are_skipped() {
+        let remaining = Duration::from_millis(60_500);
+
+        assert_eq!(until_next_warning(remaining), Duration::from_millis(50_500));
+    }
+}
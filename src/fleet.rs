@@ -0,0 +1,626 @@
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::client::{self, Accepted, ClientError, SendRequest};
+use crate::power::PowerAction;
+
+/// How long to wait before trying a host again
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// One machine in an inventory file. The port and secret use the same names as the
+/// configuration file on the machine itself, and fall back to the values passed on the command
+/// line when they're left out. A host with no secret either way isn't sent anything.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InventoryHost {
+    pub host: String,
+
+    #[serde(default, alias = "port")]
+    pub port_number: Option<u16>,
+
+    #[serde(default)]
+    pub secret: Option<String>,
+
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+/// A list of machines to send the same request to, read from a TOML or CSV file
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Inventory {
+    #[serde(default)]
+    pub hosts: Vec<InventoryHost>,
+}
+
+#[derive(Error, Debug)]
+pub enum InventoryError {
+    #[error("Unable to read the inventory file at {path}: {source}")]
+    Unreadable { path: PathBuf, source: io::Error },
+
+    #[error("The inventory file at {path} is not valid TOML: {source}")]
+    InvalidToml {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("The inventory file at {path} is not valid CSV: {source}")]
+    InvalidCsv { path: PathBuf, source: csv::Error },
+
+    #[error("Unable to tell the format of the inventory file at {path} – it should end in .toml or .csv")]
+    UnknownFormat { path: PathBuf },
+}
+
+impl Inventory {
+    /// Reads an inventory, using the file extension to decide between TOML and CSV
+    pub fn load(path: &Path) -> Result<Inventory, InventoryError> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+
+        match extension.as_deref() {
+            Some("toml") => {
+                let contents =
+                    fs::read_to_string(path).map_err(|source| InventoryError::Unreadable {
+                        path: path.to_path_buf(),
+                        source,
+                    })?;
+
+                toml::from_str(&contents).map_err(|source| InventoryError::InvalidToml {
+                    path: path.to_path_buf(),
+                    source,
+                })
+            }
+            Some("csv") => {
+                let invalid = |source| InventoryError::InvalidCsv {
+                    path: path.to_path_buf(),
+                    source,
+                };
+
+                let hosts = csv::ReaderBuilder::new()
+                    .trim(csv::Trim::All)
+                    .from_path(path)
+                    .map_err(invalid)?
+                    .deserialize()
+                    .collect::<Result<Vec<InventoryHost>, _>>()
+                    .map_err(invalid)?;
+
+                Ok(Inventory { hosts })
+            }
+            _ => Err(InventoryError::UnknownFormat {
+                path: path.to_path_buf(),
+            }),
+        }
+    }
+
+    /// The hosts in `group`, or every host if no group is given
+    pub fn hosts_in(&self, group: Option<&str>) -> Vec<InventoryHost> {
+        self.hosts
+            .iter()
+            .filter(|host| group.is_none() || host.group.as_deref() == group)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Settings shared by every request in a fleet run
+#[derive(Debug, Clone)]
+pub struct FleetOptions {
+    /// Used for hosts that don't specify their own
+    pub port: u16,
+    /// Used for hosts that don't specify their own
+    pub secret: Option<String>,
+    pub action: PowerAction,
+    pub force: bool,
+    pub timeout: Duration,
+    /// The most requests to have in flight at once
+    pub parallel: usize,
+    /// How many more times to try a host after a failure that might be temporary
+    pub retries: u32,
+}
+
+/// What happened when a request was sent to one host
+#[derive(Debug)]
+pub struct FleetResult {
+    pub host: InventoryHost,
+    pub attempts: u32,
+    pub outcome: Result<Accepted, ClientError>,
+}
+
+/// Sends the request to every host, at most `options.parallel` at a time. The results are in the
+/// same order as `hosts`.
+pub fn send_all(hosts: Vec<InventoryHost>, options: &FleetOptions) -> Vec<FleetResult> {
+    let count = hosts.len();
+    let queue = Arc::new(Mutex::new(
+        hosts.into_iter().enumerate().collect::<VecDeque<_>>(),
+    ));
+    let (sender, receiver) = mpsc::channel();
+
+    let workers: Vec<_> = (0..options.parallel.clamp(1, count.max(1)))
+        .map(|_| {
+            let queue = queue.clone();
+            let sender = sender.clone();
+            let options = options.clone();
+
+            thread::spawn(move || loop {
+                let next = queue
+                    .lock()
+                    .unwrap_or_else(|error| error.into_inner())
+                    .pop_front();
+
+                let (index, host) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+
+                let result = send_with_retries(host, &options);
+
+                if sender.send((index, result)).is_err() {
+                    break;
+                }
+            })
+        })
+        .collect();
+
+    drop(sender);
+
+    let mut results: Vec<_> = receiver.iter().collect();
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+fn send_with_retries(host: InventoryHost, options: &FleetOptions) -> FleetResult {
+    let secret = match host.secret.clone().or_else(|| options.secret.clone()) {
+        Some(secret) => secret,
+        None => {
+            return FleetResult {
+                outcome: Err(ClientError::NoSecret {
+                    host: host.host.clone(),
+                }),
+                host,
+                attempts: 0,
+            }
+        }
+    };
+
+    let request = SendRequest {
+        host: host.host.clone(),
+        port: host.port_number.unwrap_or(options.port),
+        secret,
+        action: options.action,
+        force: options.force,
+        timeout: options.timeout,
+    };
+
+    let mut attempts = 0;
+
+    loop {
+        attempts += 1;
+
+        let outcome = client::send(&request);
+
+        match &outcome {
+            Err(error) if error.is_retryable() && attempts <= options.retries => {
+                log::debug!(
+                    "Attempt {} for {} failed, retrying: {}",
+                    attempts,
+                    request.host,
+                    error
+                );
+                thread::sleep(RETRY_DELAY);
+            }
+            _ => {
+                return FleetResult {
+                    host,
+                    attempts,
+                    outcome,
+                }
+            }
+        }
+    }
+}
+
+/// Prints a table with a row for each host, followed by the totals
+pub fn print_summary(results: &[FleetResult]) {
+    let host_width = results
+        .iter()
+        .map(|result| result.host.host.len())
+        .chain(std::iter::once("HOST".len()))
+        .max()
+        .unwrap_or_default();
+
+    let group_width = results
+        .iter()
+        .map(|result| result.host.group.as_deref().unwrap_or("-").len())
+        .chain(std::iter::once("GROUP".len()))
+        .max()
+        .unwrap_or_default();
+
+    println!(
+        "{:host_width$}  {:group_width$}  {:8}  RESULT",
+        "HOST", "GROUP", "ATTEMPTS"
+    );
+
+    for result in results {
+        let outcome = match &result.outcome {
+            Ok(accepted) => accepted.to_string(),
+            Err(error) => error.to_string(),
+        };
+
+        println!(
+            "{:host_width$}  {:group_width$}  {:8}  {}",
+            result.host.host,
+            result.host.group.as_deref().unwrap_or("-"),
+            result.attempts,
+            outcome
+        );
+    }
+
+    let succeeded = results
+        .iter()
+        .filter(|result| result.outcome.is_ok())
+        .count();
+
+    let unconfirmed = results
+        .iter()
+        .filter(|result| matches!(&result.outcome, Ok(accepted) if !accepted.confirmed))
+        .count();
+
+    println!();
+
+    if unconfirmed > 0 {
+        println!(
+            "{} succeeded ({} unconfirmed), {} failed",
+            succeeded,
+            unconfirmed,
+            results.len() - succeeded
+        );
+    } else {
+        println!(
+            "{} succeeded, {} failed",
+            succeeded,
+            results.len() - succeeded
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::TempDir;
+
+    fn write(directory: &TempDir, name: &str, contents: &str) -> PathBuf {
+        let path = directory.path().join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn host(host: &str, secret: Option<&str>) -> InventoryHost {
+        InventoryHost {
+            host: host.to_string(),
+            port_number: None,
+            secret: secret.map(str::to_string),
+            group: None,
+        }
+    }
+
+    #[test]
+    fn toml_inventories_are_read() {
+        let directory = TempDir::new().unwrap();
+        let path = write(
+            &directory,
+            "classrooms.toml",
+            r#"
+                [[hosts]]
+                host = "10.0.1.100"
+                group = "lab-1"
+
+                [[hosts]]
+                host = "lab-2-01.example.com"
+                port = 50000
+                secret = "Another Secret"
+            "#,
+        );
+
+        let inventory = Inventory::load(&path).unwrap();
+
+        assert_eq!(
+            inventory.hosts,
+            vec![
+                InventoryHost {
+                    group: Some("lab-1".to_string()),
+                    ..host("10.0.1.100", None)
+                },
+                InventoryHost {
+                    port_number: Some(50000),
+                    ..host("lab-2-01.example.com", Some("Another Secret"))
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_inventories_are_read() {
+        let directory = TempDir::new().unwrap();
+        let path = write(
+            &directory,
+            "classrooms.CSV",
+            "host, port_number, secret, group\n10.0.1.100, , , lab-1\n10.0.1.101, 50000, Another Secret, lab-2\n",
+        );
+
+        let inventory = Inventory::load(&path).unwrap();
+
+        assert_eq!(
+            inventory.hosts,
+            vec![
+                InventoryHost {
+                    group: Some("lab-1".to_string()),
+                    ..host("10.0.1.100", None)
+                },
+                InventoryHost {
+                    port_number: Some(50000),
+                    group: Some("lab-2".to_string()),
+                    ..host("10.0.1.101", Some("Another Secret"))
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_inventories_are_refused() {
+        let directory = TempDir::new().unwrap();
+
+        let toml = write(&directory, "bad.toml", "[[hosts]]\nport = 1\n");
+        assert!(matches!(
+            Inventory::load(&toml),
+            Err(InventoryError::InvalidToml { .. })
+        ));
+
+        let csv = write(&directory, "bad.csv", "host,port\n10.0.1.100,not a port\n");
+        assert!(matches!(
+            Inventory::load(&csv),
+            Err(InventoryError::InvalidCsv { .. })
+        ));
+
+        let unknown = write(&directory, "hosts.txt", "10.0.1.100\n");
+        assert!(matches!(
+            Inventory::load(&unknown),
+            Err(InventoryError::UnknownFormat { .. })
+        ));
+
+        assert!(matches!(
+            Inventory::load(&directory.path().join("missing.toml")),
+            Err(InventoryError::Unreadable { .. })
+        ));
+    }
+
+    #[test]
+    fn hosts_are_picked_by_group() {
+        let inventory = Inventory {
+            hosts: vec![
+                InventoryHost {
+                    group: Some("lab-1".to_string()),
+                    ..host("a", None)
+                },
+                host("b", None),
+            ],
+        };
+
+        assert_eq!(inventory.hosts_in(Some("lab-1")).len(), 1);
+        assert_eq!(inventory.hosts_in(None).len(), 2);
+        assert!(inventory.hosts_in(Some("lab-2")).is_empty());
+    }
+
+    /// A listener that records each request line, replies to it with `reply` (or closes the
+    /// connection without replying, like older versions), and waits a little longer before
+    /// replying to secrets containing `slow`
+    struct FakeListener {
+        port: u16,
+        requests: Arc<Mutex<Vec<String>>>,
+        most_at_once: Arc<AtomicUsize>,
+    }
+
+    impl FakeListener {
+        fn start(reply: Option<&'static str>) -> FakeListener {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let most_at_once = Arc::new(AtomicUsize::new(0));
+            let open = Arc::new(AtomicUsize::new(0));
+
+            let fake = FakeListener {
+                port,
+                requests: requests.clone(),
+                most_at_once: most_at_once.clone(),
+            };
+
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let requests = requests.clone();
+                    let most_at_once = most_at_once.clone();
+                    let open = open.clone();
+
+                    thread::spawn(move || {
+                        let now_open = open.fetch_add(1, Ordering::SeqCst) + 1;
+                        most_at_once.fetch_max(now_open, Ordering::SeqCst);
+
+                        let mut line = String::new();
+                        BufReader::new(&stream).read_line(&mut line).unwrap();
+                        let line = line.trim().to_string();
+
+                        let delay = if line.contains("slow") { 300 } else { 100 };
+                        thread::sleep(Duration::from_millis(delay));
+                        requests.lock().unwrap().push(line);
+
+                        open.fetch_sub(1, Ordering::SeqCst);
+
+                        if let Some(reply) = reply {
+                            let _ = stream.write_all(format!("{reply}\n").as_bytes());
+                        }
+                    });
+                }
+            });
+
+            fake
+        }
+
+        fn requests(&self) -> Vec<String> {
+            let mut requests = self.requests.lock().unwrap().clone();
+            requests.sort();
+            requests
+        }
+    }
+
+    fn options(port: u16, secret: Option<&str>) -> FleetOptions {
+        FleetOptions {
+            port,
+            secret: secret.map(str::to_string),
+            action: PowerAction::Shutdown,
+            force: false,
+            timeout: Duration::from_secs(5),
+            parallel: 16,
+            retries: 2,
+        }
+    }
+
+    #[test]
+    fn hosts_use_their_own_secret_or_the_shared_one() {
+        let fake = FakeListener::start(Some("OK shutdown"));
+        let hosts = vec![
+            host("127.0.0.1", Some("Host Secret")),
+            host("127.0.0.1", None),
+        ];
+
+        let results = send_all(hosts, &options(fake.port, Some("Shared Secret")));
+
+        assert!(results.iter().all(|result| result.outcome.is_ok()));
+        assert_eq!(
+            fake.requests(),
+            vec!["SHUTDOWN Host Secret", "SHUTDOWN Shared Secret"]
+        );
+    }
+
+    #[test]
+    fn hosts_without_a_secret_arent_contacted() {
+        let fake = FakeListener::start(Some("OK shutdown"));
+
+        let results = send_all(vec![host("127.0.0.1", None)], &options(fake.port, None));
+
+        assert_eq!(results[0].attempts, 0);
+        assert!(matches!(
+            results[0].outcome,
+            Err(ClientError::NoSecret { .. })
+        ));
+        assert!(fake.requests().is_empty());
+    }
+
+    #[test]
+    fn results_are_in_the_order_of_the_hosts() {
+        let fake = FakeListener::start(Some("OK shutdown"));
+        let hosts = vec![
+            host("127.0.0.1", Some("first slow")),
+            host("127.0.0.1", Some("second")),
+            host("127.0.0.1", Some("third")),
+        ];
+
+        let results = send_all(hosts.clone(), &options(fake.port, None));
+
+        let order: Vec<InventoryHost> = results.into_iter().map(|result| result.host).collect();
+        assert_eq!(order, hosts);
+    }
+
+    #[test]
+    fn at_most_parallel_hosts_are_contacted_at_once() {
+        let fake = FakeListener::start(Some("OK shutdown"));
+        let hosts = (0..6)
+            .map(|index| InventoryHost {
+                secret: Some(format!("secret {index}")),
+                ..host("127.0.0.1", None)
+            })
+            .collect();
+
+        let results = send_all(
+            hosts,
+            &FleetOptions {
+                parallel: 2,
+                ..options(fake.port, None)
+            },
+        );
+
+        assert_eq!(results.len(), 6);
+        assert_eq!(fake.requests().len(), 6);
+        assert_eq!(fake.most_at_once.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn listeners_that_dont_reply_are_sent_once_and_unconfirmed() {
+        let fake = FakeListener::start(None);
+
+        let results = send_all(
+            vec![host("127.0.0.1", Some("Host Secret"))],
+            &options(fake.port, None),
+        );
+
+        assert_eq!(results[0].attempts, 1);
+        assert!(matches!(
+            results[0].outcome,
+            Ok(Accepted {
+                confirmed: false,
+                ..
+            })
+        ));
+        assert_eq!(fake.requests().len(), 1);
+    }
+
+    #[test]
+    fn refusals_arent_retried() {
+        let fake = FakeListener::start(Some("ERR failed unable to shut down"));
+
+        let results = send_all(
+            vec![host("127.0.0.1", Some("Host Secret"))],
+            &options(fake.port, None),
+        );
+
+        assert_eq!(results[0].attempts, 1);
+        assert_eq!(fake.requests().len(), 1);
+    }
+
+    #[test]
+    fn unreachable_hosts_are_retried() {
+        // Bound but never listened on, so connections are refused
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+
+        let results = send_all(
+            vec![host("127.0.0.1", Some("Host Secret"))],
+            &FleetOptions {
+                retries: 1,
+                ..options(port, None)
+            },
+        );
+
+        assert_eq!(results[0].attempts, 2);
+        assert!(matches!(
+            results[0].outcome,
+            Err(ClientError::Unreachable { .. })
+        ));
+    }
+}
@@ -0,0 +1,497 @@
+//! Powers off machines that nobody is using, for rooms where whoever's in charge forgets to send
+//! the request at the end of the day.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use systemstat::{Platform, System};
+
+use crate::audit::Origin;
+use crate::configuration::AppConfiguration;
+use crate::countdown;
+use crate::listener_service::{self, ListenerContext};
+use crate::power::PowerAction;
+use crate::protocol::Response;
+
+#[cfg(not(target_os = "linux"))]
+use std::process::Command;
+
+#[cfg(target_os = "linux")]
+use crate::logind::Logind;
+
+/// When a machine counts as idle, and what to do about it. Off by default.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct IdleConfiguration {
+    pub enabled: bool,
+
+    /// How long there must have been no user input, and no activity above the thresholds
+    pub idle_minutes: u64,
+
+    pub action: PowerAction,
+
+    /// The machine is busy while its network traffic (sent and received, across every interface
+    /// but loopback) is above this. Only measured on Linux.
+    pub max_network_bytes_per_second: Option<u64>,
+
+    /// The machine is busy while the one-minute load average is above this. Not measured on
+    /// Windows.
+    pub max_load_average: Option<f32>,
+
+    /// How often to take a measurement
+    pub check_interval_seconds: u64,
+}
+
+impl Default for IdleConfiguration {
+    fn default() -> Self {
+        IdleConfiguration {
+            enabled: false,
+            idle_minutes: 60,
+            action: PowerAction::Shutdown,
+            max_network_bytes_per_second: None,
+            max_load_average: None,
+            check_interval_seconds: 60,
+        }
+    }
+}
+
+/// The network traffic counted so far, to work out the rate from
+struct NetworkSample {
+    bytes: u64,
+    at: Instant,
+}
+
+/// Starts watching for the machine to become idle, if that's enabled
+pub fn spawn(configuration: &IdleConfiguration, context: Arc<ListenerContext>) {
+    if !configuration.enabled {
+        return;
+    }
+
+    log::info!(
+        "Will {} after {} minutes idle",
+        configuration.action,
+        configuration.idle_minutes
+    );
+
+    let configuration = configuration.clone();
+    thread::spawn(move || watch(&configuration, &context));
+}
+
+fn watch(configuration: &IdleConfiguration, context: &Arc<ListenerContext>) {
+    let idle_for = Duration::from_secs(configuration.idle_minutes * 60);
+    let interval = Duration::from_secs(configuration.check_interval_seconds.max(1));
+
+    let mut network = None;
+    // The listener starting counts as activity, so a machine isn't powered off straight after boot
+    let mut quiet_since = Instant::now();
+    let mut warned = false;
+
+    loop {
+        thread::sleep(interval);
+
+        if let Some(activity) = activity(configuration, &mut network) {
+            log::debug!("Not idle: {}", activity);
+            quiet_since = Instant::now();
+            continue;
+        }
+
+        // Without knowing when someone last used the machine, it's never treated as idle
+        let input_idle = match input_idle_time(&context.configuration()) {
+            Some(input_idle) => input_idle,
+            None => {
+                if !warned {
+                    log::warn!("Unable to tell when the machine was last used, so it won't be treated as idle");
+                    warned = true;
+                }
+
+                continue;
+            }
+        };
+
+        let idle = input_idle.min(quiet_since.elapsed());
+
+        if idle < idle_for {
+            log::trace!("Idle for {:?}", idle);
+            continue;
+        }
+
+        let action = configuration.action;
+        let origin = Origin::Idle(configuration.idle_minutes);
+
+        log::info!(action:% = action; "Idle for {} minutes, so going to {}", configuration.idle_minutes, action);
+
+        let response = if context.configuration().notifications.countdown_seconds > 0 {
+            countdown::start(action, false, origin, context)
+        } else {
+            listener_service::perform_action(action, false, &origin, context)
+        };
+
+        match response {
+            Response::Ok { .. } | Response::Pending { .. } => {}
+            response => {
+                log::warn!(action:% = action; "Unable to {} while idle: {}", action, response)
+            }
+        }
+
+        // Whatever happened, wait for another full idle period before trying again
+        quiet_since = Instant::now();
+    }
+}
+
+/// What was measured of the machine's activity, where it's being watched and could be measured
+#[derive(Debug, Default)]
+struct Measurements {
+    load_average: Option<f32>,
+    network_bytes_per_second: Option<f64>,
+}
+
+/// Describes whatever shows the machine is busy right now, such as a high load average
+fn activity(
+    configuration: &IdleConfiguration,
+    previous: &mut Option<NetworkSample>,
+) -> Option<String> {
+    busy(configuration, &measure(configuration, previous))
+}
+
+fn measure(
+    configuration: &IdleConfiguration,
+    previous: &mut Option<NetworkSample>,
+) -> Measurements {
+    let mut measurements = Measurements::default();
+
+    if configuration.max_load_average.is_some() {
+        match System::new().load_average() {
+            Ok(load) => measurements.load_average = Some(load.one),
+            Err(error) => log::debug!("Unable to check the load average: {}", error),
+        }
+    }
+
+    if configuration.max_network_bytes_per_second.is_some() {
+        match network_bytes() {
+            Ok(bytes) => {
+                let sample = NetworkSample {
+                    bytes,
+                    at: Instant::now(),
+                };
+                measurements.network_bytes_per_second = network_rate(previous, sample);
+            }
+            Err(error) => log::debug!("Unable to check the network traffic: {}", error),
+        }
+    }
+
+    measurements
+}
+
+/// Describes whichever measurement is above its threshold. Anything that couldn't be measured
+/// doesn't count as activity.
+fn busy(configuration: &IdleConfiguration, measurements: &Measurements) -> Option<String> {
+    if let (Some(max_load_average), Some(load_average)) =
+        (configuration.max_load_average, measurements.load_average)
+    {
+        if load_average > max_load_average {
+            return Some(format!("the load average is {load_average:.2}"));
+        }
+    }
+
+    if let (Some(max_bytes_per_second), Some(rate)) = (
+        configuration.max_network_bytes_per_second,
+        measurements.network_bytes_per_second,
+    ) {
+        if rate > max_bytes_per_second as f64 {
+            return Some(format!("the network is carrying {rate:.0} bytes a second"));
+        }
+    }
+
+    None
+}
+
+/// The traffic per second since the previous sample, which `sample` replaces. `None` for the
+/// first sample.
+fn network_rate(previous: &mut Option<NetworkSample>, sample: NetworkSample) -> Option<f64> {
+    let (bytes, at) = (sample.bytes, sample.at);
+
+    previous.replace(sample).map(|previous| {
+        bytes.saturating_sub(previous.bytes) as f64 / at.duration_since(previous.at).as_secs_f64()
+    })
+}
+
+/// The bytes sent and received so far, across every interface but loopback
+fn network_bytes() -> io::Result<u64> {
+    let system = System::new();
+    let mut total = 0;
+
+    for name in system
+        .networks()?
+        .keys()
+        .filter(|name| !name.starts_with("lo"))
+    {
+        let stats = system.network_stats(name)?;
+        total += stats.rx_bytes.as_u64() + stats.tx_bytes.as_u64();
+    }
+
+    Ok(total)
+}
+
+/// How long since anyone used the keyboard or mouse (or, on Linux, a terminal). `None` if that
+/// can't be told.
+#[cfg(target_os = "linux")]
+fn input_idle_time(configuration: &AppConfiguration) -> Option<Duration> {
+    let logind = Logind::connect(configuration.power.logind.bus_address.as_deref())
+        .map_err(|error| log::debug!("{}", error))
+        .ok()?;
+
+    logind
+        .idle_time()
+        .map_err(|error| {
+            log::debug!(
+                "Unable to ask logind how long the machine's been idle: {}",
+                error
+            )
+        })
+        .ok()
+}
+
+/// `IOHIDSystem` reports the time since the last input event in nanoseconds
+#[cfg(target_os = "macos")]
+fn input_idle_time(_configuration: &AppConfiguration) -> Option<Duration> {
+    let output = Command::new("ioreg")
+        .args(["-c", "IOHIDSystem", "-d", "4"])
+        .output()
+        .ok()?;
+
+    parse_hid_idle_time(&String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(any(target_os = "macos", test))]
+fn parse_hid_idle_time(output: &str) -> Option<Duration> {
+    output
+        .lines()
+        .find(|line| line.contains("\"HIDIdleTime\""))
+        .and_then(|line| line.rsplit('=').next())
+        .and_then(|nanoseconds| nanoseconds.trim().parse().ok())
+        .map(Duration::from_nanos)
+}
+
+/// The service can't see input in users' sessions, so this relies on the IDLE TIME column of
+/// `query user`, taking the most recently used session. Nobody logged in counts as idle forever.
+#[cfg(windows)]
+fn input_idle_time(_configuration: &AppConfiguration) -> Option<Duration> {
+    let output = Command::new("query").arg("user").output().ok()?;
+
+    // `query user` exits with 1 when nobody is logged in
+    if !output.status.success() {
+        return Some(Duration::MAX);
+    }
+
+    parse_query_user(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// The shortest idle time in `query user`'s output. `None` if any session's can't be read.
+#[cfg(any(windows, test))]
+fn parse_query_user(output: &str) -> Option<Duration> {
+    output
+        .lines()
+        .skip(1)
+        .map(|line| {
+            // The session name column is blank for disconnected sessions, so find the state
+            // column and take the one after it
+            let mut columns = line.split_whitespace().skip_while(|column| {
+                !matches!(*column, "Active" | "Disc" | "Conn" | "Listen" | "Idle")
+            });
+            columns.next();
+            columns.next().and_then(parse_query_idle_time)
+        })
+        .collect::<Option<Vec<Duration>>>()?
+        .into_iter()
+        .min()
+        .or(Some(Duration::MAX))
+}
+
+/// Parses `query user`'s idle times: `none` or `.` for less than a minute, then minutes, `H:MM`
+/// and `D+H:MM`
+#[cfg(any(windows, test))]
+fn parse_query_idle_time(idle: &str) -> Option<Duration> {
+    if idle == "none" || idle == "." {
+        return Some(Duration::ZERO);
+    }
+
+    let (days, rest) = match idle.split_once('+') {
+        Some((days, rest)) => (days.parse::<u64>().ok()?, rest),
+        None => (0, idle),
+    };
+
+    let (hours, minutes) = match rest.split_once(':') {
+        Some((hours, minutes)) => (hours.parse::<u64>().ok()?, minutes.parse::<u64>().ok()?),
+        None => (0, rest.parse::<u64>().ok()?),
+    };
+
+    Some(Duration::from_secs(
+        ((days * 24 + hours) * 60 + minutes) * 60,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn thresholds() -> IdleConfiguration {
+        IdleConfiguration {
+            enabled: true,
+            max_load_average: Some(0.5),
+            max_network_bytes_per_second: Some(1000),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn quiet_machines_arent_busy() {
+        let measurements = Measurements {
+            load_average: Some(0.5),
+            network_bytes_per_second: Some(1000.0),
+        };
+
+        assert_eq!(busy(&thresholds(), &measurements), None);
+    }
+
+    #[test]
+    fn a_high_load_average_is_activity() {
+        let measurements = Measurements {
+            load_average: Some(1.25),
+            network_bytes_per_second: Some(0.0),
+        };
+
+        assert_eq!(
+            busy(&thresholds(), &measurements),
+            Some("the load average is 1.25".to_string())
+        );
+    }
+
+    #[test]
+    fn heavy_network_traffic_is_activity() {
+        let measurements = Measurements {
+            load_average: Some(0.0),
+            network_bytes_per_second: Some(2500.4),
+        };
+
+        assert_eq!(
+            busy(&thresholds(), &measurements),
+            Some("the network is carrying 2500 bytes a second".to_string())
+        );
+    }
+
+    #[test]
+    fn measurements_without_a_threshold_are_ignored() {
+        let measurements = Measurements {
+            load_average: Some(100.0),
+            network_bytes_per_second: Some(1e9),
+        };
+
+        assert_eq!(busy(&IdleConfiguration::default(), &measurements), None);
+    }
+
+    #[test]
+    fn measurements_that_failed_arent_activity() {
+        assert_eq!(busy(&thresholds(), &Measurements::default()), None);
+    }
+
+    #[test]
+    fn network_rates_are_worked_out_between_samples() {
+        let start = Instant::now();
+        let mut previous = None;
+
+        let first = network_rate(
+            &mut previous,
+            NetworkSample {
+                bytes: 1000,
+                at: start,
+            },
+        );
+        let second = network_rate(
+            &mut previous,
+            NetworkSample {
+                bytes: 11_000,
+                at: start + Duration::from_secs(4),
+            },
+        );
+        // Counters can go backwards when an interface disappears
+        let third = network_rate(
+            &mut previous,
+            NetworkSample {
+                bytes: 500,
+                at: start + Duration::from_secs(5),
+            },
+        );
+
+        assert_eq!(first, None);
+        assert_eq!(second, Some(2500.0));
+        assert_eq!(third, Some(0.0));
+    }
+
+    #[test]
+    fn query_user_idle_times_are_parsed() {
+        assert_eq!(parse_query_idle_time("none"), Some(Duration::ZERO));
+        assert_eq!(parse_query_idle_time("."), Some(Duration::ZERO));
+        assert_eq!(parse_query_idle_time("5"), Some(5 * MINUTE));
+        assert_eq!(parse_query_idle_time("2:05"), Some(125 * MINUTE));
+        assert_eq!(
+            parse_query_idle_time("1+02:05"),
+            Some((24 * 60 + 125) * MINUTE)
+        );
+        assert_eq!(parse_query_idle_time("22/10/2026"), None);
+        assert_eq!(parse_query_idle_time("x+1:00"), None);
+    }
+
+    #[test]
+    fn the_most_recently_used_session_counts() {
+        let output = "\
+ USERNAME              SESSIONNAME        ID  STATE   IDLE TIME  LOGON TIME
+>alice                 console             1  Active      none   18/10/2026 08:12
+ bob                                       2  Disc        1+03:10  17/10/2026 09:30
+ carol                 rdp-tcp#3           3  Active         42  18/10/2026 10:01
+";
+
+        assert_eq!(parse_query_user(output), Some(Duration::ZERO));
+        assert_eq!(
+            parse_query_user(&output.replace(" none ", "   17 ")),
+            Some(17 * MINUTE)
+        );
+    }
+
+    #[test]
+    fn nobody_logged_in_is_idle_forever() {
+        let output =
+            " USERNAME              SESSIONNAME        ID  STATE   IDLE TIME  LOGON TIME\n";
+
+        assert_eq!(parse_query_user(output), Some(Duration::MAX));
+    }
+
+    #[test]
+    fn unreadable_sessions_mean_the_idle_time_is_unknown() {
+        let output = "\
+ USERNAME              SESSIONNAME        ID  STATE   IDLE TIME  LOGON TIME
+>alice                 console             1  Active      soon   18/10/2026 08:12
+";
+
+        assert_eq!(parse_query_user(output), None);
+    }
+
+    #[test]
+    fn hid_idle_time_is_read_from_ioreg() {
+        let output = r#"
+    | |   "HIDIdleTimeDelta" = 1000
+    | |   "HIDIdleTime" = 90000000000
+    | |   "HIDPointerAcceleration" = 45056
+"#;
+
+        assert_eq!(
+            parse_hid_idle_time(output),
+            Some(90 * Duration::from_secs(1))
+        );
+        assert_eq!(parse_hid_idle_time("\"HIDIdleTime\" = "), None);
+        assert_eq!(parse_hid_idle_time(""), None);
+    }
+}
@@ -0,0 +1,269 @@
+use serde::{Deserialize, Serialize};
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::path::PathBuf;
+#[cfg(not(target_os = "linux"))]
+use std::process::Command;
+use systemstat::{Platform, System};
+
+use crate::system_info;
+
+/// Conditions under which the listener refuses to perform an action, so that a machine isn't
+/// shut down in the middle of something important. Nothing is checked by default.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct InhibitorConfiguration {
+    /// Refuse while the one-minute load average is above this
+    pub max_load_average: Option<f32>,
+
+    /// Refuse while a process with one of these names is running (compared case-insensitively,
+    /// without any `.exe` suffix)
+    pub processes: Vec<String>,
+
+    /// Refuse while anyone is logged in
+    pub refuse_when_users_logged_in: bool,
+
+    /// Refuse while any of these files exist
+    pub lock_files: Vec<PathBuf>,
+
+    /// Let clients skip these checks by sending `FORCE` before the action
+    pub allow_force: bool,
+}
+
+impl InhibitorConfiguration {
+    /// Runs each check, returning why the action should be refused if any of them fail. A check
+    /// that can't be made refuses the action too, rather than letting it through unchecked.
+    pub fn check(&self) -> Option<String> {
+        if let Some(max_load_average) = self.max_load_average {
+            match System::new().load_average() {
+                Ok(load) if load.one > max_load_average => {
+                    return Some(format!(
+                        "the load average is {:.2}, above the limit of {:.2}",
+                        load.one, max_load_average
+                    ))
+                }
+                Ok(_) => {}
+                Err(error) => return Some(format!("unable to check the load average: {error}")),
+            }
+        }
+
+        if !self.processes.is_empty() {
+            match running_processes() {
+                Ok(running) => {
+                    if let Some(process) = self
+                        .processes
+                        .iter()
+                        .find(|process| running.contains(&normalize_process_name(process)))
+                    {
+                        return Some(format!("{process} is running"));
+                    }
+                }
+                Err(error) => {
+                    return Some(format!("unable to list the running processes: {error}"))
+                }
+            }
+        }
+
+        if self.refuse_when_users_logged_in {
+            match system_info::logged_in_users() {
+                Some(users) if !users.is_empty() => {
+                    return Some(format!("{} logged in", users.join(", ")))
+                }
+                Some(_) => {}
+                None => return Some("unable to list the logged-in users".to_string()),
+            }
+        }
+
+        if let Some(lock_file) = self.lock_files.iter().find(|path| path.exists()) {
+            return Some(format!("{} exists", lock_file.display()));
+        }
+
+        None
+    }
+}
+
+fn normalize_process_name(name: &str) -> String {
+    let name = name.to_lowercase();
+    name.strip_suffix(".exe")
+        .map(str::to_string)
+        .unwrap_or(name)
+}
+
+/// The names of every running process, normalized for comparison.
+///
+/// On Linux they're read from `/proc` rather than `ps`, which only has the first 15 characters
+/// of each name – the full name comes from the program each process was started with.
+#[cfg(target_os = "linux")]
+fn running_processes() -> Result<Vec<String>, String> {
+    let entries = fs::read_dir("/proc").map_err(|error| format!("/proc: {error}"))?;
+    let mut names = Vec::new();
+
+    for entry in entries.flatten() {
+        let is_process = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.bytes().all(|byte| byte.is_ascii_digit()));
+
+        if !is_process {
+            continue;
+        }
+
+        // Processes can exit while they're being listed
+        let path = entry.path();
+        let comm = fs::read_to_string(path.join("comm")).unwrap_or_default();
+        let cmdline = fs::read(path.join("cmdline")).unwrap_or_default();
+
+        names.extend(process_names(&comm, &cmdline));
+    }
+
+    Ok(names)
+}
+
+/// The names a Linux process goes by: its (possibly shortened) `comm`, and the file name of the
+/// program in its command line. Kernel threads don't have a command line.
+#[cfg(target_os = "linux")]
+fn process_names(comm: &str, cmdline: &[u8]) -> Vec<String> {
+    let program = cmdline
+        .split(|&byte| byte == 0)
+        .next()
+        .map(String::from_utf8_lossy)
+        .unwrap_or_default();
+
+    let mut names = vec![
+        normalize_process_name(comm.trim_end_matches('\n')),
+        normalize_process_name(program.rsplit('/').next().unwrap_or_default()),
+    ];
+
+    names.retain(|name| !name.is_empty());
+    names.dedup();
+    names
+}
+
+#[cfg(not(target_os = "linux"))]
+fn running_processes() -> Result<Vec<String>, String> {
+    #[cfg(windows)]
+    let (program, output) = (
+        "tasklist",
+        Command::new("tasklist")
+            .args(["/fo", "csv", "/nh"])
+            .output(),
+    );
+
+    #[cfg(not(windows))]
+    let (program, output) = (
+        "ps",
+        Command::new("ps").args(["-A", "-o", "comm="]).output(),
+    );
+
+    let output = output.map_err(|error| format!("{program}: {error}"))?;
+
+    if !output.status.success() {
+        return Err(format!("{program} failed with {}", output.status));
+    }
+
+    Ok(parse_process_list(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// The process names in the output of `tasklist` or `ps -o comm=`
+#[cfg(any(not(target_os = "linux"), test))]
+fn parse_process_list(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            // `tasklist` quotes each column, and `ps` on macOS prints the full path
+            let name = line.split(',').next()?.trim().trim_matches('"');
+            let name = name.rsplit('/').next()?;
+            Some(normalize_process_name(name))
+        })
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn process_names_are_compared_without_case_or_exe() {
+        assert_eq!(normalize_process_name("Blender.EXE"), "blender");
+        assert_eq!(normalize_process_name("ffmpeg"), "ffmpeg");
+    }
+
+    #[test]
+    fn tasklist_output_is_parsed() {
+        let output = "\"System Idle Process\",\"0\",\"Services\",\"0\",\"8 K\"\r\n\"Blender.exe\",\"4242\",\"Console\",\"1\",\"512,000 K\"\r\n";
+
+        assert_eq!(
+            parse_process_list(output),
+            vec!["system idle process", "blender"]
+        );
+    }
+
+    #[test]
+    fn ps_output_is_parsed() {
+        let output = "/sbin/launchd\n/Applications/Blender.app/Contents/MacOS/Blender\nffmpeg\n\n";
+
+        assert_eq!(
+            parse_process_list(output),
+            vec!["launchd", "blender", "ffmpeg"]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn long_names_come_from_the_command_line() {
+        // `comm` is cut off at 15 characters
+        let names = process_names(
+            "render-farm-age\n",
+            b"/opt/render/render-farm-agent\0--queue\0night\0",
+        );
+
+        assert_eq!(names, vec!["render-farm-age", "render-farm-agent"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn kernel_threads_only_have_a_comm() {
+        assert_eq!(process_names("kworker/0:1\n", b""), vec!["kworker/0:1"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn running_processes_with_long_names_are_found() {
+        // The test binary's name is much longer than 15 characters
+        let exe = std::env::current_exe().unwrap();
+        let name = exe.file_name().unwrap().to_str().unwrap().to_string();
+        assert!(name.len() > 15);
+
+        let inhibitors = InhibitorConfiguration {
+            processes: vec![name.clone()],
+            ..Default::default()
+        };
+
+        assert_eq!(inhibitors.check(), Some(format!("{name} is running")));
+    }
+
+    #[test]
+    fn lock_files_refuse_while_they_exist() {
+        let directory = TempDir::new().unwrap();
+        let lock_file = directory.path().join("backup.lock");
+        let inhibitors = InhibitorConfiguration {
+            lock_files: vec![lock_file.clone()],
+            ..Default::default()
+        };
+
+        assert_eq!(inhibitors.check(), None);
+
+        std::fs::write(&lock_file, "").unwrap();
+        assert_eq!(
+            inhibitors.check(),
+            Some(format!("{} exists", lock_file.display()))
+        );
+    }
+
+    #[test]
+    fn nothing_is_checked_by_default() {
+        assert_eq!(InhibitorConfiguration::default().check(), None);
+    }
+}
@@ -1,29 +1,285 @@
-use std::io::Read;
-use std::net::{Shutdown, TcpListener, TcpStream};
-use std::thread;
-use system_shutdown::shutdown;
+use chrono::{DateTime, Utc};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use systemstat::{Platform, System};
 
+use crate::audit::{AuditEvent, AuditLog, Origin};
 use crate::configuration::AppConfiguration;
+use crate::control;
+use crate::countdown::{self, Countdown};
+use crate::idle;
+use crate::power::{PowerAction, PowerError};
+use crate::protocol::{ErrorKind, Request, Response, MIN_HEARTBEAT_INTERVAL};
+use crate::reload;
+use crate::scheduler;
+use crate::status::DaemonStatus;
+use crate::system_info::SystemInfo;
+use crate::systemd::{self, Heartbeats};
+
+/// Everything a connection handler needs, shared between all of the listener's threads
+pub struct ListenerContext {
+    /// Replaced when the configuration is reloaded – read it with `configuration()`
+    configuration: RwLock<Arc<AppConfiguration>>,
+
+    pub audit_log: AuditLog,
+    pub status: Arc<DaemonStatus>,
+
+    /// The current time, for checking maintenance windows
+    pub clock: fn() -> DateTime<Utc>,
+
+    /// The remote action waiting for its countdown to finish, if any
+    pub countdown: Countdown,
+
+    /// Bumped whenever the listening sockets are replaced, which tells the threads accepting
+    /// connections on the old ones to stop
+    generation: AtomicU64,
+
+    accepting: Mutex<Accepting>,
+
+    /// How many `PING KEEPALIVE` connections are open
+    keepalives: AtomicUsize,
+
+    /// Beaten by the threads accepting connections and reloading the configuration, for the
+    /// systemd watchdog
+    pub heartbeats: Arc<Heartbeats>,
+}
+
+/// The heartbeat the threads accepting connections beat
+const ACCEPTING_HEARTBEAT: &str = "accepting connections";
+
+/// The threads accepting connections, and where they're listening
+#[derive(Default)]
+struct Accepting {
+    threads: Vec<JoinHandle<()>>,
+    addresses: Vec<SocketAddr>,
+
+    /// Whether the sockets came from systemd, in which case they can't be replaced
+    socket_activated: bool,
+}
+
+impl ListenerContext {
+    pub fn new(configuration: &AppConfiguration) -> ListenerContext {
+        ListenerContext {
+            configuration: RwLock::new(Arc::new(configuration.clone())),
+            audit_log: AuditLog::open(&configuration.audit),
+            status: Arc::new(DaemonStatus::new(configuration)),
+            clock: Utc::now,
+            countdown: Countdown::default(),
+            generation: AtomicU64::new(0),
+            accepting: Mutex::new(Accepting::default()),
+            keepalives: AtomicUsize::new(0),
+            heartbeats: Arc::new(Heartbeats::default()),
+        }
+    }
+
+    /// The current configuration. Hold on to it for as long as it needs to stay the same – a
+    /// reload doesn't change a configuration that's already been read.
+    pub fn configuration(&self) -> Arc<AppConfiguration> {
+        self.configuration
+            .read()
+            .unwrap_or_else(|error| error.into_inner())
+            .clone()
+    }
+
+    /// Switches to `configuration` for everything from now on. Connections that are already
+    /// open aren't affected.
+    pub fn set_configuration(&self, configuration: AppConfiguration) {
+        self.status.record_configuration(&configuration);
+
+        *self
+            .configuration
+            .write()
+            .unwrap_or_else(|error| error.into_inner()) = Arc::new(configuration);
+    }
+
+    fn accepting(&self) -> MutexGuard<'_, Accepting> {
+        self.accepting
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
 
 pub fn run(configuration: &AppConfiguration) {
-    let listener = TcpListener::bind(configuration).unwrap();
-    log::debug!("Listening on {:?}", configuration.port_number);
+    let context = Arc::new(ListenerContext::new(configuration));
+
+    let mut listeners = systemd::listen_fds();
+    let socket_activated = !listeners.is_empty();
+
+    if listeners.is_empty() {
+        listeners.push(TcpListener::bind(configuration).unwrap());
+        log::debug!("Listening on {:?}", configuration.port_number);
+    }
+
+    {
+        let mut accepting = context.accepting();
+        accepting.socket_activated = socket_activated;
+
+        for listener in listeners {
+            if let Ok(address) = listener.local_addr() {
+                context.status.record_bound_socket(address);
+                accepting.addresses.push(address);
+            }
+
+            accepting
+                .threads
+                .push(spawn_accepting(listener, 0, context.clone()));
+        }
+    }
+
+    control::serve(&configuration.control, context.status.clone());
+    scheduler::spawn(&configuration.scheduler, context.clone());
+    idle::spawn(&configuration.idle, context.clone());
+    reload::spawn(&configuration.reload, context.clone());
+
+    systemd::notify_ready();
+    systemd::spawn_watchdog(context.heartbeats.clone());
+
+    // Wait for every thread accepting connections, including any started by a reload
+    loop {
+        let thread = context.accepting().threads.pop();
+
+        match thread {
+            Some(thread) => {
+                let _ = thread.join();
+            }
+            None => break,
+        }
+    }
+
+    systemd::notify_stopping();
+}
+
+/// Listens on the port in `configuration` instead of the current one. Connections that are
+/// already open carry on.
+pub fn rebind(context: &Arc<ListenerContext>, configuration: &AppConfiguration) -> io::Result<()> {
+    let mut accepting = context.accepting();
+
+    if accepting.socket_activated {
+        return Err(io::Error::other(
+            "systemd passed in the listening socket – change the port in the socket unit instead",
+        ));
+    }
+
+    let listener = TcpListener::bind(configuration)?;
+    let address = listener.local_addr()?;
+    let generation = context.generation.fetch_add(1, Ordering::SeqCst) + 1;
+
+    accepting
+        .threads
+        .push(spawn_accepting(listener, generation, context.clone()));
+
+    let previous = std::mem::replace(&mut accepting.addresses, vec![address]);
+    context.status.replace_bound_sockets(vec![address]);
+
+    // Wake the old threads up, so they notice that they should stop straight away
+    for address in previous {
+        let ip = match address.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+
+        let _ = TcpStream::connect_timeout(
+            &SocketAddr::new(ip, address.port()),
+            Duration::from_secs(1),
+        );
+    }
+
+    Ok(())
+}
+
+fn spawn_accepting(
+    listener: TcpListener,
+    generation: u64,
+    context: Arc<ListenerContext>,
+) -> JoinHandle<()> {
+    thread::spawn(move || accept_connections(listener, generation, context))
+}
+
+fn accept_connections(listener: TcpListener, generation: u64, context: Arc<ListenerContext>) {
+    let local_address = listener.local_addr().ok();
+
+    if let Some(address) = local_address {
+        log::info!("Accepting connections on {}", address);
+    }
+
+    // Waiting for connections with a timeout (rather than blocking in `accept`) lets the loop
+    // beat its heartbeat while nobody's connecting
+    #[cfg(unix)]
+    if let Err(error) = listener.set_nonblocking(true) {
+        log::warn!(
+            "Unable to stop {:?} from blocking: {}",
+            local_address,
+            error
+        );
+    }
+
+    loop {
+        let ready = match wait_for_connection(&listener, systemd::HEARTBEAT_INTERVAL) {
+            Ok(ready) => ready,
+            Err(error) => {
+                log::error!("Unable to wait for connections: {}", error);
+                thread::sleep(systemd::HEARTBEAT_INTERVAL);
+                continue;
+            }
+        };
+
+        context.heartbeats.beat(ACCEPTING_HEARTBEAT);
+
+        if context.generation.load(Ordering::SeqCst) != generation {
+            if let Some(address) = local_address {
+                log::info!("Stopped accepting connections on {}", address);
+            }
+
+            return;
+        }
+
+        if !ready {
+            continue;
+        }
+
+        // The connection may have gone again since it was waiting, and connections mustn't
+        // inherit the listener's non-blocking mode (as they do on macOS)
+        let stream = match listener.accept() {
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => continue,
+            result => result.and_then(|(stream, _)| stream.set_nonblocking(false).map(|_| stream)),
+        };
+
+        let configuration = context.configuration();
 
-    for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
-                let interface_ip = stream.local_addr().unwrap().ip();
+                // A peer that resets the connection straight away leaves nothing to look up
+                let (peer, local) = match (stream.peer_addr(), stream.local_addr()) {
+                    (Ok(peer), Ok(local)) => (peer, local),
+                    (Err(error), _) | (_, Err(error)) => {
+                        log::warn!("Dropping a connection that closed as it arrived: {}", error);
+                        continue;
+                    }
+                };
+
+                let interface_ip = local.ip();
                 log::debug!("IP: {:?}", interface_ip);
 
-                if !configuration.addresses.contains(&interface_ip) {
+                context
+                    .audit_log
+                    .record(AuditEvent::Connection { peer, local });
+
+                if !is_configured_address(&configuration.addresses, interface_ip) {
                     log::debug!("Received a shutdown signal on {:?}, but the configuration only allows them from {:?} – ignoring", interface_ip, configuration.addresses);
                 }
 
-                let secret = configuration.secret.clone();
+                let context = context.clone();
 
                 thread::spawn(move || {
-                    log::info!("New connection: {}", stream.peer_addr().unwrap());
-                    handle_stream(stream, &secret)
+                    let _connection = context.status.record_connection_accepted();
+
+                    log::info!(peer_addr:% = peer; "New connection: {}", peer);
+                    handle_stream(stream, peer, &context)
                 });
             }
             Err(e) => {
@@ -33,30 +289,491 @@ pub fn run(configuration: &AppConfiguration) {
     }
 }
 
-pub fn handle_stream(mut stream: TcpStream, secret: &str) {
+/// Waits up to `timeout` for a connection to arrive on `listener`, returning whether one did
+#[cfg(unix)]
+fn wait_for_connection(listener: &TcpListener, timeout: Duration) -> io::Result<bool> {
+    use nix::errno::Errno;
+    use nix::poll::{poll, PollFd, PollFlags};
+    use std::convert::TryFrom;
+    use std::os::unix::io::AsRawFd;
+
+    let mut fds = [PollFd::new(listener.as_raw_fd(), PollFlags::POLLIN)];
+    let timeout = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+
+    match poll(&mut fds, timeout) {
+        Ok(ready) => Ok(ready > 0),
+        Err(Errno::EINTR) => Ok(false),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Elsewhere `accept` blocks until a connection arrives, so the heartbeat only beats when one
+/// does – which is fine, as only systemd's watchdog reads it
+#[cfg(not(unix))]
+fn wait_for_connection(_listener: &TcpListener, _timeout: Duration) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Whether a connection that arrived on `interface_ip` came in on one of the configured
+/// addresses. An unspecified address (`0.0.0.0` or `::`) stands for every interface.
+fn is_configured_address(addresses: &[IpAddr], interface_ip: IpAddr) -> bool {
+    // A dual-stack socket (such as the one a systemd socket unit listens on) sees IPv4
+    // connections arrive on IPv4-mapped addresses like `::ffff:10.0.1.100`
+    let interface_ip = interface_ip.to_canonical();
+
+    addresses.iter().any(|address| match address {
+        IpAddr::V4(address) if address.is_unspecified() => interface_ip.is_ipv4(),
+        IpAddr::V6(address) if address.is_unspecified() => true,
+        address => *address == interface_ip,
+    })
+}
+
+pub fn handle_stream(stream: TcpStream, peer: SocketAddr, context: &Arc<ListenerContext>) {
+    let audit_log = &context.audit_log;
+
     let mut buffer = String::new();
 
-    match stream.read_to_string(&mut buffer) {
-        Ok(_) => {
-            let input = &buffer.trim();
-            log::debug!("Comparing {} and {}", input, secret);
-            if &secret == input {
-                match shutdown() {
-                    Ok(_) => {
-                        log::info!("Shutting down - source: {}", stream.peer_addr().unwrap())
-                    }
-                    Err(error) => log::error!("Failed to shut down: {}", error),
-                }
+    let configuration = context.configuration();
+
+    let read_timeout = Duration::from_secs(configuration.connection.read_timeout_seconds);
+    let _ = stream.set_read_timeout(Some(read_timeout).filter(|timeout| !timeout.is_zero()));
+
+    if let Err(error) = BufReader::new(&stream).read_line(&mut buffer) {
+        let timed_out = matches!(
+            error.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        );
+
+        if timed_out {
+            log::info!(peer_addr:% = peer; "{} didn't send a request within {:?}, closing the connection", peer, read_timeout);
+        } else {
+            log::error!(peer_addr:% = peer; "An error occurred, terminating connection with {}: {}", peer, error);
+        }
+
+        audit_log.record(AuditEvent::Rejection {
+            origin: Origin::Peer(peer),
+            reason: if timed_out {
+                "timed out waiting for a request"
+            } else {
+                "unreadable request"
             }
+            .to_string(),
+        });
+        let _ = stream.shutdown(Shutdown::Both);
+        return;
+    }
+
+    // Only the command is logged, since the rest of the message can hold the secret
+    log::debug!(
+        "Received a {} message from {}",
+        buffer.split_whitespace().next().unwrap_or("blank"),
+        peer
+    );
+
+    let response = match Request::parse_with_secret(&buffer, &configuration.secret) {
+        Request::Action {
+            action,
+            secret,
+            force,
+        } => handle_action(action, &secret, force, peer, context),
+        Request::Ping {
+            keepalive: Some(interval),
+        } => {
+            let connection = &configuration.connection;
+
+            let _keepalive = match KeepaliveSlot::take(
+                context,
+                connection.max_keepalive_connections,
+            ) {
+                Some(slot) => slot,
+                None => {
+                    log::info!(peer_addr:% = peer; "Refusing a keepalive connection from {} – the limit of {} is reached", peer, connection.max_keepalive_connections);
+                    context.status.record_connection_rejected();
+                    audit_log.record(AuditEvent::Rejection {
+                        origin: Origin::Peer(peer),
+                        reason: "too many keepalive connections".to_string(),
+                    });
+
+                    let reason = format!(
+                        "too many keepalive connections are open (the limit is {})",
+                        connection.max_keepalive_connections
+                    );
+                    reply(&stream, peer, &Response::error(ErrorKind::Rejected, reason));
+                    return;
+                }
+            };
 
-            log::debug!("Received message: {}", input);
+            let interval = interval
+                .unwrap_or_else(|| Duration::from_secs(connection.heartbeat_interval_seconds));
+
+            send_heartbeats(
+                &stream,
+                peer,
+                interval.max(MIN_HEARTBEAT_INTERVAL),
+                Duration::from_secs(connection.max_keepalive_seconds),
+            );
+            return;
         }
-        Err(_) => {
-            log::error!(
-                "An error occurred, terminating connection with {}",
-                stream.peer_addr().unwrap()
+        Request::Ping { keepalive: None } => pong(),
+        Request::Info { secret } => handle_info(secret.as_deref(), peer, context),
+    };
+
+    reply(&stream, peer, &response);
+}
+
+fn reply(stream: &TcpStream, peer: SocketAddr, response: &Response) {
+    if let Err(error) = (&*stream).write_all(format!("{response}\n").as_bytes()) {
+        log::debug!("Unable to reply to {}: {}", peer, error);
+    }
+}
+
+/// One of the limited number of `PING KEEPALIVE` connections, given back when it's dropped
+struct KeepaliveSlot<'a> {
+    keepalives: &'a AtomicUsize,
+}
+
+impl<'a> KeepaliveSlot<'a> {
+    /// Takes a slot, unless `maximum` are already taken
+    fn take(context: &'a ListenerContext, maximum: usize) -> Option<KeepaliveSlot<'a>> {
+        context
+            .keepalives
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                Some(open + 1).filter(|&open| open <= maximum)
+            })
+            .ok()
+            .map(|_| KeepaliveSlot {
+                keepalives: &context.keepalives,
+            })
+    }
+}
+
+impl Drop for KeepaliveSlot<'_> {
+    fn drop(&mut self) {
+        self.keepalives.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn pong() -> Response {
+    Response::Pong {
+        hostname: whoami::fallible::hostname().ok(),
+        uptime: System::new().uptime().ok(),
+    }
+}
+
+/// Sends a `PONG` on every `interval` until the client goes away, or until the connection has
+/// been open for `lifetime`. A controller that stops receiving them while the connection stays
+/// open knows the machine has hung.
+fn send_heartbeats(stream: &TcpStream, peer: SocketAddr, interval: Duration, lifetime: Duration) {
+    log::debug!("Sending heartbeats to {} every {:?}", peer, interval);
+
+    // A client that stops reading would otherwise leave the write blocked for good
+    let _ = stream.set_write_timeout(Some(interval));
+    let closing_at = Instant::now() + lifetime;
+
+    loop {
+        if let Err(error) = (&*stream).write_all(format!("{}\n", pong()).as_bytes()) {
+            log::debug!("Stopped sending heartbeats to {}: {}", peer, error);
+            return;
+        }
+
+        if Instant::now() + interval > closing_at {
+            log::debug!(
+                "Closing the keepalive connection from {} after {:?}",
+                peer,
+                lifetime
+            );
+
+            let reason = format!(
+                "keepalive connections are closed after {} seconds – reconnect to keep watching",
+                lifetime.as_secs()
             );
-            stream.shutdown(Shutdown::Both).unwrap();
+            reply(stream, peer, &Response::error(ErrorKind::Rejected, reason));
+            let _ = stream.shutdown(Shutdown::Both);
+            return;
+        }
+
+        thread::sleep(interval);
+    }
+}
+
+fn handle_info(secret: Option<&str>, peer: SocketAddr, context: &ListenerContext) -> Response {
+    let configuration = &context.configuration().info;
+
+    if !configuration.enabled {
+        return Response::error(ErrorKind::Invalid, "INFO is disabled");
+    }
+
+    if configuration.require_secret {
+        match secret {
+            None => return Response::error(ErrorKind::Unauthorized, "INFO requires the secret"),
+            Some(secret) if !authenticate(secret, peer, context) => {
+                return Response::error(ErrorKind::Unauthorized, "incorrect secret")
+            }
+            Some(_) => {}
+        }
+    }
+
+    log::debug!("Sending system information to {}", peer);
+
+    Response::Info(Box::new(SystemInfo::collect()))
+}
+
+/// Checks the secret a client sent, recording the attempt
+fn authenticate(secret: &str, peer: SocketAddr, context: &ListenerContext) -> bool {
+    let configuration = context.configuration();
+    log::debug!("Checking the secret sent by {}", peer);
+
+    let authenticated = configuration.secret == secret;
+    context.status.record_authentication(authenticated);
+    context.audit_log.record(AuditEvent::Authentication {
+        peer,
+        success: authenticated,
+    });
+
+    authenticated
+}
+
+fn handle_action(
+    action: PowerAction,
+    secret: &str,
+    force: bool,
+    peer: SocketAddr,
+    context: &Arc<ListenerContext>,
+) -> Response {
+    if !authenticate(secret, peer, context) {
+        return Response::error(ErrorKind::Unauthorized, "incorrect secret");
+    }
+
+    if context.configuration().notifications.countdown_seconds > 0 {
+        return countdown::start(action, force, Origin::Peer(peer), context);
+    }
+
+    perform_action(action, force, &Origin::Peer(peer), context)
+}
+
+/// Performs `action` once its origin has been trusted, subject to the maintenance windows, the
+/// inhibitors and the power backend. Remote requests and scheduled actions both end up here.
+pub fn perform_action(
+    action: PowerAction,
+    force: bool,
+    origin: &Origin,
+    context: &ListenerContext,
+) -> Response {
+    if let Err(response) = check_action(action, force, origin, context) {
+        return response;
+    }
+
+    let audit_log = &context.audit_log;
+
+    match action.perform(&context.configuration().power) {
+        Ok(_) => {
+            log::info!(peer_addr:% = origin, action:% = action; "Performing {} - source: {}", action, origin);
+            systemd::notify_stopping();
+            context.status.record_action(true);
+            audit_log.record(AuditEvent::Action {
+                origin: origin.clone(),
+                action: action.to_string(),
+                success: true,
+                error: None,
+            });
+
+            Response::Ok { action }
+        }
+        Err(PowerError::Inhibited(reason)) => reject(action, reason, origin, context),
+        Err(PowerError::Failed(error)) => {
+            log::error!(peer_addr:% = origin, action:% = action; "Failed to {}: {}", action, error);
+            context.status.record_action(false);
+            audit_log.record(AuditEvent::Action {
+                origin: origin.clone(),
+                action: action.to_string(),
+                success: false,
+                error: Some(error.to_string()),
+            });
+
+            Response::error(ErrorKind::Failed, error.to_string())
         }
     }
 }
+
+/// Checks the maintenance windows and inhibitors, returning the response to send if `action`
+/// isn't allowed right now
+pub fn check_action(
+    action: PowerAction,
+    force: bool,
+    origin: &Origin,
+    context: &ListenerContext,
+) -> Result<(), Response> {
+    let configuration = context.configuration();
+
+    // Unlike the inhibitors, maintenance windows can't be forced
+    if let Err(reason) = configuration.maintenance.check(action, (context.clock)()) {
+        return Err(reject(action, reason, origin, context));
+    }
+
+    let inhibitors = &configuration.inhibitors;
+
+    if force && inhibitors.allow_force {
+        log::info!(peer_addr:% = origin, action:% = action; "{} forced {}, skipping the inhibitor checks", origin, action);
+    } else {
+        if force {
+            log::warn!(peer_addr:% = origin; "{} asked to force {}, but forcing isn't allowed", origin, action);
+        }
+
+        if let Some(reason) = inhibitors.check() {
+            return Err(reject(action, reason, origin, context));
+        }
+    }
+
+    Ok(())
+}
+
+fn reject(
+    action: PowerAction,
+    reason: String,
+    origin: &Origin,
+    context: &ListenerContext,
+) -> Response {
+    log::info!(peer_addr:% = origin, action:% = action; "Refusing to {}: {}", action, reason);
+    context.status.record_action_rejected();
+    context.audit_log.record(AuditEvent::Rejection {
+        origin: origin.clone(),
+        reason: format!("{action} refused: {reason}"),
+    });
+
+    Response::error(ErrorKind::Rejected, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maintenance::{MaintenanceWindow, TimeOfDay, WindowPolicy};
+    use std::convert::TryFrom;
+
+    /// A listener that's only allowed to act between 18:00 and 19:00 UTC, at `clock`
+    fn context(clock: fn() -> DateTime<Utc>) -> ListenerContext {
+        let mut configuration = AppConfiguration::default();
+        configuration.audit.enabled = false;
+        configuration.maintenance.timezone = Some(chrono_tz::UTC);
+        configuration.maintenance.windows = vec![MaintenanceWindow {
+            name: None,
+            policy: WindowPolicy::Allow,
+            days: Vec::new(),
+            start: TimeOfDay::try_from("18:00".to_string()).unwrap(),
+            end: TimeOfDay::try_from("19:00".to_string()).unwrap(),
+            actions: Vec::new(),
+        }];
+
+        let mut context = ListenerContext::new(&configuration);
+        context.clock = clock;
+        context
+    }
+
+    fn check(context: &ListenerContext) -> Result<(), Response> {
+        let origin = Origin::Schedule("test".to_string());
+        check_action(PowerAction::Shutdown, false, &origin, context)
+    }
+
+    fn addresses(addresses: &[&str]) -> Vec<IpAddr> {
+        addresses
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn connections_must_arrive_on_a_configured_address() {
+        let configured = addresses(&["10.0.1.100", "fe80::1"]);
+
+        assert!(is_configured_address(
+            &configured,
+            "10.0.1.100".parse().unwrap()
+        ));
+        assert!(is_configured_address(
+            &configured,
+            "fe80::1".parse().unwrap()
+        ));
+        assert!(!is_configured_address(
+            &configured,
+            "192.168.1.5".parse().unwrap()
+        ));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_their_ipv4_address() {
+        let configured = addresses(&["10.0.1.100"]);
+
+        assert!(is_configured_address(
+            &configured,
+            "::ffff:10.0.1.100".parse().unwrap()
+        ));
+        assert!(!is_configured_address(
+            &configured,
+            "::ffff:10.0.1.101".parse().unwrap()
+        ));
+        assert!(is_configured_address(
+            &addresses(&["0.0.0.0"]),
+            "::ffff:10.0.1.100".parse().unwrap()
+        ));
+    }
+
+    #[test]
+    fn unspecified_addresses_match_every_interface() {
+        let ipv4 = addresses(&["0.0.0.0"]);
+        let any = addresses(&["::"]);
+
+        assert!(is_configured_address(&ipv4, "10.0.1.100".parse().unwrap()));
+        assert!(!is_configured_address(&ipv4, "fe80::1".parse().unwrap()));
+        assert!(is_configured_address(&any, "10.0.1.100".parse().unwrap()));
+        assert!(is_configured_address(&any, "fe80::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn actions_are_checked_against_the_listeners_clock() {
+        let inside = context(|| "2024-01-01T18:30:00Z".parse().unwrap());
+        let outside = context(|| "2024-01-01T12:00:00Z".parse().unwrap());
+
+        assert_eq!(check(&inside), Ok(()));
+        assert!(matches!(
+            check(&outside),
+            Err(Response::Error {
+                kind: ErrorKind::Rejected,
+                ..
+            })
+        ));
+        assert_eq!(outside.status.report().counters.actions_rejected, 1);
+    }
+
+    #[test]
+    fn accepting_beats_the_heartbeat_while_idle_and_stops_when_replaced() {
+        let context = Arc::new(context(Utc::now));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let thread = spawn_accepting(listener, 0, context.clone());
+
+        thread::sleep(systemd::HEARTBEAT_INTERVAL * 2);
+        let limit = systemd::HEARTBEAT_INTERVAL + Duration::from_millis(500);
+        assert!(context.heartbeats.stale(Instant::now(), limit).is_empty());
+
+        // Nobody connects, but the thread still notices it's been replaced
+        context.generation.fetch_add(1, Ordering::SeqCst);
+        thread::sleep(systemd::HEARTBEAT_INTERVAL * 2);
+        assert!(thread.is_finished());
+    }
+
+    #[test]
+    fn connections_are_accepted_in_blocking_mode() {
+        let context = Arc::new(context(Utc::now));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        spawn_accepting(listener, 0, context.clone());
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(b"PING\n").unwrap();
+
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply).unwrap();
+        assert!(reply.starts_with("PONG"), "{:?}", reply);
+    }
+}
@@ -0,0 +1,442 @@
+use serde::{Deserialize, Serialize};
+use simplelog::{
+    ColorChoice, CombinedLogger, Config, LevelFilter, SharedLogger, TermLogger, TerminalMode,
+};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::rotating_file::RotatingFile;
+use crate::system_log::{self, SystemLogConfiguration};
+
+/// Settings for the diagnostic log. Any of these can be overridden at launch with the
+/// `--log-level`, `--log-file` and `--log-format` flags, and the level with `RUST_LOG`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct LoggingConfiguration {
+    pub level: LevelFilter,
+
+    /// Also write the log to this file (in addition to the terminal)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+
+    /// Rotate the log file once it grows past this size. `0` disables rotation.
+    pub max_size_bytes: u64,
+
+    /// The number of rotated log files to keep around.
+    pub max_files: u32,
+
+    pub format: LogFormat,
+
+    /// Also forward the log to journald or syslog
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<SystemLogConfiguration>,
+}
+
+impl Default for LoggingConfiguration {
+    fn default() -> Self {
+        if cfg!(debug_assertions) {
+            LoggingConfiguration {
+                level: LevelFilter::Debug,
+                file: Some(PathBuf::from("shutdown-on-lan.log")),
+                max_size_bytes: 10 * 1024 * 1024,
+                max_files: 5,
+                format: LogFormat::Plain,
+                system: None,
+            }
+        } else {
+            LoggingConfiguration {
+                level: LevelFilter::Info,
+                file: None,
+                max_size_bytes: 10 * 1024 * 1024,
+                max_files: 5,
+                format: LogFormat::Plain,
+                system: None,
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Plain,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string.to_ascii_lowercase().as_str() {
+            "plain" => Ok(LogFormat::Plain),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "Unknown log format {string:?} – expected `plain` or `json`"
+            )),
+        }
+    }
+}
+
+/// Reads the log level out of `RUST_LOG`. Both a bare level (`debug`) and `env_logger`-style
+/// directives (`shutdown_on_lan=trace,warn`) are understood – a directive for this crate wins
+/// over a bare level.
+pub fn level_from_environment() -> Option<LevelFilter> {
+    level_from_directives(&std::env::var("RUST_LOG").ok()?)
+}
+
+fn level_from_directives(value: &str) -> Option<LevelFilter> {
+    let mut level = None;
+
+    for directive in value.split(',').map(str::trim) {
+        match directive.split_once('=') {
+            Some((target, directive_level)) => {
+                if target.starts_with("shutdown_on_lan") {
+                    if let Ok(directive_level) = directive_level.parse() {
+                        return Some(directive_level);
+                    }
+                }
+            }
+            None => {
+                if let Ok(bare_level) = directive.parse() {
+                    level = Some(bare_level);
+                }
+            }
+        }
+    }
+
+    level
+}
+
+pub fn init(configuration: &LoggingConfiguration) {
+    let (loggers, errors) = loggers(configuration);
+
+    CombinedLogger::init(loggers).unwrap();
+
+    for error in errors {
+        log::error!("{}", error);
+    }
+
+    log::debug!("Logging initialized with {:?}", configuration);
+}
+
+/// The loggers for each configured target, and why any target couldn't be set up. The terminal
+/// is always logged to, so there's somewhere to report those errors.
+fn loggers(configuration: &LoggingConfiguration) -> (Vec<Box<dyn SharedLogger>>, Vec<String>) {
+    let mut loggers: Vec<Box<dyn SharedLogger>> = Vec::new();
+
+    match configuration.format {
+        LogFormat::Plain => loggers.push(TermLogger::new(
+            configuration.level,
+            Config::default(),
+            TerminalMode::Mixed,
+            ColorChoice::Auto,
+        )),
+        LogFormat::Json => loggers.push(Box::new(LineLogger {
+            level: configuration.level,
+            format: LogFormat::Json,
+            sink: Mutex::new(LineSink::Stderr),
+        })),
+    }
+
+    let mut errors = Vec::new();
+
+    if let Some(path) = &configuration.file {
+        match RotatingFile::open(
+            path,
+            configuration.max_size_bytes,
+            None,
+            configuration.max_files,
+        ) {
+            Ok(file) => loggers.push(Box::new(LineLogger {
+                level: configuration.level,
+                format: configuration.format,
+                sink: Mutex::new(LineSink::File(file)),
+            })),
+            Err(error) => errors.push(format!("Unable to open log file at {path:?}: {error}")),
+        }
+    }
+
+    if let Some(system) = &configuration.system {
+        match system_log::logger(system, configuration.level) {
+            Ok(logger) => loggers.push(logger),
+            Err(error) => errors.push(format!("Unable to connect to {system:?}: {error}")),
+        }
+    }
+
+    (loggers, errors)
+}
+
+/// The structured key-value pairs attached to `record` (for instance `peer_addr` or `action`)
+pub fn key_values(record: &log::Record) -> Vec<(String, String)> {
+    struct Collector(Vec<(String, String)>);
+
+    impl<'kvs> log::kv::VisitSource<'kvs> for Collector {
+        fn visit_pair(
+            &mut self,
+            key: log::kv::Key<'kvs>,
+            value: log::kv::Value<'kvs>,
+        ) -> Result<(), log::kv::Error> {
+            self.0.push((key.to_string(), value.to_string()));
+            Ok(())
+        }
+    }
+
+    let mut collector = Collector(Vec::new());
+    let _ = record.key_values().visit(&mut collector);
+    collector.0
+}
+
+enum LineSink {
+    Stderr,
+    File(RotatingFile),
+}
+
+/// A logger that writes each record as a single line, either as plain text or as JSON.
+struct LineLogger {
+    level: LevelFilter,
+    format: LogFormat,
+    sink: Mutex<LineSink>,
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: &'a str,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    fields: BTreeMap<String, String>,
+}
+
+impl LineLogger {
+    fn format(&self, record: &log::Record) -> String {
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+
+        let fields = key_values(record);
+
+        match self.format {
+            LogFormat::Plain => {
+                let mut line = format!(
+                    "{} [{}] {}: {}",
+                    timestamp,
+                    record.level(),
+                    record.target(),
+                    record.args()
+                );
+
+                for (key, value) in fields {
+                    line.push_str(&format!(" {key}={value}"));
+                }
+
+                line
+            }
+            LogFormat::Json => serde_json::to_string(&JsonRecord {
+                timestamp: &timestamp,
+                level: record.level().as_str(),
+                target: record.target(),
+                message: record.args().to_string(),
+                fields: fields.into_iter().collect(),
+            })
+            .unwrap_or_default(),
+        }
+    }
+}
+
+impl log::Log for LineLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = self.format(record);
+        let mut sink = self.sink.lock().unwrap_or_else(|error| error.into_inner());
+
+        // There's nowhere left to report a failure to write a log line, so it's dropped
+        let _ = match &mut *sink {
+            LineSink::Stderr => writeln!(std::io::stderr(), "{line}"),
+            LineSink::File(file) => file.write_line(&line),
+        };
+    }
+
+    fn flush(&self) {}
+}
+
+impl SharedLogger for LineLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn log::Log> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn configuration(format: LogFormat, file: Option<PathBuf>) -> LoggingConfiguration {
+        LoggingConfiguration {
+            level: LevelFilter::Info,
+            file,
+            format,
+            ..Default::default()
+        }
+    }
+
+    fn line(format: LogFormat, fields: &[(&str, &str)]) -> String {
+        let logger = LineLogger {
+            level: LevelFilter::Info,
+            format,
+            sink: Mutex::new(LineSink::Stderr),
+        };
+
+        logger.format(
+            &log::Record::builder()
+                .level(log::Level::Warn)
+                .target("shutdown_on_lan::listener_service")
+                .args(format_args!("Refused {}", "shutdown"))
+                .key_values(&fields)
+                .build(),
+        )
+    }
+
+    #[test]
+    fn log_formats_are_parsed_in_any_case() {
+        assert_eq!("plain".parse(), Ok(LogFormat::Plain));
+        assert_eq!("JSON".parse(), Ok(LogFormat::Json));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn bare_levels_are_read_from_directives() {
+        assert_eq!(level_from_directives("debug"), Some(LevelFilter::Debug));
+        assert_eq!(level_from_directives(" warn "), Some(LevelFilter::Warn));
+        assert_eq!(level_from_directives("verbose"), None);
+        assert_eq!(level_from_directives(""), None);
+    }
+
+    #[test]
+    fn directives_for_this_crate_win_over_bare_levels() {
+        assert_eq!(
+            level_from_directives("warn,shutdown_on_lan=trace"),
+            Some(LevelFilter::Trace)
+        );
+        assert_eq!(
+            level_from_directives("shutdown_on_lan::reload=debug,error"),
+            Some(LevelFilter::Debug)
+        );
+        assert_eq!(
+            level_from_directives("zbus=trace,info"),
+            Some(LevelFilter::Info)
+        );
+        assert_eq!(
+            level_from_directives("shutdown_on_lan=loud,info"),
+            Some(LevelFilter::Info)
+        );
+    }
+
+    #[test]
+    fn the_terminal_is_always_logged_to() {
+        for format in [LogFormat::Plain, LogFormat::Json] {
+            let (loggers, errors) = loggers(&configuration(format, None));
+
+            assert_eq!(loggers.len(), 1, "{:?}", format);
+            assert!(errors.is_empty(), "{:?}", errors);
+        }
+    }
+
+    #[test]
+    fn files_are_logged_to_as_well() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("shutdown-on-lan.log");
+
+        let (loggers, errors) = loggers(&configuration(LogFormat::Json, Some(path.clone())));
+
+        assert_eq!(loggers.len(), 2);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(loggers
+            .iter()
+            .all(|logger| logger.level() == LevelFilter::Info));
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn files_that_cant_be_opened_are_reported_and_skipped() {
+        let directory = TempDir::new().unwrap();
+        let file = directory.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let path = file.join("shutdown-on-lan.log");
+
+        let (loggers, errors) = loggers(&configuration(LogFormat::Plain, Some(path)));
+
+        assert_eq!(loggers.len(), 1);
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].starts_with("Unable to open log file"),
+            "{}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn plain_lines_end_with_the_fields() {
+        let line = line(LogFormat::Plain, &[("peer_addr", "192.0.2.1:50000")]);
+        let (_timestamp, rest) = line.split_once(' ').unwrap();
+
+        assert_eq!(
+            rest,
+            "[WARN] shutdown_on_lan::listener_service: Refused shutdown peer_addr=192.0.2.1:50000"
+        );
+    }
+
+    #[test]
+    fn json_lines_hold_the_fields_separately() {
+        let line = line(LogFormat::Json, &[("action", "shutdown")]);
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+
+        assert_eq!(record["level"], "WARN");
+        assert_eq!(record["target"], "shutdown_on_lan::listener_service");
+        assert_eq!(record["message"], "Refused shutdown");
+        assert_eq!(record["fields"]["action"], "shutdown");
+        assert!(OffsetDateTime::parse(record["timestamp"].as_str().unwrap(), &Rfc3339).is_ok());
+    }
+
+    #[test]
+    fn json_lines_leave_out_empty_fields() {
+        let record: serde_json::Value = serde_json::from_str(&line(LogFormat::Json, &[])).unwrap();
+
+        assert!(record.get("fields").is_none());
+    }
+
+    #[test]
+    fn records_below_the_level_are_dropped() {
+        let logger = LineLogger {
+            level: LevelFilter::Warn,
+            format: LogFormat::Plain,
+            sink: Mutex::new(LineSink::Stderr),
+        };
+
+        let metadata = |level| log::Metadata::builder().level(level).build();
+
+        assert!(log::Log::enabled(&logger, &metadata(log::Level::Error)));
+        assert!(log::Log::enabled(&logger, &metadata(log::Level::Warn)));
+        assert!(!log::Log::enabled(&logger, &metadata(log::Level::Info)));
+    }
+}
@@ -0,0 +1,398 @@
+//! Performs power actions through systemd-logind, so that inhibitor locks taken by backup jobs,
+//! package managers and the like are respected. `system_shutdown` asks logind to ignore them.
+
+use std::convert::TryFrom;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use zbus::blocking::{Connection, ConnectionBuilder};
+use zbus::zvariant::{self, OwnedValue};
+
+use crate::power::{LogindConfiguration, PowerAction};
+
+const DESTINATION: &str = "org.freedesktop.login1";
+const PATH: &str = "/org/freedesktop/login1";
+const INTERFACE: &str = "org.freedesktop.login1.Manager";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+/// How often to check again while waiting for inhibitors to be released
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An inhibitor lock, as returned by `ListInhibitors`
+#[derive(Debug, Clone)]
+pub struct Inhibitor {
+    /// Colon-separated list of what's inhibited, such as `shutdown:sleep`
+    pub what: String,
+    pub who: String,
+    pub why: String,
+    /// `block` or `delay`
+    pub mode: String,
+    pub uid: u32,
+    pub pid: u32,
+}
+
+#[derive(Error, Debug)]
+pub enum LogindError {
+    #[error("Unable to connect to logind: {0}")]
+    Unavailable(zbus::Error),
+
+    #[error("{0}")]
+    Inhibited(String),
+
+    #[error("logind refused: {0}")]
+    Failed(zbus::Error),
+}
+
+pub struct Logind {
+    connection: Connection,
+}
+
+impl Logind {
+    /// Connects to the system bus, or to `address` if given (for testing against a mock logind)
+    pub fn connect(address: Option<&str>) -> Result<Logind, LogindError> {
+        let connection = match address {
+            Some(address) => {
+                ConnectionBuilder::address(address).and_then(|builder| builder.build())
+            }
+            None => Connection::system(),
+        }
+        .map_err(LogindError::Unavailable)?;
+
+        Ok(Logind { connection })
+    }
+
+    pub fn list_inhibitors(&self) -> zbus::Result<Vec<Inhibitor>> {
+        let reply = self.connection.call_method(
+            Some(DESTINATION),
+            PATH,
+            Some(INTERFACE),
+            "ListInhibitors",
+            &(),
+        )?;
+
+        let inhibitors: Vec<(String, String, String, String, u32, u32)> = reply.body()?;
+
+        Ok(inhibitors
+            .into_iter()
+            .map(|(what, who, why, mode, uid, pid)| Inhibitor {
+                what,
+                who,
+                why,
+                mode,
+                uid,
+                pid,
+            })
+            .collect())
+    }
+
+    /// The `block` inhibitors that stop `action` from happening right now
+    pub fn blocking_inhibitors(&self, action: PowerAction) -> zbus::Result<Vec<Inhibitor>> {
+        let inhibited = inhibitor_kind(action);
+
+        Ok(self
+            .list_inhibitors()?
+            .into_iter()
+            .filter(|inhibitor| {
+                inhibitor.mode == "block" && inhibitor.what.split(':').any(|what| what == inhibited)
+            })
+            .collect())
+    }
+
+    /// How long since anyone used the machine, going by logind's idle hint (which covers every
+    /// session, and is set when nobody is logged in)
+    pub fn idle_time(&self) -> zbus::Result<Duration> {
+        if !self.property::<bool>("IdleHint")? {
+            return Ok(Duration::ZERO);
+        }
+
+        let since = UNIX_EPOCH + Duration::from_micros(self.property::<u64>("IdleSinceHint")?);
+
+        Ok(SystemTime::now().duration_since(since).unwrap_or_default())
+    }
+
+    fn property<T>(&self, name: &str) -> zbus::Result<T>
+    where
+        T: TryFrom<OwnedValue, Error = zvariant::Error>,
+    {
+        let reply = self.connection.call_method(
+            Some(DESTINATION),
+            PATH,
+            Some(PROPERTIES_INTERFACE),
+            "Get",
+            &(INTERFACE, name),
+        )?;
+
+        Ok(T::try_from(reply.body::<OwnedValue>()?)?)
+    }
+
+    /// Performs `action`, first waiting up to the configured time for any blocking inhibitors to
+    /// be released
+    pub fn perform(
+        &self,
+        action: PowerAction,
+        configuration: &LogindConfiguration,
+    ) -> Result<(), LogindError> {
+        let deadline =
+            Instant::now() + Duration::from_secs(configuration.wait_for_inhibitors_seconds);
+
+        loop {
+            let inhibitors = self
+                .blocking_inhibitors(action)
+                .map_err(LogindError::Failed)?;
+
+            if inhibitors.is_empty() {
+                break;
+            }
+
+            let reason = describe(&inhibitors);
+
+            if Instant::now() >= deadline {
+                return Err(LogindError::Inhibited(reason));
+            }
+
+            log::info!("Waiting to {}: {}", action, reason);
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        // Not interactive: logind mustn't prompt for authorization, or override other users' sessions
+        self.connection
+            .call_method(
+                Some(DESTINATION),
+                PATH,
+                Some(INTERFACE),
+                method_name(action),
+                &(false),
+            )
+            .map_err(LogindError::Failed)?;
+
+        Ok(())
+    }
+}
+
+fn method_name(action: PowerAction) -> &'static str {
+    match action {
+        PowerAction::Shutdown => "PowerOff",
+        PowerAction::Reboot => "Reboot",
+        PowerAction::Sleep => "Suspend",
+        PowerAction::Hibernate => "Hibernate",
+    }
+}
+
+fn inhibitor_kind(action: PowerAction) -> &'static str {
+    match action {
+        PowerAction::Shutdown | PowerAction::Reboot => "shutdown",
+        PowerAction::Sleep | PowerAction::Hibernate => "sleep",
+    }
+}
+
+fn describe(inhibitors: &[Inhibitor]) -> String {
+    let descriptions: Vec<String> = inhibitors
+        .iter()
+        .map(|inhibitor| {
+            format!(
+                "{} (uid {}, pid {}) is blocking it: {}",
+                inhibitor.who, inhibitor.uid, inhibitor.pid, inhibitor.why
+            )
+        })
+        .collect();
+
+    descriptions.join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use zbus::dbus_interface;
+
+    type InhibitorRecord = (String, String, String, String, u32, u32);
+
+    /// Stands in for logind's manager, answering just the methods `perform` calls
+    #[derive(Clone, Default)]
+    struct MockManager {
+        inhibitors: Arc<Mutex<Vec<InhibitorRecord>>>,
+        power_offs: Arc<AtomicUsize>,
+    }
+
+    #[dbus_interface(name = "org.freedesktop.login1.Manager")]
+    impl MockManager {
+        fn list_inhibitors(&self) -> Vec<InhibitorRecord> {
+            self.inhibitors.lock().unwrap().clone()
+        }
+
+        fn power_off(&self, _interactive: bool) {
+            self.power_offs.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl MockManager {
+        fn inhibit(&self, what: &str, mode: &str) {
+            self.inhibitors.lock().unwrap().push((
+                what.to_string(),
+                "backup".to_string(),
+                "Backing up".to_string(),
+                mode.to_string(),
+                0,
+                42,
+            ));
+        }
+
+        fn release(&self) {
+            self.inhibitors.lock().unwrap().clear();
+        }
+
+        fn power_offs(&self) -> usize {
+            self.power_offs.load(Ordering::SeqCst)
+        }
+    }
+
+    /// A private session bus with the mock logind on it, torn down when dropped
+    struct MockLogind {
+        daemon: Child,
+        address: String,
+        manager: MockManager,
+
+        /// Kept open so the mock keeps answering
+        _connection: Connection,
+    }
+
+    impl MockLogind {
+        /// Starts the bus, or returns `None` if `dbus-daemon` isn't installed
+        fn start() -> Option<MockLogind> {
+            let mut daemon = match Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address=1"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+            {
+                Ok(daemon) => daemon,
+                Err(error) => {
+                    eprintln!("Skipping the logind tests, as dbus-daemon can't be run: {error}");
+                    return None;
+                }
+            };
+
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+            let address = address.trim().to_string();
+
+            let manager = MockManager::default();
+            let connection = ConnectionBuilder::address(address.as_str())
+                .and_then(|builder| builder.name(DESTINATION))
+                .and_then(|builder| builder.serve_at(PATH, manager.clone()))
+                .and_then(|builder| builder.build())
+                .unwrap();
+
+            Some(MockLogind {
+                daemon,
+                address,
+                manager,
+                _connection: connection,
+            })
+        }
+
+        fn logind(&self) -> Logind {
+            Logind::connect(Some(&self.address)).unwrap()
+        }
+    }
+
+    impl Drop for MockLogind {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    fn waiting(seconds: u64) -> LogindConfiguration {
+        LogindConfiguration {
+            wait_for_inhibitors_seconds: seconds,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn actions_go_ahead_without_inhibitors() {
+        let mock = match MockLogind::start() {
+            Some(mock) => mock,
+            None => return,
+        };
+
+        mock.logind()
+            .perform(PowerAction::Shutdown, &waiting(0))
+            .unwrap();
+
+        assert_eq!(mock.manager.power_offs(), 1);
+    }
+
+    #[test]
+    fn only_blocking_inhibitors_for_the_action_count() {
+        let mock = match MockLogind::start() {
+            Some(mock) => mock,
+            None => return,
+        };
+
+        mock.manager.inhibit("shutdown", "delay");
+        mock.manager.inhibit("sleep:idle", "block");
+
+        let inhibitors = mock
+            .logind()
+            .blocking_inhibitors(PowerAction::Shutdown)
+            .unwrap();
+        assert!(inhibitors.is_empty());
+
+        let inhibitors = mock
+            .logind()
+            .blocking_inhibitors(PowerAction::Sleep)
+            .unwrap();
+        assert_eq!(inhibitors.len(), 1);
+        assert_eq!(inhibitors[0].pid, 42);
+    }
+
+    #[test]
+    fn blocking_inhibitors_refuse_the_action() {
+        let mock = match MockLogind::start() {
+            Some(mock) => mock,
+            None => return,
+        };
+
+        mock.manager.inhibit("shutdown:sleep", "block");
+
+        let result = mock.logind().perform(PowerAction::Shutdown, &waiting(0));
+
+        match result {
+            Err(LogindError::Inhibited(reason)) => assert!(reason.contains("backup")),
+            result => panic!("expected the shutdown to be inhibited, got {:?}", result),
+        }
+        assert_eq!(mock.manager.power_offs(), 0);
+    }
+
+    #[test]
+    fn actions_wait_for_inhibitors_to_be_released() {
+        let mock = match MockLogind::start() {
+            Some(mock) => mock,
+            None => return,
+        };
+
+        mock.manager.inhibit("shutdown", "block");
+
+        let manager = mock.manager.clone();
+        let releasing = thread::spawn(move || {
+            thread::sleep(POLL_INTERVAL + POLL_INTERVAL / 2);
+            manager.release();
+        });
+
+        let started = Instant::now();
+        mock.logind()
+            .perform(PowerAction::Shutdown, &waiting(10))
+            .unwrap();
+        releasing.join().unwrap();
+
+        assert!(started.elapsed() >= POLL_INTERVAL);
+        assert_eq!(mock.manager.power_offs(), 1);
+    }
+}
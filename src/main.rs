@@ -3,16 +3,47 @@ extern crate log;
 extern crate simplelog;
 extern crate system_shutdown;
 
+use crate::client::{Presence, SendRequest};
 use crate::configuration::AppConfiguration;
+use crate::fleet::{FleetOptions, Inventory};
+use crate::logging::LogFormat;
+use crate::power::PowerAction;
+use crate::service_installer::ServiceInstaller;
+use crate::system_log::SystemLogConfiguration;
 use anyhow::{Context, Result};
-use simplelog::*;
-use std::fs::File;
+use simplelog::LevelFilter;
+use std::path::PathBuf;
 use std::process;
-use std::vec;
+use std::time::Duration;
 use structopt::StructOpt;
 
+mod audit;
+mod client;
 mod configuration;
+mod configuration_migration;
+mod configuration_storage;
+mod configuration_validation;
+mod control;
+mod countdown;
+mod fleet;
+mod idle;
+mod inhibitors;
 mod listener_service;
+mod logging;
+#[cfg(target_os = "linux")]
+mod logind;
+mod maintenance;
+mod notifications;
+mod power;
+mod protocol;
+mod reload;
+mod rotating_file;
+mod scheduler;
+mod service_installer;
+mod status;
+mod system_info;
+mod system_log;
+mod systemd;
 mod windows_listener_service;
 
 #[derive(Debug, StructOpt)]
@@ -21,10 +52,41 @@ mod windows_listener_service;
     about = "A tool for implementing the opposite of wake-on-LAN – the ability to remotely shut down a machine."
 )]
 struct AppArguments {
+    /// Use this configuration file instead of the usual one. A `.toml`, `.json` or `.plist` file is read as such, and anything else as INI
+    #[structopt(
+        long = "config",
+        global = true,
+        env = configuration_storage::CONFIGURATION_PATH_VARIABLE,
+        parse(from_os_str)
+    )]
+    config: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    logging: LoggingArguments,
+
     #[structopt(subcommand)]
     command: Option<Command>,
 }
 
+#[derive(Debug, StructOpt)]
+struct LoggingArguments {
+    /// The most verbose level to log at (error, warn, info, debug or trace). Overrides `RUST_LOG` and the configuration file
+    #[structopt(long = "log-level", global = true)]
+    level: Option<LevelFilter>,
+
+    /// Also write the log to this file. Overrides the configuration file
+    #[structopt(long = "log-file", global = true, parse(from_os_str))]
+    file: Option<PathBuf>,
+
+    /// Write log lines as `plain` text or `json`. Overrides the configuration file
+    #[structopt(long = "log-format", global = true)]
+    format: Option<LogFormat>,
+
+    /// Also forward the log to `journald`, `syslog`, or `syslog:<address>` (where the address is `unix:<path>` or `udp:<host>:<port>`). Overrides the configuration file
+    #[structopt(long = "system-log", global = true)]
+    system: Option<SystemLogConfiguration>,
+}
+
 #[derive(Debug, StructOpt)]
 enum Command {
     Get {
@@ -46,15 +108,121 @@ enum Command {
         #[structopt(long = "secret")]
         secret: Option<String>,
     },
+    /// Ask a remote machine (or every machine in an inventory file) to shut down, or perform another action, and report the response
+    Send {
+        /// The machine to send the request to
+        #[structopt(required_unless = "inventory")]
+        host: Option<String>,
+
+        /// Send the request to every machine listed in this TOML or CSV file instead
+        #[structopt(long = "inventory", parse(from_os_str), conflicts_with = "host")]
+        inventory: Option<PathBuf>,
+
+        /// Only send to machines in this group of the inventory file
+        #[structopt(long = "group", requires = "inventory")]
+        group: Option<String>,
+
+        /// The port the remote machine is listening on (for inventory hosts that don't specify one)
+        #[structopt(long = "port", default_value = "53632")]
+        port: u16,
+
+        /// The remote machine's secret (for inventory hosts that don't specify one)
+        #[structopt(long = "secret", required_unless = "inventory")]
+        secret: Option<String>,
+
+        /// What to ask the remote machine to do: shutdown, reboot, sleep or hibernate
+        #[structopt(long = "action", default_value = "shutdown")]
+        action: PowerAction,
+
+        /// Ask the remote machine to skip its safety checks (such as refusing while a user is logged in), if it allows that
+        #[structopt(long = "force")]
+        force: bool,
+
+        /// How many seconds to wait for the remote machine to connect and respond
+        #[structopt(long = "timeout", default_value = "10")]
+        timeout: u64,
+
+        /// The most inventory hosts to contact at once
+        #[structopt(long = "parallel", default_value = "16")]
+        parallel: usize,
+
+        /// How many more times to try an inventory host that couldn't be reached
+        #[structopt(long = "retries", default_value = "2")]
+        retries: u32,
+    },
+    /// Check whether a remote machine's listener is up (no secret needed), or keep watching it for heartbeats
+    Ping {
+        /// The machine to check
+        host: String,
+
+        /// The port the remote machine is listening on
+        #[structopt(long = "port", default_value = "53632")]
+        port: u16,
+
+        /// How many seconds to wait for the remote machine to connect and respond
+        #[structopt(long = "timeout", default_value = "10")]
+        timeout: u64,
+
+        /// Keep the connection open and print a heartbeat every this many seconds, until the machine goes away or stops responding
+        #[structopt(long = "watch")]
+        watch: Option<u64>,
+    },
+    /// Ask a remote machine to describe its state – uptime, load, logged-in users, power and temperature – as JSON
+    Info {
+        /// The machine to ask
+        host: String,
+
+        /// The port the remote machine is listening on
+        #[structopt(long = "port", default_value = "53632")]
+        port: u16,
+
+        /// The remote machine's secret, if it requires one for this
+        #[structopt(long = "secret")]
+        secret: Option<String>,
+
+        /// How many seconds to wait for the remote machine to connect and respond
+        #[structopt(long = "timeout", default_value = "10")]
+        timeout: u64,
+    },
+    /// Ask the running service what it's doing – uptime, bound sockets, open connections, counters and the configuration it's using (with the secret redacted)
+    Status {
+        /// Print the raw JSON report
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// Check the configuration for problems – such as addresses this machine doesn't have, a weak secret or files that can't be written – and list every one of them. The exit code is the one for the first problem
+    Check {},
     /// Run the tool in standalone mode (mostly only useful on Windows, the same as running with no arguments on other platforms)
     Run {},
+    /// Register this executable as a service (systemd on Linux, launchd on macOS and the Service Control Manager on Windows) and create the default configuration if there isn't one
+    Install {
+        /// Write the service files relative to this directory instead of `/` (without starting the service)
+        #[structopt(long = "root", default_value = "/", parse(from_os_str), hidden = true)]
+        root: PathBuf,
+    },
+    /// Stop the service and unregister it. The configuration is left in place
+    Uninstall {
+        /// Remove the service files relative to this directory instead of `/`
+        #[structopt(long = "root", default_value = "/", parse(from_os_str), hidden = true)]
+        root: PathBuf,
+    },
+    /// Print a systemd unit for running this tool as a service
+    SystemdUnit {
+        /// Print a socket unit (listening on the configured port) instead, for socket activation
+        #[structopt(long = "socket")]
+        socket: bool,
+    },
 }
 
 fn main() -> Result<()> {
-    init_logging();
-
     let args = AppArguments::from_args();
 
+    if let Some(path) = args.config {
+        configuration_storage::set_path_override(path);
+    }
+
+    init_logging(&args.logging);
+
     match args.command {
         None => run()?,
         Some(Command::Set {
@@ -83,7 +251,11 @@ fn main() -> Result<()> {
 
             if let Some(ip_address) = ip_address {
                 println!("Set IP Addresses: {ip_address:?}");
-                config.set_addresses(ip_address);
+
+                if let Err(error) = config.set_addresses(ip_address) {
+                    eprintln!("{error}");
+                    process::exit(error.exit_code());
+                }
             }
 
             if let Some(secret) = secret {
@@ -93,10 +265,30 @@ fn main() -> Result<()> {
 
             log::debug!("Saving Configuration");
 
-            config.save()?;
+            configuration_storage::storage().save(&config)?;
 
             println!("Configuration Changes Saved.");
         }
+        Some(Command::Check {}) => {
+            let storage = configuration_storage::storage();
+            let problems = configuration_validation::check(storage.as_ref());
+
+            if problems.is_empty() {
+                println!("The configuration at {} looks good", storage.location());
+            } else {
+                println!(
+                    "Found {} problem(s) with the configuration at {}:",
+                    problems.len(),
+                    storage.location()
+                );
+
+                for problem in &problems {
+                    println!("  - {problem}");
+                }
+
+                process::exit(problems[0].exit_code());
+            }
+        }
         Some(Command::Get { port, ip_addresses }) => {
             let config = get_app_configuration()?;
 
@@ -108,50 +300,217 @@ fn main() -> Result<()> {
                 println!("Listening IP Addresses: {:?}", config.addresses);
             }
         }
+        Some(Command::Send {
+            host,
+            inventory,
+            group,
+            port,
+            secret,
+            action,
+            force,
+            timeout,
+            parallel,
+            retries,
+        }) => {
+            if let Some(inventory) = inventory {
+                let hosts = Inventory::load(&inventory)?.hosts_in(group.as_deref());
+
+                if hosts.is_empty() {
+                    eprintln!("There are no matching hosts in {}", inventory.display());
+                    process::exit(exitcode::DATAERR);
+                }
+
+                let options = FleetOptions {
+                    port,
+                    secret,
+                    action,
+                    force,
+                    timeout: Duration::from_secs(timeout),
+                    parallel,
+                    retries,
+                };
+
+                let results = fleet::send_all(hosts, &options);
+                fleet::print_summary(&results);
+
+                // Report the first failure, so scripts can tell that something went wrong
+                if let Some(error) = results
+                    .iter()
+                    .find_map(|result| result.outcome.as_ref().err())
+                {
+                    process::exit(error.exit_code());
+                }
+            } else {
+                // structopt makes sure both are present when there's no inventory
+                let request = SendRequest {
+                    host: host.unwrap_or_default(),
+                    port,
+                    secret: secret.unwrap_or_default(),
+                    action,
+                    force,
+                    timeout: Duration::from_secs(timeout),
+                };
+
+                match client::send(&request) {
+                    Ok(accepted) => println!("{}: {}", request.host, accepted),
+                    Err(error) => {
+                        eprintln!("{error}");
+                        process::exit(error.exit_code());
+                    }
+                }
+            }
+        }
+        Some(Command::Ping {
+            host,
+            port,
+            timeout,
+            watch,
+        }) => {
+            let timeout = Duration::from_secs(timeout);
+
+            let error = match watch {
+                Some(interval) => client::watch(
+                    &host,
+                    port,
+                    Duration::from_secs(interval.max(1)),
+                    timeout,
+                    |presence| println!("{}", describe_presence(&host, presence)),
+                ),
+                None => match client::ping(&host, port, timeout) {
+                    Ok(presence) => {
+                        println!("{}", describe_presence(&host, &presence));
+                        return Ok(());
+                    }
+                    Err(error) => error,
+                },
+            };
+
+            eprintln!("{error}");
+            process::exit(error.exit_code());
+        }
+        Some(Command::Info {
+            host,
+            port,
+            secret,
+            timeout,
+        }) => match client::info(&host, port, secret, Duration::from_secs(timeout)) {
+            Ok(info) => println!("{}", serde_json::to_string_pretty(&info)?),
+            Err(error) => {
+                eprintln!("{error}");
+                process::exit(error.exit_code());
+            }
+        },
+        Some(Command::Status { json }) => {
+            let configuration = configuration_storage::storage().fetch().unwrap_or_default();
+
+            let report = match control::request_status(&configuration.control) {
+                Ok(report) => report,
+                Err(error) => {
+                    eprintln!(
+                        "Unable to reach the service at {}: {}",
+                        configuration.control.address, error
+                    );
+                    process::exit(exitcode::UNAVAILABLE);
+                }
+            };
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                report.print();
+            }
+        }
         Some(Command::Run {}) => {
             println!("Running in standalone mode");
             run_standalone()?
         }
+        Some(Command::Install { root }) => {
+            let executable =
+                std::env::current_exe().context("Unable to find this executable's path")?;
+            ServiceInstaller::new(root, executable).install()?
+        }
+        Some(Command::Uninstall { root }) => {
+            let executable =
+                std::env::current_exe().context("Unable to find this executable's path")?;
+            ServiceInstaller::new(root, executable).uninstall()?
+        }
+        Some(Command::SystemdUnit { socket }) => {
+            if socket {
+                let port = configuration_storage::storage()
+                    .fetch()
+                    .unwrap_or_default()
+                    .port_number;
+                print!("{}", systemd::socket_unit(port));
+            } else {
+                let executable =
+                    std::env::current_exe().context("Unable to find this executable's path")?;
+                print!("{}", systemd::service_unit(&executable));
+            }
+        }
     }
 
     Ok(())
 }
 
-fn validate_app_configuration() -> Result<()> {
-    AppConfiguration::validate().context("Unable to validate the configuration file")
+/// The configuration for the listener to run with, with the secret resolved
+fn validate_app_configuration() -> Result<AppConfiguration> {
+    let configuration = configuration_validation::load(configuration_storage::storage().as_ref())
+        .context("Unable to validate the configuration file")?;
+
+    configuration_validation::check_default_secret(&configuration)
+        .context(configuration_validation::REFUSING_DEFAULT_SECRET)?;
+
+    Ok(configuration)
 }
 
 fn get_app_configuration() -> Result<AppConfiguration> {
-    AppConfiguration::fetch().context("Unable to read the configuration file")
+    configuration_storage::storage()
+        .fetch()
+        .context("Unable to read the configuration file")
 }
 
-fn init_logging() {
-    if cfg!(debug_assertions) {
-        CombinedLogger::init(vec![
-            TermLogger::new(
-                LevelFilter::Debug,
-                Config::default(),
-                TerminalMode::Mixed,
-                ColorChoice::Auto,
-            ),
-            WriteLogger::new(
-                LevelFilter::Debug,
-                Config::default(),
-                File::create("shutdown-on-lan.log").unwrap(),
-            ),
-        ])
-        .unwrap();
-    } else {
-        CombinedLogger::init(vec![TermLogger::new(
-            LevelFilter::Info,
-            Config::default(),
-            TerminalMode::Mixed,
-            ColorChoice::Auto,
-        )])
-        .unwrap();
+fn describe_presence(host: &str, presence: &Presence) -> String {
+    let mut description = format!("{host} is up");
+
+    if let Some(hostname) = &presence.hostname {
+        description += &format!(" as {hostname}");
+    }
+
+    if let Some(uptime) = presence.uptime {
+        description += &format!(", booted {} seconds ago", uptime.as_secs());
+    }
+
+    description
+}
+
+fn init_logging(args: &LoggingArguments) {
+    // The logger isn't set up yet, so anything logged while reading the configuration is dropped
+    let mut configuration = configuration_storage::storage()
+        .fetch()
+        .map(|configuration| configuration.logging)
+        .unwrap_or_default();
+
+    if let Some(level) = logging::level_from_environment() {
+        configuration.level = level;
+    }
+
+    if let Some(level) = args.level {
+        configuration.level = level;
+    }
+
+    if let Some(file) = &args.file {
+        configuration.file = Some(file.clone());
+    }
+
+    if let Some(format) = args.format {
+        configuration.format = format;
+    }
+
+    if let Some(system) = &args.system {
+        configuration.system = Some(system.clone());
     }
 
-    log::debug!("File Logger Initialized");
+    logging::init(&configuration);
 }
 
 #[cfg(windows)]
@@ -165,8 +524,7 @@ fn run() -> Result<()> {
 }
 
 fn run_standalone() -> Result<()> {
-    validate_app_configuration()?;
-    let config = get_app_configuration()?;
+    let config = validate_app_configuration()?;
     listener_service::run(&config);
 
     Ok(())
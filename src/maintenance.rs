@@ -0,0 +1,378 @@
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::power::PowerAction;
+
+/// Restricts when actions may be performed, such as never during exam hours. Windows are
+/// checked in the configured time zone (or the machine's own, if there isn't one).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct MaintenanceConfiguration {
+    /// An IANA time zone name, such as `Europe/London`
+    pub timezone: Option<Tz>,
+
+    pub windows: Vec<MaintenanceWindow>,
+}
+
+/// A weekly recurring range of time in which actions are either allowed or denied
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MaintenanceWindow {
+    /// Shown to clients when the window refuses an action
+    #[serde(default)]
+    pub name: Option<String>,
+
+    pub policy: WindowPolicy,
+
+    /// The days the window starts on. Every day, if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+
+    pub start: TimeOfDay,
+
+    /// If this is before `start`, the window runs past midnight into the next day. If it's the
+    /// same as `start`, the window lasts a whole day.
+    pub end: TimeOfDay,
+
+    /// The actions the window applies to. Every action, if empty
+    #[serde(default)]
+    pub actions: Vec<PowerAction>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WindowPolicy {
+    /// Actions are only allowed inside `allow` windows (if there are any for the action)
+    Allow,
+    /// Actions are never allowed inside `deny` windows
+    Deny,
+}
+
+/// A time of day written as `HH:MM`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(NaiveTime);
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(string: String) -> Result<Self, Self::Error> {
+        NaiveTime::parse_from_str(&string, "%H:%M")
+            .map(TimeOfDay)
+            .map_err(|_| format!("Invalid time {string:?} – expected HH:MM"))
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        time.to_string()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.format("%H:%M"))
+    }
+}
+
+impl MaintenanceWindow {
+    fn applies_to(&self, action: PowerAction) -> bool {
+        self.actions.is_empty() || self.actions.contains(&action)
+    }
+
+    fn runs_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    /// Whether the window is open at `time` on `day`
+    fn contains(&self, day: Weekday, time: NaiveTime) -> bool {
+        let (start, end) = (self.start.0, self.end.0);
+
+        if start < end {
+            self.runs_on(day) && start <= time && time < end
+        } else {
+            // Past midnight (or for a whole day, when `start` and `end` are the same), the window
+            // belongs to the day it started on
+            (self.runs_on(day) && time >= start) || (self.runs_on(day.pred()) && time < end)
+        }
+    }
+}
+
+impl fmt::Display for MaintenanceWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "{name} ")?;
+        }
+
+        write!(f, "({}–{}", self.start, self.end)?;
+
+        if !self.days.is_empty() {
+            let days: Vec<String> = self.days.iter().map(|day| day.to_string()).collect();
+            write!(f, " on {}", days.join(", "))?;
+        }
+
+        write!(f, ")")
+    }
+}
+
+impl MaintenanceConfiguration {
+    /// Checks whether `action` is allowed at `now`, returning why not if it isn't. `now` is
+    /// passed in, rather than read from the clock, so the windows can be checked at any time.
+    pub fn check(&self, action: PowerAction, now: DateTime<Utc>) -> Result<(), String> {
+        let (day, time) = match &self.timezone {
+            Some(timezone) => local_day_and_time(timezone, now),
+            None => local_day_and_time(&chrono::Local, now),
+        };
+
+        let windows: Vec<&MaintenanceWindow> = self
+            .windows
+            .iter()
+            .filter(|window| window.applies_to(action))
+            .collect();
+
+        if let Some(window) = windows
+            .iter()
+            .find(|window| window.policy == WindowPolicy::Deny && window.contains(day, time))
+        {
+            return Err(format!("{action} isn't allowed during {window}"));
+        }
+
+        let allowed: Vec<&&MaintenanceWindow> = windows
+            .iter()
+            .filter(|window| window.policy == WindowPolicy::Allow)
+            .collect();
+
+        if !allowed.is_empty() && !allowed.iter().any(|window| window.contains(day, time)) {
+            let descriptions: Vec<String> =
+                allowed.iter().map(|window| window.to_string()).collect();
+
+            return Err(format!(
+                "{action} is only allowed during {}",
+                descriptions.join(", ")
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn local_day_and_time<Z: TimeZone>(timezone: &Z, now: DateTime<Utc>) -> (Weekday, NaiveTime) {
+    let local = now.with_timezone(timezone);
+    (local.weekday(), local.time())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(string: &str) -> TimeOfDay {
+        TimeOfDay::try_from(string.to_string()).unwrap()
+    }
+
+    fn window(policy: WindowPolicy, days: &[Weekday], start: &str, end: &str) -> MaintenanceWindow {
+        MaintenanceWindow {
+            name: None,
+            policy,
+            days: days.to_vec(),
+            start: time(start),
+            end: time(end),
+            actions: Vec::new(),
+        }
+    }
+
+    fn configuration(timezone: &str, windows: Vec<MaintenanceWindow>) -> MaintenanceConfiguration {
+        MaintenanceConfiguration {
+            timezone: Some(timezone.parse().unwrap()),
+            windows,
+        }
+    }
+
+    /// An instant, written as a UTC time
+    fn at(string: &str) -> DateTime<Utc> {
+        string.parse().unwrap()
+    }
+
+    fn allowed(configuration: &MaintenanceConfiguration, now: &str) -> bool {
+        configuration.check(PowerAction::Shutdown, at(now)).is_ok()
+    }
+
+    #[test]
+    fn no_windows_allow_everything() {
+        let configuration = configuration("UTC", Vec::new());
+
+        assert!(allowed(&configuration, "2024-01-01T12:00:00Z"));
+    }
+
+    #[test]
+    fn deny_windows_refuse_actions_inside_them() {
+        let configuration = configuration(
+            "UTC",
+            vec![window(
+                WindowPolicy::Deny,
+                &[Weekday::Mon],
+                "09:00",
+                "12:30",
+            )],
+        );
+
+        // 2024-01-01 was a Monday
+        assert!(allowed(&configuration, "2024-01-01T08:59:00Z"));
+        assert!(!allowed(&configuration, "2024-01-01T09:00:00Z"));
+        assert!(!allowed(&configuration, "2024-01-01T12:29:00Z"));
+        assert!(allowed(&configuration, "2024-01-01T12:30:00Z"));
+        assert!(allowed(&configuration, "2024-01-02T10:00:00Z"));
+    }
+
+    #[test]
+    fn allow_windows_refuse_actions_outside_them() {
+        let configuration = configuration(
+            "UTC",
+            vec![window(WindowPolicy::Allow, &[], "18:00", "19:00")],
+        );
+
+        assert!(allowed(&configuration, "2024-01-01T18:30:00Z"));
+        assert!(!allowed(&configuration, "2024-01-01T17:59:00Z"));
+    }
+
+    #[test]
+    fn deny_windows_win_over_allow_windows() {
+        let configuration = configuration(
+            "UTC",
+            vec![
+                window(WindowPolicy::Allow, &[], "00:00", "23:59"),
+                window(WindowPolicy::Deny, &[], "09:00", "10:00"),
+            ],
+        );
+
+        assert!(!allowed(&configuration, "2024-01-01T09:30:00Z"));
+        assert!(allowed(&configuration, "2024-01-01T10:30:00Z"));
+    }
+
+    #[test]
+    fn windows_only_apply_to_their_actions() {
+        let mut sleep_only = window(WindowPolicy::Deny, &[], "00:00", "00:00");
+        sleep_only.actions = vec![PowerAction::Sleep];
+        let configuration = configuration("UTC", vec![sleep_only]);
+
+        assert!(configuration
+            .check(PowerAction::Shutdown, at("2024-01-01T12:00:00Z"))
+            .is_ok());
+        assert!(configuration
+            .check(PowerAction::Sleep, at("2024-01-01T12:00:00Z"))
+            .is_err());
+    }
+
+    #[test]
+    fn windows_across_midnight_belong_to_the_day_they_start_on() {
+        let configuration = configuration(
+            "UTC",
+            vec![window(
+                WindowPolicy::Deny,
+                &[Weekday::Fri],
+                "22:00",
+                "06:00",
+            )],
+        );
+
+        // Friday 2024-01-05 evening, and the early hours of Saturday
+        assert!(!allowed(&configuration, "2024-01-05T23:00:00Z"));
+        assert!(!allowed(&configuration, "2024-01-06T05:59:00Z"));
+        assert!(allowed(&configuration, "2024-01-06T06:00:00Z"));
+
+        // The early hours of Friday belong to Thursday's window, which there isn't one of
+        assert!(allowed(&configuration, "2024-01-05T05:00:00Z"));
+        assert!(allowed(&configuration, "2024-01-06T23:00:00Z"));
+    }
+
+    #[test]
+    fn windows_starting_and_ending_at_the_same_time_last_a_whole_day() {
+        let configuration = configuration(
+            "UTC",
+            vec![window(
+                WindowPolicy::Allow,
+                &[Weekday::Sat],
+                "00:00",
+                "00:00",
+            )],
+        );
+
+        assert!(allowed(&configuration, "2024-01-06T00:00:00Z"));
+        assert!(allowed(&configuration, "2024-01-06T12:00:00Z"));
+        assert!(allowed(&configuration, "2024-01-06T23:59:00Z"));
+        assert!(!allowed(&configuration, "2024-01-07T00:00:00Z"));
+        assert!(!allowed(&configuration, "2024-01-05T23:59:00Z"));
+    }
+
+    #[test]
+    fn windows_are_checked_in_the_configured_time_zone() {
+        let windows = vec![window(
+            WindowPolicy::Deny,
+            &[Weekday::Mon],
+            "09:00",
+            "10:00",
+        )];
+        let tokyo = configuration("Asia/Tokyo", windows.clone());
+        let new_york = configuration("America/New_York", windows);
+
+        // 09:30 on Monday in Tokyo is 00:30 UTC, and 19:30 on Sunday in New York
+        assert!(!allowed(&tokyo, "2024-01-01T00:30:00Z"));
+        assert!(allowed(&new_york, "2024-01-01T00:30:00Z"));
+
+        // 09:30 on Monday in New York (EST, UTC-5)
+        assert!(!allowed(&new_york, "2024-01-01T14:30:00Z"));
+    }
+
+    #[test]
+    fn windows_follow_daylight_saving_time() {
+        let configuration = configuration(
+            "Europe/London",
+            vec![window(WindowPolicy::Deny, &[], "09:00", "10:00")],
+        );
+
+        // GMT in winter, BST (UTC+1) in summer
+        assert!(!allowed(&configuration, "2024-01-15T09:30:00Z"));
+        assert!(allowed(&configuration, "2024-07-15T09:30:00Z"));
+        assert!(!allowed(&configuration, "2024-07-15T08:30:00Z"));
+    }
+
+    #[test]
+    fn windows_spanning_a_daylight_saving_change_cover_the_local_times() {
+        let configuration = configuration(
+            "America/New_York",
+            vec![window(
+                WindowPolicy::Deny,
+                &[Weekday::Sun],
+                "01:00",
+                "04:00",
+            )],
+        );
+
+        // On 2024-03-10 clocks went from 02:00 EST straight to 03:00 EDT, so the window is only
+        // two hours long: 06:00–08:00 UTC
+        assert!(allowed(&configuration, "2024-03-10T05:59:00Z"));
+        assert!(!allowed(&configuration, "2024-03-10T06:30:00Z"));
+        assert!(!allowed(&configuration, "2024-03-10T07:30:00Z"));
+        assert!(allowed(&configuration, "2024-03-10T08:00:00Z"));
+
+        // On 2024-11-03 01:00–02:00 happened twice, so it's four hours long: 05:00–09:00 UTC
+        assert!(!allowed(&configuration, "2024-11-03T05:30:00Z"));
+        assert!(!allowed(&configuration, "2024-11-03T06:30:00Z"));
+        assert!(!allowed(&configuration, "2024-11-03T08:30:00Z"));
+        assert!(allowed(&configuration, "2024-11-03T09:00:00Z"));
+    }
+
+    #[test]
+    fn refusals_name_the_window() {
+        let mut exams = window(WindowPolicy::Deny, &[], "09:00", "10:00");
+        exams.name = Some("Exams".to_string());
+        let configuration = configuration("UTC", vec![exams]);
+
+        let reason = configuration
+            .check(PowerAction::Reboot, at("2024-01-01T09:00:00Z"))
+            .unwrap_err();
+
+        assert_eq!(reason, "reboot isn't allowed during Exams (09:00–10:00)");
+    }
+}
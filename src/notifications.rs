@@ -0,0 +1,380 @@
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use std::sync::mpsc::Sender;
+
+/// How people using the machine are warned before a remote action
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct NotificationConfiguration {
+    /// Wait this many seconds before performing a remote action, warning anyone logged in and
+    /// replying `PENDING` straight away. `0` performs actions as soon as they're requested.
+    pub countdown_seconds: u64,
+
+    /// Show a notification on every logged-in user's desktop (Linux only)
+    pub desktop: bool,
+
+    /// Broadcast the warning to every terminal – with `wall` on Linux and macOS, and `msg` on
+    /// Windows
+    pub broadcast: bool,
+
+    /// Offer a button on desktop notifications that postpones the action
+    pub allow_postpone: bool,
+
+    /// How long each press of the button postpones the action by
+    pub postpone_minutes: u64,
+
+    /// How many times an action can be postponed
+    pub max_postpones: u32,
+}
+
+impl Default for NotificationConfiguration {
+    fn default() -> Self {
+        NotificationConfiguration {
+            countdown_seconds: 0,
+            desktop: true,
+            broadcast: true,
+            allow_postpone: false,
+            postpone_minutes: 10,
+            max_postpones: 1,
+        }
+    }
+}
+
+impl NotificationConfiguration {
+    /// Whether an action that's been postponed `postpones` times can be postponed again
+    pub fn can_postpone(&self, postpones: u32) -> bool {
+        self.allow_postpone && postpones < self.max_postpones
+    }
+}
+
+/// Shows `message` to everyone logged in to this machine – with `wall` on Linux and macOS, and
+/// `msg` on Windows. Failures are logged rather than returned, since a warning that can't be
+/// shown shouldn't stop anything else from happening.
+pub fn broadcast(message: &str) {
+    match broadcast_command(message).status() {
+        Ok(status) if status.success() => log::debug!("Broadcast {:?}", message),
+        Ok(status) => log::warn!("Unable to broadcast a message: exited with {}", status),
+        Err(error) => log::warn!("Unable to broadcast a message: {}", error),
+    }
+}
+
+#[cfg(windows)]
+fn broadcast_command(message: &str) -> Command {
+    let mut command = Command::new("msg");
+    command.arg("*").arg(message);
+    command
+}
+
+#[cfg(not(windows))]
+fn broadcast_command(message: &str) -> Command {
+    let mut command = Command::new("wall");
+    command.arg(message);
+    command
+}
+
+/// Warns the people using this machine about an upcoming action, through every configured
+/// channel. Desktop notifications are updated in place each time, rather than piling up.
+pub struct Notifier {
+    broadcast: bool,
+
+    #[cfg(target_os = "linux")]
+    sessions: Vec<desktop::Session>,
+}
+
+impl Notifier {
+    pub fn new(configuration: &NotificationConfiguration) -> Notifier {
+        Notifier {
+            broadcast: configuration.broadcast,
+
+            #[cfg(target_os = "linux")]
+            sessions: if configuration.desktop {
+                desktop::sessions()
+            } else {
+                Vec::new()
+            },
+        }
+    }
+
+    /// Shows (or updates) the warning. With `postpone`, desktop notifications get a button with
+    /// that label, and each press is sent to the channel.
+    pub fn warn(&mut self, summary: &str, body: &str, postpone: Option<(&str, &Sender<()>)>) {
+        if self.broadcast {
+            broadcast(&format!("{summary}. {body}"));
+        }
+
+        #[cfg(target_os = "linux")]
+        for session in &mut self.sessions {
+            if let Err(error) = session.show(summary, body, postpone) {
+                log::warn!("Unable to show a desktop notification: {}", error);
+            }
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        let _ = postpone;
+    }
+
+    /// Takes down any desktop notifications
+    pub fn close(&self) {
+        #[cfg(target_os = "linux")]
+        for session in &self.sessions {
+            session.close();
+        }
+    }
+}
+
+/// Desktop notifications through the `org.freedesktop.Notifications` service on each logged-in
+/// user's session bus
+#[cfg(target_os = "linux")]
+mod desktop {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+    use std::sync::mpsc::Sender;
+    use std::sync::Arc;
+    use std::thread;
+    use zbus::blocking::{Connection, ConnectionBuilder, MessageIterator};
+    use zbus::zvariant::Value;
+    use zbus::{MatchRule, MessageType};
+
+    const DESTINATION: &str = "org.freedesktop.Notifications";
+    const PATH: &str = "/org/freedesktop/Notifications";
+    const INTERFACE: &str = "org.freedesktop.Notifications";
+
+    const APP_NAME: &str = "shutdown-on-lan";
+    const ICON: &str = "system-shutdown";
+    const POSTPONE_ACTION: &str = "postpone";
+
+    /// Where each user's session bus lives under systemd
+    const RUNTIME_DIRECTORY: &str = "/run/user";
+
+    /// The notification shown on one user's desktop
+    pub struct Session {
+        connection: Connection,
+        /// The notification's ID, once shown, so it can be replaced and closed. `0` until then.
+        id: Arc<AtomicU32>,
+        /// Whether presses of the postpone button are being listened for
+        listening: Arc<AtomicBool>,
+    }
+
+    /// Connects to the session bus of everyone logged in. Root is allowed to connect to them.
+    pub fn sessions() -> Vec<Session> {
+        let entries = match fs::read_dir(RUNTIME_DIRECTORY) {
+            Ok(entries) => entries,
+            Err(error) => {
+                log::debug!("Unable to look for session buses: {}", error);
+                return Vec::new();
+            }
+        };
+
+        entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path().join("bus"))
+            .filter(|path| path.exists())
+            .filter_map(|path| {
+                let address = format!("unix:path={}", path.display());
+
+                match ConnectionBuilder::address(address.as_str())
+                    .and_then(|builder| builder.build())
+                {
+                    Ok(connection) => Some(Session {
+                        connection,
+                        id: Arc::new(AtomicU32::new(0)),
+                        listening: Arc::new(AtomicBool::new(false)),
+                    }),
+                    Err(error) => {
+                        log::debug!("Unable to connect to {}: {}", path.display(), error);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    impl Session {
+        pub fn show(
+            &mut self,
+            summary: &str,
+            body: &str,
+            postpone: Option<(&str, &Sender<()>)>,
+        ) -> zbus::Result<()> {
+            let mut actions = Vec::new();
+
+            if let Some((label, sender)) = postpone {
+                if !self.listening.swap(true, Ordering::Relaxed) {
+                    if let Err(error) = self.listen(sender.clone()) {
+                        self.listening.store(false, Ordering::Relaxed);
+                        return Err(error);
+                    }
+                }
+
+                actions.push(POSTPONE_ACTION);
+                actions.push(label);
+            }
+
+            // Critical, so that it stays up until the action happens or the user dismisses it
+            let mut hints: HashMap<&str, Value<'_>> = HashMap::new();
+            hints.insert("urgency", Value::U8(2));
+
+            let reply = self.connection.call_method(
+                Some(DESTINATION),
+                PATH,
+                Some(INTERFACE),
+                "Notify",
+                &(
+                    APP_NAME,
+                    self.id.load(Ordering::Relaxed),
+                    ICON,
+                    summary,
+                    body,
+                    actions,
+                    hints,
+                    0i32,
+                ),
+            )?;
+
+            self.id.store(reply.body()?, Ordering::Relaxed);
+
+            Ok(())
+        }
+
+        pub fn close(&self) {
+            let id = self.id.load(Ordering::Relaxed);
+
+            if id == 0 {
+                return;
+            }
+
+            if let Err(error) = self.connection.call_method(
+                Some(DESTINATION),
+                PATH,
+                Some(INTERFACE),
+                "CloseNotification",
+                &(id),
+            ) {
+                log::debug!("Unable to close notification {}: {}", id, error);
+            }
+        }
+
+        /// Reports presses of the postpone button to `sender`, until the notification closes (it's
+        /// shown again with the button if there's another warning)
+        fn listen(&self, sender: Sender<()>) -> zbus::Result<()> {
+            let rule = MatchRule::builder()
+                .msg_type(MessageType::Signal)
+                .path(PATH)?
+                .interface(INTERFACE)?
+                .build();
+
+            let messages = MessageIterator::for_match_rule(rule, &self.connection, None)?;
+            let id = self.id.clone();
+            let listening = self.listening.clone();
+
+            thread::spawn(move || {
+                for message in messages.filter_map(Result::ok) {
+                    let ours = |notification: u32| notification == id.load(Ordering::Relaxed);
+
+                    // Stop once the notification's gone, or the countdown's over
+                    let finished = match message.member().as_deref() {
+                        Some("ActionInvoked") => match message.body::<(u32, String)>() {
+                            Ok((notification, action))
+                                if ours(notification) && action == POSTPONE_ACTION =>
+                            {
+                                sender.send(()).is_err()
+                            }
+                            _ => false,
+                        },
+                        Some("NotificationClosed") => matches!(
+                            message.body::<(u32, u32)>(),
+                            Ok((notification, _)) if ours(notification)
+                        ),
+                        _ => false,
+                    };
+
+                    if finished {
+                        break;
+                    }
+                }
+
+                listening.store(false, Ordering::Relaxed);
+            });
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_settings_take_their_defaults() {
+        let configuration: NotificationConfiguration =
+            toml::from_str("countdown_seconds = 300").unwrap();
+
+        assert_eq!(
+            configuration,
+            NotificationConfiguration {
+                countdown_seconds: 300,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn actions_can_be_postponed_up_to_the_limit() {
+        let configuration = NotificationConfiguration {
+            allow_postpone: true,
+            max_postpones: 2,
+            ..Default::default()
+        };
+
+        assert!(configuration.can_postpone(0));
+        assert!(configuration.can_postpone(1));
+        assert!(!configuration.can_postpone(2));
+    }
+
+    #[test]
+    fn actions_cant_be_postponed_unless_allowed() {
+        let configuration = NotificationConfiguration {
+            allow_postpone: false,
+            max_postpones: 2,
+            ..Default::default()
+        };
+
+        assert!(!configuration.can_postpone(0));
+    }
+
+    #[test]
+    fn broadcasts_pass_the_message_as_a_single_argument() {
+        let message = "This computer will shut down in 5 minutes. Please save your work.";
+        let command = broadcast_command(message);
+        let arguments: Vec<_> = command.get_args().collect();
+
+        #[cfg(windows)]
+        assert_eq!(
+            (command.get_program(), arguments),
+            ("msg".as_ref(), vec!["*".as_ref(), message.as_ref()])
+        );
+
+        #[cfg(not(windows))]
+        assert_eq!(
+            (command.get_program(), arguments),
+            ("wall".as_ref(), vec![message.as_ref()])
+        );
+    }
+
+    #[test]
+    fn nothing_is_shown_when_every_channel_is_off() {
+        let mut notifier = Notifier::new(&NotificationConfiguration {
+            desktop: false,
+            broadcast: false,
+            ..Default::default()
+        });
+
+        notifier.warn("summary", "body", None);
+        notifier.close();
+
+        #[cfg(target_os = "linux")]
+        assert!(notifier.sessions.is_empty());
+    }
+}
@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[cfg(target_os = "linux")]
+use crate::logind::{Logind, LogindError};
+
+/// Something a client can ask this machine to do
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerAction {
+    Shutdown,
+    Reboot,
+    Sleep,
+    Hibernate,
+}
+
+impl PowerAction {
+    pub const ALL: [PowerAction; 4] = [
+        PowerAction::Shutdown,
+        PowerAction::Reboot,
+        PowerAction::Sleep,
+        PowerAction::Hibernate,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PowerAction::Shutdown => "shutdown",
+            PowerAction::Reboot => "reboot",
+            PowerAction::Sleep => "sleep",
+            PowerAction::Hibernate => "hibernate",
+        }
+    }
+
+    pub fn perform(&self, configuration: &PowerConfiguration) -> Result<(), PowerError> {
+        #[cfg(target_os = "linux")]
+        if configuration.logind.enabled {
+            let result = Logind::connect(configuration.logind.bus_address.as_deref())
+                .and_then(|logind| logind.perform(*self, &configuration.logind));
+
+            match result {
+                Ok(()) => return Ok(()),
+                Err(LogindError::Inhibited(reason)) => return Err(PowerError::Inhibited(reason)),
+                Err(LogindError::Failed(error)) => {
+                    return Err(PowerError::Failed(io::Error::other(error)))
+                }
+                Err(error @ LogindError::Unavailable(_)) => {
+                    log::warn!(
+                        "{}, falling back to the default power backend – which ignores inhibitor locks",
+                        error
+                    )
+                }
+            }
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        let _ = configuration;
+
+        match self {
+            PowerAction::Shutdown => system_shutdown::shutdown(),
+            PowerAction::Reboot => system_shutdown::reboot(),
+            PowerAction::Sleep => system_shutdown::sleep(),
+            PowerAction::Hibernate => system_shutdown::hibernate(),
+        }
+        .map_err(PowerError::Failed)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum PowerError {
+    /// Something on this machine asked for it not to happen right now (only logind reports this)
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    #[error("{0}")]
+    Inhibited(String),
+
+    #[error("{0}")]
+    Failed(io::Error),
+}
+
+/// How power actions are carried out
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct PowerConfiguration {
+    pub logind: LogindConfiguration,
+}
+
+/// Settings for performing power actions through systemd-logind. Only used on Linux.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct LogindConfiguration {
+    /// Use logind when it's available, so its inhibitor locks are respected
+    pub enabled: bool,
+
+    /// The D-Bus address to find logind at, instead of the system bus
+    pub bus_address: Option<String>,
+
+    /// How long to wait for blocking inhibitors to be released before refusing
+    pub wait_for_inhibitors_seconds: u64,
+}
+
+impl Default for LogindConfiguration {
+    fn default() -> Self {
+        LogindConfiguration {
+            enabled: true,
+            bus_address: None,
+            wait_for_inhibitors_seconds: 0,
+        }
+    }
+}
+
+impl fmt::Display for PowerAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for PowerAction {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        PowerAction::ALL
+            .iter()
+            .find(|action| action.as_str().eq_ignore_ascii_case(string))
+            .copied()
+            .ok_or_else(|| {
+                format!("Unknown action {string:?} – expected shutdown, reboot, sleep or hibernate")
+            })
+    }
+}
@@ -0,0 +1,548 @@
+//! The line-based protocol spoken between `shutdown-on-lan send` (or `nc`) and the listener.
+//!
+//! A client sends a single line and the listener replies with a single line:
+//!
+//! | Request               | Meaning                               |
+//! |-----------------------|---------------------------------------|
+//! | `<secret>`            | Shut down (the original protocol)     |
+//! | `SHUTDOWN <secret>`   | Shut down                             |
+//! | `REBOOT <secret>`     | Reboot                                |
+//! | `SLEEP <secret>`      | Sleep                                 |
+//! | `HIBERNATE <secret>`  | Hibernate                             |
+//! | `FORCE <action> <secret>` | Any of the above, skipping the inhibitor checks (if allowed) |
+//! | `PING`                | Is the listener up? (no secret needed)|
+//! | `PING KEEPALIVE [n]`  | Send a `PONG` every `n` seconds       |
+//! | `INFO [secret]`       | Describe the machine's current state  |
+//!
+//! | Response              | Meaning                               |
+//! |-----------------------|---------------------------------------|
+//! | `OK <action>`         | The action is underway                |
+//! | `PENDING <action> <n>`| The action will happen in `n` seconds, once the users have been warned |
+//! | `ERR <kind> <reason>` | Nothing will happen, and why          |
+//! | `PONG [key=value …]`  | The listener is up, with its hostname and uptime (in seconds) if known |
+//! | `INFO <json>`         | A `SystemInfo` object                 |
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::power::PowerAction;
+use crate::system_info::SystemInfo;
+
+/// How the listener treats each connection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ConnectionConfiguration {
+    /// How long to wait for a client to send its request before hanging up
+    pub read_timeout_seconds: u64,
+
+    /// How often to send a heartbeat for `PING KEEPALIVE` when the client doesn't ask for an interval
+    pub heartbeat_interval_seconds: u64,
+
+    /// How many `PING KEEPALIVE` connections can be open at once. They don't need the secret, so
+    /// anyone on the network could otherwise open as many as they liked.
+    pub max_keepalive_connections: usize,
+
+    /// How long a `PING KEEPALIVE` connection is kept open for before the client has to reconnect
+    pub max_keepalive_seconds: u64,
+}
+
+impl Default for ConnectionConfiguration {
+    fn default() -> Self {
+        ConnectionConfiguration {
+            read_timeout_seconds: 30,
+            heartbeat_interval_seconds: 10,
+            max_keepalive_connections: 16,
+            max_keepalive_seconds: 60 * 60,
+        }
+    }
+}
+
+/// The most often the listener sends heartbeats, whatever interval the client asks for
+pub const MIN_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+const PING_COMMAND: &str = "PING";
+const KEEPALIVE_KEYWORD: &str = "KEEPALIVE";
+const INFO_COMMAND: &str = "INFO";
+const FORCE_KEYWORD: &str = "FORCE";
+const PENDING_STATUS: &str = "PENDING";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Action {
+        action: PowerAction,
+        secret: String,
+        /// Skip the inhibitor checks, if the configuration allows it
+        force: bool,
+    },
+    /// A health check. With `keepalive`, the listener keeps the connection open and sends a
+    /// heartbeat on that interval (or its default interval, if `Some(None)`).
+    Ping { keepalive: Option<Option<Duration>> },
+    /// A request for `SystemInfo`, which may need the secret depending on the configuration
+    Info { secret: Option<String> },
+}
+
+impl Request {
+    /// Parses a line sent by a client. Anything that doesn't start with an action keyword is
+    /// treated as a bare secret asking for a shutdown, so existing clients keep working – see
+    /// `parse_with_secret` for secrets that do.
+    pub fn parse(line: &str) -> Request {
+        let line = line.trim();
+
+        let mut words = line.split_whitespace();
+
+        if words.next() == Some(PING_COMMAND) {
+            match words.next() {
+                None => return Request::Ping { keepalive: None },
+                Some(KEEPALIVE_KEYWORD) => {
+                    let interval = words.next().and_then(|seconds| seconds.parse().ok());
+
+                    return Request::Ping {
+                        keepalive: Some(interval.map(Duration::from_secs)),
+                    };
+                }
+                Some(_) => {}
+            }
+        }
+
+        if line == INFO_COMMAND {
+            return Request::Info { secret: None };
+        }
+
+        if let Some(secret) = line
+            .strip_prefix(INFO_COMMAND)
+            .and_then(|rest| rest.strip_prefix(' '))
+        {
+            return Request::Info {
+                secret: Some(secret.to_string()),
+            };
+        }
+
+        if let Some((action, secret)) = line
+            .strip_prefix(FORCE_KEYWORD)
+            .and_then(|rest| rest.strip_prefix(' '))
+            .and_then(split_action)
+        {
+            return Request::Action {
+                action,
+                secret: secret.to_string(),
+                force: true,
+            };
+        }
+
+        if let Some((action, secret)) = split_action(line) {
+            return Request::Action {
+                action,
+                secret: secret.to_string(),
+                force: false,
+            };
+        }
+
+        Request::Action {
+            action: PowerAction::Shutdown,
+            secret: line.to_string(),
+            force: false,
+        }
+    }
+
+    /// Parses a line sent by a client to a listener whose secret is `secret`. Before actions were
+    /// added, any line was a bare secret, so a secret that happens to start with a keyword (like
+    /// `Reboot the lab`) must still work when it's sent on its own: if the line as a whole is the
+    /// secret, and parsing it gives a request with some other secret, it's a bare secret.
+    pub fn parse_with_secret(line: &str, secret: &str) -> Request {
+        let request = Request::parse(line);
+
+        let parsed_secret = match &request {
+            Request::Action { secret, .. } => Some(secret.as_str()),
+            Request::Info { secret } => secret.as_deref(),
+            Request::Ping { .. } => None,
+        };
+
+        match parsed_secret {
+            Some(parsed_secret) if parsed_secret != secret && line.trim() == secret => {
+                Request::Action {
+                    action: PowerAction::Shutdown,
+                    secret: secret.to_string(),
+                    force: false,
+                }
+            }
+            _ => request,
+        }
+    }
+}
+
+/// Splits `<ACTION> <secret>` into its parts, if it starts with an action keyword
+fn split_action(line: &str) -> Option<(PowerAction, &str)> {
+    let (keyword, secret) = line.split_once(' ')?;
+
+    PowerAction::ALL
+        .iter()
+        .find(|action| action.as_str().eq_ignore_ascii_case(keyword))
+        .map(|action| (*action, secret))
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Request::Action {
+                action,
+                secret,
+                force,
+            } => {
+                if *force {
+                    write!(f, "{FORCE_KEYWORD} ")?;
+                }
+
+                write!(f, "{} {}", action.as_str().to_uppercase(), secret)
+            }
+            Request::Ping { keepalive: None } => f.write_str(PING_COMMAND),
+            Request::Ping {
+                keepalive: Some(None),
+            } => write!(f, "{PING_COMMAND} {KEEPALIVE_KEYWORD}"),
+            Request::Ping {
+                keepalive: Some(Some(interval)),
+            } => write!(
+                f,
+                "{} {} {}",
+                PING_COMMAND,
+                KEEPALIVE_KEYWORD,
+                interval.as_secs()
+            ),
+            Request::Info { secret: None } => f.write_str(INFO_COMMAND),
+            Request::Info {
+                secret: Some(secret),
+            } => write!(f, "{INFO_COMMAND} {secret}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The secret didn't match
+    Unauthorized,
+    /// The request couldn't be understood
+    Invalid,
+    /// The action was attempted, but the operating system refused
+    Failed,
+    /// The action wasn't attempted, because one of the inhibitor checks failed
+    Rejected,
+}
+
+impl ErrorKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Unauthorized => "unauthorized",
+            ErrorKind::Invalid => "invalid",
+            ErrorKind::Failed => "failed",
+            ErrorKind::Rejected => "rejected",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Ok {
+        action: PowerAction,
+    },
+    /// The action was accepted, but the listener is counting down before performing it
+    Pending {
+        action: PowerAction,
+        delay: Duration,
+    },
+    Error {
+        kind: ErrorKind,
+        reason: String,
+    },
+    Pong {
+        hostname: Option<String>,
+        uptime: Option<Duration>,
+    },
+    Info(Box<SystemInfo>),
+}
+
+impl Response {
+    pub fn error(kind: ErrorKind, reason: impl Into<String>) -> Response {
+        Response::Error {
+            kind,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Response::Ok { action } => write!(f, "OK {action}"),
+            Response::Pending { action, delay } => {
+                write!(f, "{PENDING_STATUS} {action} {}", delay.as_secs())
+            }
+            Response::Error { kind, reason } => write!(f, "ERR {} {}", kind.as_str(), reason),
+            Response::Pong { hostname, uptime } => {
+                f.write_str("PONG")?;
+
+                if let Some(hostname) = hostname {
+                    write!(f, " hostname={hostname}")?;
+                }
+
+                if let Some(uptime) = uptime {
+                    write!(f, " uptime={}", uptime.as_secs())?;
+                }
+
+                Ok(())
+            }
+            Response::Info(info) => {
+                let json = serde_json::to_string(info).map_err(|_| fmt::Error)?;
+                write!(f, "{INFO_COMMAND} {json}")
+            }
+        }
+    }
+}
+
+impl FromStr for Response {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let line = line.trim();
+        let malformed = || format!("Malformed response {line:?}");
+
+        let (status, rest) = line.split_once(' ').unwrap_or((line, ""));
+
+        match status {
+            "OK" => Ok(Response::Ok {
+                action: rest.parse().map_err(|_| malformed())?,
+            }),
+            PENDING_STATUS => {
+                let (action, delay) = rest.split_once(' ').ok_or_else(malformed)?;
+
+                Ok(Response::Pending {
+                    action: action.parse().map_err(|_| malformed())?,
+                    delay: Duration::from_secs(delay.parse().map_err(|_| malformed())?),
+                })
+            }
+            "ERR" => {
+                let (kind, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+
+                let kind = [
+                    ErrorKind::Unauthorized,
+                    ErrorKind::Invalid,
+                    ErrorKind::Failed,
+                    ErrorKind::Rejected,
+                ]
+                .iter()
+                .copied()
+                .find(|candidate| candidate.as_str() == kind)
+                .ok_or_else(malformed)?;
+
+                Ok(Response::error(kind, reason))
+            }
+            "PONG" => {
+                let mut hostname = None;
+                let mut uptime = None;
+
+                // Unknown keys are skipped, so newer listeners can report more
+                for (key, value) in rest
+                    .split_whitespace()
+                    .filter_map(|pair| pair.split_once('='))
+                {
+                    match key {
+                        "hostname" => hostname = Some(value.to_string()),
+                        "uptime" => {
+                            uptime =
+                                Some(Duration::from_secs(value.parse().map_err(|_| malformed())?))
+                        }
+                        _ => {}
+                    }
+                }
+
+                Ok(Response::Pong { hostname, uptime })
+            }
+            INFO_COMMAND => Ok(Response::Info(Box::new(
+                serde_json::from_str(rest).map_err(|_| malformed())?,
+            ))),
+            _ => Err(malformed()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(action: PowerAction, secret: &str, force: bool) -> Request {
+        Request::Action {
+            action,
+            secret: secret.to_string(),
+            force,
+        }
+    }
+
+    fn bare(secret: &str) -> Request {
+        action(PowerAction::Shutdown, secret, false)
+    }
+
+    #[test]
+    fn a_bare_line_is_a_secret_asking_for_a_shutdown() {
+        assert_eq!(
+            Request::parse("Super Secret String\n"),
+            bare("Super Secret String")
+        );
+        assert_eq!(Request::parse("PING pong"), bare("PING pong"));
+        assert_eq!(Request::parse("FORCE"), bare("FORCE"));
+        assert_eq!(Request::parse("INFORMATION"), bare("INFORMATION"));
+    }
+
+    #[test]
+    fn actions_prefix_the_secret_in_any_case() {
+        assert_eq!(
+            Request::parse("REBOOT Super Secret String"),
+            action(PowerAction::Reboot, "Super Secret String", false)
+        );
+        assert_eq!(
+            Request::parse("sleep s3cret"),
+            action(PowerAction::Sleep, "s3cret", false)
+        );
+        assert_eq!(
+            Request::parse("Hibernate s3cret"),
+            action(PowerAction::Hibernate, "s3cret", false)
+        );
+        assert_eq!(
+            Request::parse("SHUTDOWN s3cret"),
+            action(PowerAction::Shutdown, "s3cret", false)
+        );
+    }
+
+    #[test]
+    fn force_prefixes_an_action() {
+        assert_eq!(
+            Request::parse("FORCE SHUTDOWN s3cret"),
+            action(PowerAction::Shutdown, "s3cret", true)
+        );
+        assert_eq!(Request::parse("FORCE s3cret"), bare("FORCE s3cret"));
+    }
+
+    #[test]
+    fn pings_need_no_secret() {
+        assert_eq!(Request::parse("PING"), Request::Ping { keepalive: None });
+        assert_eq!(
+            Request::parse("PING KEEPALIVE"),
+            Request::Ping {
+                keepalive: Some(None)
+            }
+        );
+        assert_eq!(
+            Request::parse("PING KEEPALIVE 30"),
+            Request::Ping {
+                keepalive: Some(Some(Duration::from_secs(30)))
+            }
+        );
+    }
+
+    #[test]
+    fn info_may_carry_the_secret() {
+        assert_eq!(Request::parse("INFO"), Request::Info { secret: None });
+        assert_eq!(
+            Request::parse("INFO s3cret"),
+            Request::Info {
+                secret: Some("s3cret".to_string())
+            }
+        );
+    }
+
+    #[test]
+    fn secrets_starting_with_a_keyword_still_work_on_their_own() {
+        for secret in [
+            "Reboot the lab",
+            "shutdown everything now",
+            "FORCE majeure",
+            "FORCE SLEEP please",
+            "INFO desk password",
+        ] {
+            assert_eq!(Request::parse_with_secret(secret, secret), bare(secret));
+            assert_eq!(
+                Request::parse_with_secret(&format!("{secret}\r\n"), secret),
+                bare(secret)
+            );
+        }
+    }
+
+    #[test]
+    fn secrets_starting_with_a_keyword_can_still_be_prefixed() {
+        let secret = "Reboot the lab";
+
+        assert_eq!(
+            Request::parse_with_secret("REBOOT Reboot the lab", secret),
+            action(PowerAction::Reboot, secret, false)
+        );
+        assert_eq!(
+            Request::parse_with_secret("INFO Reboot the lab", secret),
+            Request::Info {
+                secret: Some(secret.to_string())
+            }
+        );
+    }
+
+    #[test]
+    fn the_wrong_secret_is_parsed_as_sent() {
+        assert_eq!(
+            Request::parse_with_secret("REBOOT guess", "s3cret"),
+            action(PowerAction::Reboot, "guess", false)
+        );
+        assert_eq!(Request::parse_with_secret("guess", "s3cret"), bare("guess"));
+    }
+
+    #[test]
+    fn pings_are_never_secrets() {
+        assert_eq!(
+            Request::parse_with_secret("PING", "PING"),
+            Request::Ping { keepalive: None }
+        );
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        for request in [
+            action(PowerAction::Reboot, "s3cret", false),
+            action(PowerAction::Hibernate, "s3cret", true),
+            Request::Ping { keepalive: None },
+            Request::Ping {
+                keepalive: Some(Some(Duration::from_secs(5))),
+            },
+            Request::Info { secret: None },
+            Request::Info {
+                secret: Some("s3cret".to_string()),
+            },
+        ] {
+            assert_eq!(Request::parse(&request.to_string()), request);
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for response in [
+            Response::Ok {
+                action: PowerAction::Sleep,
+            },
+            Response::Pending {
+                action: PowerAction::Shutdown,
+                delay: Duration::from_secs(300),
+            },
+            Response::error(
+                ErrorKind::Rejected,
+                "isn't allowed during Exams (09:00–10:00)",
+            ),
+            Response::Pong {
+                hostname: Some("lab-1".to_string()),
+                uptime: Some(Duration::from_secs(42)),
+            },
+        ] {
+            assert_eq!(response.to_string().parse::<Response>(), Ok(response));
+        }
+    }
+
+    #[test]
+    fn unknown_responses_are_malformed() {
+        assert!("NOPE".parse::<Response>().is_err());
+        assert!("ERR sideways reason".parse::<Response>().is_err());
+    }
+}
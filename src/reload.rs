@@ -0,0 +1,511 @@
+//! Reloads the configuration while the listener is running – when the configuration file
+//! changes, on `SIGHUP`, or when the Service Control Manager sends `ParamChange` on Windows.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::configuration::AppConfiguration;
+use crate::configuration_storage::{self, AppConfigurationStorage};
+use crate::configuration_validation;
+use crate::listener_service::{self, ListenerContext};
+use crate::systemd;
+
+/// Editors often write a file in several steps, so changes are collected for this long before
+/// the configuration is read
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ReloadConfiguration {
+    /// Reload when the configuration file changes. `SIGHUP` (and `ParamChange` on Windows)
+    /// reload it either way.
+    pub watch: bool,
+
+    /// How often to look at the configuration file where it can't be watched with inotify
+    pub poll_interval_seconds: u64,
+}
+
+impl Default for ReloadConfiguration {
+    fn default() -> Self {
+        ReloadConfiguration {
+            watch: true,
+            poll_interval_seconds: 5,
+        }
+    }
+}
+
+static REQUESTS: OnceLock<Mutex<Sender<()>>> = OnceLock::new();
+
+/// Asks the running listener to reload its configuration. Does nothing if it isn't running.
+pub fn request() {
+    if let Some(requests) = REQUESTS.get() {
+        let _ = requests
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .send(());
+    }
+}
+
+/// Starts reloading the configuration whenever it's asked to
+pub fn spawn(configuration: &ReloadConfiguration, context: Arc<ListenerContext>) {
+    let (sender, receiver) = mpsc::channel();
+
+    if REQUESTS.set(Mutex::new(sender)).is_err() {
+        log::warn!("The configuration is already being reloaded by another listener");
+        return;
+    }
+
+    #[cfg(unix)]
+    hangup::spawn();
+
+    if configuration.watch {
+        match configuration_storage::storage().path() {
+            Some(path) => watch(
+                path,
+                Duration::from_secs(configuration.poll_interval_seconds),
+            ),
+            None => log::info!(
+                "The configuration isn't stored in a file, so it's only reloaded when asked"
+            ),
+        }
+    }
+
+    thread::spawn(move || {
+        let storage = configuration_storage::storage();
+        handle_requests(receiver, storage.as_ref(), &context)
+    });
+}
+
+/// The heartbeat the thread reloading the configuration beats
+const RELOADING_HEARTBEAT: &str = "reloading the configuration";
+
+/// Reloads the configuration from `storage` for each batch of requests – the ones that arrive
+/// within `SETTLE_TIME` of each other only reload it once – until the requests stop coming
+fn handle_requests(
+    requests: Receiver<()>,
+    storage: &dyn AppConfigurationStorage,
+    context: &Arc<ListenerContext>,
+) {
+    loop {
+        context.heartbeats.beat(RELOADING_HEARTBEAT);
+
+        match requests.recv_timeout(systemd::HEARTBEAT_INTERVAL) {
+            Ok(()) => {}
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        thread::sleep(SETTLE_TIME);
+        while requests.try_recv().is_ok() {}
+
+        systemd::notify_reloading();
+        reload(storage, context);
+        systemd::notify_ready();
+    }
+}
+
+/// Reads the configuration again and switches the listener over to it, if it's valid. If it
+/// isn't – or it's missing, as it can be for a moment while an editor saves it – the listener
+/// carries on with the configuration it has.
+fn reload(storage: &dyn AppConfigurationStorage, context: &Arc<ListenerContext>) {
+    let configuration = match configuration_validation::reload(storage) {
+        Ok(configuration) => configuration,
+        Err(error) => {
+            log::error!("Keeping the current configuration: {}", error);
+            return;
+        }
+    };
+
+    if let Err(error) = configuration_validation::check_default_secret(&configuration) {
+        log::error!("Keeping the current configuration: {}", error);
+        return;
+    }
+
+    let current = context.configuration();
+
+    if *current == configuration {
+        log::debug!("The configuration at {} hasn't changed", storage.location());
+        return;
+    }
+
+    if configuration.port_number != current.port_number {
+        if let Err(error) = listener_service::rebind(context, &configuration) {
+            log::error!(
+                "Keeping the current configuration, as port {} can't be listened on: {}",
+                configuration.port_number,
+                error
+            );
+            return;
+        }
+    }
+
+    let needing_restart = sections_needing_restart(&current, &configuration);
+
+    if !needing_restart.is_empty() {
+        log::warn!(
+            "Changes to {} take effect once the service is restarted",
+            needing_restart.join(", ")
+        );
+    }
+
+    context.set_configuration(configuration);
+    log::info!("Reloaded the configuration from {}", storage.location());
+}
+
+/// The sections of the configuration that are only read when the listener starts
+fn sections_needing_restart(
+    current: &AppConfiguration,
+    configuration: &AppConfiguration,
+) -> Vec<&'static str> {
+    let mut sections = Vec::new();
+
+    if current.audit != configuration.audit {
+        sections.push("audit");
+    }
+
+    if current.logging != configuration.logging {
+        sections.push("logging");
+    }
+
+    if current.control != configuration.control {
+        sections.push("control");
+    }
+
+    if current.scheduler != configuration.scheduler {
+        sections.push("scheduler");
+    }
+
+    if current.idle != configuration.idle {
+        sections.push("idle");
+    }
+
+    if current.reload != configuration.reload {
+        sections.push("reload");
+    }
+
+    sections
+}
+
+/// Asks for a reload whenever the file at `path` changes – with inotify where it's available,
+/// and by looking at the file every `poll_interval` otherwise
+fn watch(path: PathBuf, poll_interval: Duration) {
+    log::info!("Watching {:?} for changes", path);
+
+    thread::spawn(move || {
+        #[cfg(target_os = "linux")]
+        if let Err(error) = inotify::watch(&path) {
+            log::info!(
+                "Unable to watch {:?} with inotify, so looking at it every {:?} instead: {}",
+                path,
+                poll_interval,
+                error
+            );
+        }
+
+        poll(&path, poll_interval.max(Duration::from_secs(1)));
+    });
+}
+
+fn poll(path: &Path, interval: Duration) {
+    let modified = |path: &Path| -> Option<(SystemTime, u64)> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    };
+
+    let mut last_modified = modified(path);
+
+    loop {
+        thread::sleep(interval);
+
+        let modified = modified(path);
+
+        if modified != last_modified {
+            log::debug!("{:?} has changed", path);
+            last_modified = modified;
+            request();
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+    use std::path::Path;
+
+    /// Watches the directory, rather than the file, since editors often replace the file rather
+    /// than writing to it. Only returns if the file can't be watched.
+    pub fn watch(path: &Path) -> nix::Result<()> {
+        let directory = path
+            .parent()
+            .filter(|directory| !directory.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC)?;
+        inotify.add_watch(
+            directory,
+            AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO | AddWatchFlags::IN_CREATE,
+        )?;
+
+        loop {
+            let changed = inotify
+                .read_events()?
+                .into_iter()
+                .any(|event| event.name.as_deref() == path.file_name());
+
+            if changed {
+                log::debug!("{:?} has changed", path);
+                super::request();
+            }
+        }
+    }
+}
+
+/// Turns `SIGHUP` into a reload request. The signal handler only writes to a pipe, which a
+/// thread reads from, since there's little else a handler can safely do.
+#[cfg(unix)]
+mod hangup {
+    use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+    use nix::unistd;
+    use std::os::unix::io::RawFd;
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::thread;
+
+    /// The end of the pipe the handler writes to
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn handle(_signal: nix::libc::c_int) {
+        let _ = unistd::write(PIPE.load(Ordering::Relaxed), &[0]);
+    }
+
+    pub fn spawn() {
+        let (reader, writer): (RawFd, RawFd) = match unistd::pipe() {
+            Ok(pipe) => pipe,
+            Err(error) => {
+                log::warn!("Unable to listen for SIGHUP: {}", error);
+                return;
+            }
+        };
+
+        PIPE.store(writer, Ordering::Relaxed);
+
+        let action = SigAction::new(
+            SigHandler::Handler(handle),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+
+        // Safety: the handler only calls `write`, which is async-signal-safe
+        if let Err(error) = unsafe { signal::sigaction(Signal::SIGHUP, &action) } {
+            log::warn!("Unable to listen for SIGHUP: {}", error);
+            return;
+        }
+
+        thread::spawn(move || {
+            let mut buffer = [0; 16];
+
+            while let Ok(read) = unistd::read(reader, &mut buffer) {
+                if read == 0 {
+                    break;
+                }
+
+                log::info!("Received SIGHUP, reloading the configuration");
+                super::request();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{ConfigurationError, DEFAULT_SECRET};
+    use crate::configuration_storage::MemoryStorage;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn current() -> AppConfiguration {
+        let mut configuration = AppConfiguration {
+            secret: "the current secret".to_string(),
+            ..Default::default()
+        };
+        configuration.audit.enabled = false;
+        configuration.logging.file = None;
+        configuration
+    }
+
+    fn context() -> Arc<ListenerContext> {
+        Arc::new(ListenerContext::new(&current()))
+    }
+
+    type Change = fn(&mut AppConfiguration);
+
+    #[test]
+    fn settings_read_per_connection_dont_need_a_restart() {
+        let changes: Vec<Change> = vec![
+            |configuration| configuration.secret = "a new secret".to_string(),
+            |configuration| configuration.addresses = vec!["10.0.1.100".parse().unwrap()],
+            |configuration| configuration.port_number = 50000,
+            |configuration| configuration.insecure_allow_default_secret = true,
+            |configuration| configuration.connection.max_keepalive_connections = 1,
+            |configuration| configuration.inhibitors.refuse_when_users_logged_in = true,
+            |configuration| configuration.maintenance.timezone = Some(chrono_tz::UTC),
+            |configuration| configuration.info.enabled = false,
+            |configuration| configuration.notifications.countdown_seconds = 60,
+            |configuration| configuration.power.logind.wait_for_inhibitors_seconds = 60,
+        ];
+
+        for change in changes {
+            let mut configuration = current();
+            change(&mut configuration);
+
+            assert_ne!(configuration, current());
+            assert!(sections_needing_restart(&current(), &configuration).is_empty());
+        }
+    }
+
+    #[test]
+    fn settings_read_at_startup_need_a_restart() {
+        let changes: Vec<(&str, Change)> = vec![
+            ("audit", |configuration| configuration.audit.max_files = 1),
+            ("logging", |configuration| {
+                configuration.logging.max_files = 1
+            }),
+            ("control", |configuration| {
+                configuration.control.enabled = false
+            }),
+            ("scheduler", |configuration| {
+                configuration.scheduler.timezone = Some(chrono_tz::UTC)
+            }),
+            ("idle", |configuration| {
+                configuration.idle.check_interval_seconds = 1
+            }),
+            ("reload", |configuration| configuration.reload.watch = false),
+        ];
+
+        for (section, change) in changes {
+            let mut configuration = current();
+            change(&mut configuration);
+
+            assert_eq!(
+                sections_needing_restart(&current(), &configuration),
+                vec![section]
+            );
+        }
+    }
+
+    #[test]
+    fn every_section_needing_a_restart_is_named() {
+        let mut configuration = current();
+        configuration.audit.max_files = 1;
+        configuration.reload.watch = false;
+
+        assert_eq!(
+            sections_needing_restart(&current(), &configuration),
+            vec!["audit", "reload"]
+        );
+    }
+
+    #[test]
+    fn changes_are_applied_in_place() {
+        let context = context();
+        let mut configuration = current();
+        configuration.secret = "a new secret".to_string();
+        configuration.inhibitors.refuse_when_users_logged_in = true;
+        // Only takes effect after a restart, but is still the configuration in use
+        configuration.audit.max_files = 1;
+
+        reload(&MemoryStorage::new(Some(configuration.clone())), &context);
+
+        assert_eq!(*context.configuration(), configuration);
+    }
+
+    #[test]
+    fn invalid_configurations_are_ignored() {
+        let mut default_secret = current();
+        default_secret.secret = DEFAULT_SECRET.to_string();
+
+        for storage in [
+            MemoryStorage::new(None),
+            MemoryStorage::new(Some(default_secret)),
+        ] {
+            let context = context();
+
+            reload(&storage, &context);
+
+            assert_eq!(*context.configuration(), current());
+        }
+    }
+
+    #[test]
+    fn the_configuration_is_kept_if_the_new_port_cant_be_listened_on() {
+        let taken = TcpListener::bind("0.0.0.0:0").unwrap();
+        let mut configuration = current();
+        configuration.port_number = taken.local_addr().unwrap().port();
+        let context = context();
+
+        reload(&MemoryStorage::new(Some(configuration)), &context);
+
+        assert_eq!(*context.configuration(), current());
+    }
+
+    /// Counts how often the configuration is read
+    struct CountingStorage {
+        storage: MemoryStorage,
+        fetches: AtomicUsize,
+    }
+
+    impl AppConfigurationStorage for CountingStorage {
+        fn fetch(&self) -> Result<AppConfiguration, ConfigurationError> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            self.storage.fetch()
+        }
+
+        fn save(&self, configuration: &AppConfiguration) -> Result<(), ConfigurationError> {
+            self.storage.save(configuration)
+        }
+
+        fn delete(&self) -> Result<(), ConfigurationError> {
+            self.storage.delete()
+        }
+
+        fn exists(&self) -> bool {
+            self.storage.exists()
+        }
+
+        fn location(&self) -> String {
+            self.storage.location()
+        }
+
+        fn backup(&self) -> Result<String, ConfigurationError> {
+            self.storage.backup()
+        }
+    }
+
+    #[test]
+    fn requests_that_arrive_together_reload_once() {
+        let storage = CountingStorage {
+            storage: MemoryStorage::new(Some(current())),
+            fetches: AtomicUsize::new(0),
+        };
+        let (sender, receiver) = mpsc::channel();
+
+        // An editor saving the file, followed by SIGHUP
+        for _ in 0..3 {
+            sender.send(()).unwrap();
+        }
+
+        let later = thread::spawn(move || {
+            thread::sleep(SETTLE_TIME * 3);
+            sender.send(()).unwrap();
+        });
+
+        handle_requests(receiver, &storage, &context());
+        later.join().unwrap();
+
+        assert_eq!(storage.fetches.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// An append-only file that is rotated out of the way once it grows past `max_size_bytes` or
+/// becomes older than `max_age`. Rotated files are kept as `<path>.1` (newest) through
+/// `<path>.<max_files>` (oldest).
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size_bytes: u64,
+    max_age: Option<Duration>,
+    max_files: u32,
+
+    file: File,
+    size: u64,
+    opened_at: SystemTime,
+}
+
+impl RotatingFile {
+    pub fn open(
+        path: &Path,
+        max_size_bytes: u64,
+        max_age: Option<Duration>,
+        max_files: u32,
+    ) -> io::Result<RotatingFile> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+
+        let opened_at = metadata
+            .created()
+            .or_else(|_| metadata.modified())
+            .unwrap_or_else(|_| SystemTime::now());
+
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_size_bytes,
+            max_age,
+            max_files,
+            file,
+            size: metadata.len(),
+            opened_at,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends `line` (and a trailing newline) to the file, rotating first if needed.
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.needs_rotation(line.len() as u64 + 1) {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.file.flush()?;
+
+        self.size += line.len() as u64 + 1;
+
+        Ok(())
+    }
+
+    fn needs_rotation(&self, incoming_bytes: u64) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+
+        if self.max_size_bytes > 0 && self.size + incoming_bytes > self.max_size_bytes {
+            return true;
+        }
+
+        match self.max_age {
+            Some(max_age) => self
+                .opened_at
+                .elapsed()
+                .map(|age| age > max_age)
+                .unwrap_or(false),
+            None => false,
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            // Nothing to keep – start the current file over
+            self.file.set_len(0)?;
+        } else {
+            let _ = std::fs::remove_file(self.rotated_path(self.max_files));
+
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+
+                if from.exists() {
+                    std::fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+
+        self.size = 0;
+        self.opened_at = SystemTime::now();
+
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{index}"));
+        PathBuf::from(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn read(path: PathBuf) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn rotates_once_the_size_is_reached() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("test.log");
+        let mut file = RotatingFile::open(&path, 10, None, 3).unwrap();
+
+        file.write_line("first").unwrap();
+        file.write_line("second").unwrap();
+
+        assert_eq!(read(path.clone()), "second\n");
+        assert_eq!(read(file.rotated_path(1)), "first\n");
+    }
+
+    #[test]
+    fn keeps_max_files_generations() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("test.log");
+        let mut file = RotatingFile::open(&path, 6, None, 2).unwrap();
+
+        for line in &["one..", "two..", "three", "four."] {
+            file.write_line(line).unwrap();
+        }
+
+        assert_eq!(read(path), "four.\n");
+        assert_eq!(read(file.rotated_path(1)), "three\n");
+        assert_eq!(read(file.rotated_path(2)), "two..\n");
+        assert!(!file.rotated_path(3).exists());
+    }
+
+    #[test]
+    fn starts_over_when_keeping_no_files() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("test.log");
+        let mut file = RotatingFile::open(&path, 10, None, 0).unwrap();
+
+        file.write_line("first").unwrap();
+        file.write_line("second").unwrap();
+
+        assert_eq!(read(path), "second\n");
+        assert!(!file.rotated_path(1).exists());
+    }
+
+    #[test]
+    fn a_single_long_line_isnt_rotated_away() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("test.log");
+        let mut file = RotatingFile::open(&path, 4, None, 2).unwrap();
+
+        file.write_line("longer than the limit").unwrap();
+
+        assert_eq!(read(path), "longer than the limit\n");
+        assert!(!file.rotated_path(1).exists());
+    }
+
+    #[test]
+    fn counts_what_was_already_in_the_file() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("test.log");
+        std::fs::write(&path, "existing\n").unwrap();
+
+        let mut file = RotatingFile::open(&path, 14, None, 1).unwrap();
+        file.write_line("new").unwrap();
+        file.write_line("newer").unwrap();
+
+        assert_eq!(read(path), "newer\n");
+        assert_eq!(read(file.rotated_path(1)), "existing\nnew\n");
+    }
+
+    #[test]
+    fn rotates_once_the_file_is_too_old() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("test.log");
+        let mut file = RotatingFile::open(&path, 0, Some(Duration::from_secs(60)), 1).unwrap();
+
+        file.write_line("old").unwrap();
+        file.opened_at = SystemTime::now() - Duration::from_secs(120);
+        file.write_line("new").unwrap();
+
+        assert_eq!(read(path), "new\n");
+        assert_eq!(read(file.rotated_path(1)), "old\n");
+    }
+
+    #[test]
+    fn creates_missing_directories() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("logs").join("test.log");
+
+        let mut file = RotatingFile::open(&path, 0, None, 1).unwrap();
+        file.write_line("line").unwrap();
+
+        assert_eq!(read(path), "line\n");
+    }
+}
@@ -0,0 +1,407 @@
+use chrono::{DateTime, Duration as ChronoDuration, Local, TimeZone, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::audit::Origin;
+use crate::listener_service::{self, ListenerContext};
+use crate::notifications;
+use crate::power::PowerAction;
+use crate::protocol::Response;
+
+/// The longest the scheduler sleeps for before looking at the clock again, so that it notices
+/// the clock changing or the machine waking from sleep. A run that's noticed later than this
+/// was missed (the machine was asleep or off), and is skipped rather than performed late.
+const MAX_SLEEP: Duration = Duration::from_secs(30);
+
+/// Actions the listener performs by itself, such as powering off at 22:00 if the machine is
+/// still on. They go through the same maintenance windows and inhibitors as remote requests.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct SchedulerConfiguration {
+    /// An IANA time zone name, such as `Europe/London`. The machine's own, if left out.
+    pub timezone: Option<Tz>,
+
+    pub entries: Vec<ScheduledAction>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledAction {
+    #[serde(default)]
+    pub name: Option<String>,
+
+    pub schedule: CronSchedule,
+
+    #[serde(default = "default_action")]
+    pub action: PowerAction,
+
+    /// Warn anyone logged in this many minutes beforehand. `0` doesn't warn.
+    #[serde(default)]
+    pub warning_minutes: u64,
+
+    /// The warning to show, instead of the default one
+    #[serde(default)]
+    pub warning_message: Option<String>,
+}
+
+fn default_action() -> PowerAction {
+    PowerAction::Shutdown
+}
+
+/// A cron expression. The usual five fields (minute, hour, day of month, month, day of week)
+/// are accepted, as are the six- and seven-field forms with seconds and years. Days of the week
+/// are numbered as usual, from 0 (Sunday) to 7 (Sunday again), or named.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct CronSchedule {
+    expression: String,
+    schedule: Schedule,
+}
+
+impl TryFrom<String> for CronSchedule {
+    type Error = String;
+
+    fn try_from(expression: String) -> Result<Self, Self::Error> {
+        let mut fields: Vec<String> = expression.split_whitespace().map(String::from).collect();
+
+        if fields.len() == 5 {
+            fields.insert(0, "0".to_string());
+        }
+
+        // The cron crate numbers the days of the week from 1 (Sunday), rather than from 0
+        if let Some(days) = fields.get_mut(5) {
+            *days = days_of_week(days)
+                .map_err(|error| format!("Invalid schedule {expression:?}: {error}"))?;
+        }
+
+        let schedule = Schedule::from_str(&fields.join(" "))
+            .map_err(|error| format!("Invalid schedule {expression:?}: {error}"))?;
+
+        Ok(CronSchedule {
+            expression,
+            schedule,
+        })
+    }
+}
+
+/// Converts a day of the week field from the usual numbering into the cron crate's, spelling out
+/// numeric ranges and steps as lists. Names, `*` and `?` mean the same to both.
+fn days_of_week(field: &str) -> Result<String, String> {
+    let mut converted = Vec::new();
+
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (item, None),
+        };
+
+        let numeric = |value: &str| !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit());
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start, end),
+            None => (range, range),
+        };
+
+        if !numeric(start) && !numeric(end) {
+            converted.push(item.to_string());
+            continue;
+        }
+
+        let day = |value: &str| match value.parse::<u32>() {
+            Ok(day) if day <= 7 && numeric(value) => Ok(day),
+            _ => Err(format!(
+                "{value:?} isn't a day of the week (0 to 7, or a name such as MON)"
+            )),
+        };
+        let (start, end) = (day(start)?, day(end)?);
+
+        // A single day with a step runs from that day to the end of the week
+        let end = if step.is_some() && start == end {
+            7
+        } else {
+            end
+        };
+
+        if start > end {
+            return Err(format!("{range:?} runs backwards"));
+        }
+
+        let step = match step.map(str::parse::<usize>) {
+            None => 1,
+            Some(Ok(step)) if step > 0 => step,
+            Some(_) => return Err(format!("{item:?} has an invalid step")),
+        };
+
+        let days: BTreeSet<u32> = (start..=end).step_by(step).map(|day| day % 7 + 1).collect();
+        converted.extend(days.iter().map(u32::to_string));
+    }
+
+    Ok(converted.join(","))
+}
+
+impl From<CronSchedule> for String {
+    fn from(schedule: CronSchedule) -> Self {
+        schedule.expression
+    }
+}
+
+impl PartialEq for CronSchedule {
+    fn eq(&self, other: &Self) -> bool {
+        self.expression == other.expression
+    }
+}
+
+impl Eq for CronSchedule {}
+
+impl fmt::Display for ScheduledAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => f.write_str(name),
+            None => f.write_str(&self.schedule.expression),
+        }
+    }
+}
+
+impl ScheduledAction {
+    /// The first time the entry runs after `now`
+    pub fn next_run(&self, timezone: Option<Tz>, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match timezone {
+            Some(timezone) => next_after(&self.schedule.schedule, &timezone, now),
+            None => next_after(&self.schedule.schedule, &Local, now),
+        }
+    }
+
+    fn warning(&self, at: DateTime<Utc>, timezone: Option<Tz>) -> String {
+        self.warning_message.clone().unwrap_or_else(|| {
+            let time = match timezone {
+                Some(timezone) => at.with_timezone(&timezone).format("%H:%M").to_string(),
+                None => at.with_timezone(&Local).format("%H:%M").to_string(),
+            };
+
+            format!(
+                "This computer will {} at {}. Please save your work.",
+                self.action, time
+            )
+        })
+    }
+}
+
+fn next_after<Z: TimeZone>(
+    schedule: &Schedule,
+    timezone: &Z,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    schedule
+        .after(&now.with_timezone(timezone))
+        .next()
+        .map(|next| next.with_timezone(&Utc))
+}
+
+/// Starts a background thread for each scheduled entry
+pub fn spawn(configuration: &SchedulerConfiguration, context: Arc<ListenerContext>) {
+    for (index, entry) in configuration.entries.iter().enumerate() {
+        let entry = entry.clone();
+        let timezone = configuration.timezone;
+        let context = context.clone();
+
+        log::info!("Scheduled {} ({})", entry.action, entry);
+
+        thread::spawn(move || run_entry(index, &entry, timezone, &context));
+    }
+}
+
+fn run_entry(
+    index: usize,
+    entry: &ScheduledAction,
+    timezone: Option<Tz>,
+    context: &ListenerContext,
+) {
+    loop {
+        let next = match entry.next_run(timezone, (context.clock)()) {
+            Some(next) => next,
+            None => {
+                log::info!("{} won't run again", entry);
+                context.status.clear_scheduled_run(index);
+                return;
+            }
+        };
+
+        log::debug!("{} will next run at {}", entry, next);
+        context
+            .status
+            .record_scheduled_run(index, entry.to_string(), entry.action, next);
+
+        if entry.warning_minutes > 0 {
+            let warning_at = next - ChronoDuration::minutes(entry.warning_minutes as i64);
+
+            if warning_at > (context.clock)() && sleep_until(warning_at, context) {
+                notifications::broadcast(&entry.warning(next, timezone));
+            }
+        }
+
+        if !sleep_until(next, context) {
+            log::warn!(
+                "{} missed its run at {} (it's now {}), so it won't {} until its next one",
+                entry,
+                next,
+                (context.clock)(),
+                entry.action
+            );
+            continue;
+        }
+
+        let origin = Origin::Schedule(entry.to_string());
+
+        match listener_service::perform_action(entry.action, false, &origin, context) {
+            Response::Ok { .. } => log::info!("{} performed {}", entry, entry.action),
+            response => log::warn!("{} didn't {}: {}", entry, entry.action, response),
+        }
+    }
+}
+
+/// Sleeps until `at`, and says whether that's (about) when it woke up – false if it woke up too
+/// late, because the machine was asleep at the time
+fn sleep_until(at: DateTime<Utc>, context: &ListenerContext) -> bool {
+    loop {
+        let now = (context.clock)();
+        let remaining = match (at - now).to_std() {
+            Ok(remaining) if !remaining.is_zero() => remaining,
+            _ => {
+                return (now - at)
+                    .to_std()
+                    .map_or(true, |overslept| overslept <= MAX_SLEEP)
+            }
+        };
+
+        thread::sleep(remaining.min(MAX_SLEEP));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::AppConfiguration;
+    use chrono::{Datelike, Weekday};
+
+    fn entry(schedule: &str) -> ScheduledAction {
+        ScheduledAction {
+            name: None,
+            schedule: CronSchedule::try_from(schedule.to_string()).unwrap(),
+            action: PowerAction::Shutdown,
+            warning_minutes: 0,
+            warning_message: None,
+        }
+    }
+
+    /// The weekdays of the next `count` runs after noon on Saturday 2024-06-01 (UTC)
+    fn weekdays(schedule: &str, count: usize) -> Vec<Weekday> {
+        let entry = entry(schedule);
+        let mut now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let mut weekdays = Vec::new();
+
+        for _ in 0..count {
+            now = entry.next_run(Some(chrono_tz::UTC), now).unwrap();
+            weekdays.push(now.weekday());
+        }
+
+        weekdays
+    }
+
+    #[test]
+    fn one_to_five_is_monday_to_friday() {
+        use Weekday::*;
+
+        assert_eq!(
+            weekdays("0 22 * * 1-5", 6),
+            vec![Mon, Tue, Wed, Thu, Fri, Mon]
+        );
+    }
+
+    #[test]
+    fn sunday_is_zero_seven_or_named() {
+        for schedule in &["0 22 * * 0", "0 22 * * 7", "0 22 * * SUN", "0 22 * * sun"] {
+            assert_eq!(weekdays(schedule, 2), vec![Weekday::Sun; 2], "{}", schedule);
+        }
+    }
+
+    #[test]
+    fn runs_at_the_scheduled_time() {
+        let entry = entry("30 22 * * 1-5");
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+
+        assert_eq!(
+            entry.next_run(Some(chrono_tz::UTC), now),
+            Some(Utc.with_ymd_and_hms(2024, 6, 3, 22, 30, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn lists_steps_and_names_are_converted() {
+        use Weekday::*;
+
+        assert_eq!(weekdays("0 22 * * 0,6", 3), vec![Sat, Sun, Sat]);
+        assert_eq!(weekdays("0 22 * * 1-5/2", 4), vec![Mon, Wed, Fri, Mon]);
+        assert_eq!(weekdays("0 22 * * */2", 4), vec![Sat, Sun, Tue, Thu]);
+        assert_eq!(weekdays("0 22 * * MON-FRI", 2), vec![Mon, Tue]);
+        assert_eq!(weekdays("0 0 22 * * 1 *", 2), vec![Mon, Mon]);
+    }
+
+    #[test]
+    fn invalid_days_are_refused() {
+        for schedule in &[
+            "0 22 * * 8",
+            "0 22 * * 5-1",
+            "0 22 * * 1-5/0",
+            "0 22 * * 1-FRI",
+        ] {
+            assert!(
+                CronSchedule::try_from(schedule.to_string()).is_err(),
+                "{}",
+                schedule
+            );
+        }
+    }
+
+    #[test]
+    fn the_expression_is_kept_as_written() {
+        let schedule = CronSchedule::try_from("0 22 * * 1-5".to_string()).unwrap();
+
+        assert_eq!(String::from(schedule), "0 22 * * 1-5");
+    }
+
+    fn context(clock: fn() -> DateTime<Utc>) -> ListenerContext {
+        let mut configuration = AppConfiguration::default();
+        configuration.audit.enabled = false;
+
+        let mut context = ListenerContext::new(&configuration);
+        context.clock = clock;
+        context
+    }
+
+    #[test]
+    fn runs_noticed_on_time_go_ahead() {
+        let context = context(|| Utc.with_ymd_and_hms(2024, 6, 3, 22, 0, 20).unwrap());
+
+        assert!(sleep_until(
+            Utc.with_ymd_and_hms(2024, 6, 3, 22, 0, 0).unwrap(),
+            &context
+        ));
+    }
+
+    #[test]
+    fn runs_missed_while_asleep_are_skipped() {
+        // Woken up at 08:00 the next morning, long after the 22:00 power-off
+        let context = context(|| Utc.with_ymd_and_hms(2024, 6, 4, 8, 0, 0).unwrap());
+
+        assert!(!sleep_until(
+            Utc.with_ymd_and_hms(2024, 6, 3, 22, 0, 0).unwrap(),
+            &context
+        ));
+    }
+}
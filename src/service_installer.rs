@@ -0,0 +1,358 @@
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+
+use crate::configuration_storage;
+
+/// Registers (or unregisters) this executable with the platform's service manager – a systemd
+/// unit on Linux, a launchd daemon on macOS and an SCM service on Windows.
+pub struct ServiceInstaller {
+    /// Service files (and the configuration) are written relative to this directory. Anything
+    /// other than `/` is treated as a staging area: files are written, but the service manager
+    /// isn't told about them.
+    root: PathBuf,
+    executable: PathBuf,
+}
+
+impl ServiceInstaller {
+    pub fn new(root: PathBuf, executable: PathBuf) -> ServiceInstaller {
+        ServiceInstaller { root, executable }
+    }
+
+    fn is_live_system(&self) -> bool {
+        self.root == Path::new("/")
+    }
+
+    /// `path` (which must be absolute) relocated under `root`
+    #[cfg(not(windows))]
+    fn rooted(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    fn create_configuration_if_not_exists(&self) -> Result<()> {
+        #[cfg(not(windows))]
+        let storage = if self.is_live_system() {
+            configuration_storage::storage()
+        } else {
+            configuration_storage::rooted_storage(&self.root)
+        };
+
+        #[cfg(windows)]
+        let storage = configuration_storage::storage();
+
+        storage
+            .create_if_not_exists()
+            .context("Unable to create the default configuration")
+    }
+
+    #[cfg(not(windows))]
+    fn write_file(path: &Path, contents: &str) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Unable to create {parent:?}"))?;
+        }
+
+        log::debug!("Writing {:?}", path);
+        std::fs::write(path, contents).with_context(|| format!("Unable to write {path:?}"))
+    }
+
+    #[cfg(not(windows))]
+    fn remove_file(path: &Path) -> Result<()> {
+        if !path.exists() {
+            log::debug!("{:?} doesn't exist – nothing to remove", path);
+            return Ok(());
+        }
+
+        log::debug!("Removing {:?}", path);
+        std::fs::remove_file(path).with_context(|| format!("Unable to remove {path:?}"))
+    }
+}
+
+#[cfg(not(windows))]
+fn run_command(program: &str, arguments: &[&str]) -> Result<()> {
+    log::debug!("Running {} {:?}", program, arguments);
+
+    let status = std::process::Command::new(program)
+        .args(arguments)
+        .status()
+        .with_context(|| format!("Unable to run `{program}`"))?;
+
+    if !status.success() {
+        return Err(anyhow!(
+            "`{} {}` failed with {}",
+            program,
+            arguments.join(" "),
+            status
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+const SERVICE_UNIT_PATH: &str = "/etc/systemd/system/shutdown-on-lan.service";
+
+#[cfg(target_os = "linux")]
+const SOCKET_UNIT_PATH: &str = "/etc/systemd/system/shutdown-on-lan.socket";
+
+#[cfg(target_os = "linux")]
+impl ServiceInstaller {
+    pub fn install(&self) -> Result<()> {
+        self.create_configuration_if_not_exists()?;
+
+        let unit_path = self.rooted(SERVICE_UNIT_PATH);
+        Self::write_file(&unit_path, &crate::systemd::service_unit(&self.executable))?;
+        println!("Installed systemd unit at {unit_path:?}");
+
+        if self.is_live_system() {
+            run_command("systemctl", &["daemon-reload"])?;
+            run_command("systemctl", &["enable", "--now", "shutdown-on-lan.service"])?;
+            println!("Started shutdown-on-lan.service");
+        }
+
+        Ok(())
+    }
+
+    pub fn uninstall(&self) -> Result<()> {
+        if self.is_live_system() {
+            // The units may already be stopped or disabled – that's fine
+            let _ = run_command("systemctl", &["disable", "--now", "shutdown-on-lan.socket"]);
+            let _ = run_command(
+                "systemctl",
+                &["disable", "--now", "shutdown-on-lan.service"],
+            );
+        }
+
+        Self::remove_file(&self.rooted(SOCKET_UNIT_PATH))?;
+        Self::remove_file(&self.rooted(SERVICE_UNIT_PATH))?;
+
+        if self.is_live_system() {
+            run_command("systemctl", &["daemon-reload"])?;
+        }
+
+        println!("Removed shutdown-on-lan.service");
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+const LAUNCH_DAEMON_TEMPLATE: &str = include_str!("../build/mac/com.jkmassel.shutdownonlan.plist");
+
+/// The path that the bundled launchd plist expects the binary to be installed at
+#[cfg(target_os = "macos")]
+const TEMPLATE_EXECUTABLE_PATH: &str = "/Library/Services/shutdownonlan";
+
+#[cfg(target_os = "macos")]
+const LAUNCH_DAEMON_PATH: &str = "/Library/LaunchDaemons/com.jkmassel.shutdownonlan.plist";
+
+#[cfg(target_os = "macos")]
+impl ServiceInstaller {
+    pub fn install(&self) -> Result<()> {
+        self.create_configuration_if_not_exists()?;
+
+        let plist_path = self.rooted(LAUNCH_DAEMON_PATH);
+        let plist = LAUNCH_DAEMON_TEMPLATE
+            .replace(TEMPLATE_EXECUTABLE_PATH, &self.executable.to_string_lossy());
+
+        Self::write_file(&plist_path, &plist)?;
+        println!("Installed launchd daemon at {plist_path:?}");
+
+        if self.is_live_system() {
+            // Same as the installer's `postinstall` script – unload any previous version first
+            let _ = run_command("launchctl", &["unload", "-w", LAUNCH_DAEMON_PATH]);
+            run_command("launchctl", &["load", "-w", LAUNCH_DAEMON_PATH])?;
+            println!("Started com.jkmassel.shutdownonlan");
+        }
+
+        Ok(())
+    }
+
+    pub fn uninstall(&self) -> Result<()> {
+        if self.is_live_system() {
+            // The daemon may already be unloaded – that's fine
+            let _ = run_command("launchctl", &["unload", "-w", LAUNCH_DAEMON_PATH]);
+        }
+
+        Self::remove_file(&self.rooted(LAUNCH_DAEMON_PATH))?;
+        println!("Removed com.jkmassel.shutdownonlan");
+
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+const SERVICE_NAME: &str = "ShutdownOnLan";
+
+#[cfg(windows)]
+impl ServiceInstaller {
+    pub fn install(&self) -> Result<()> {
+        use std::ffi::OsString;
+        use windows_service::service::{
+            ServiceAccess, ServiceErrorControl, ServiceInfo, ServiceStartType, ServiceType,
+        };
+        use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+        if !self.is_live_system() {
+            return Err(anyhow!(
+                "Installing into another root isn't supported on Windows"
+            ));
+        }
+
+        self.create_configuration_if_not_exists()?;
+
+        let manager = ServiceManager::local_computer(
+            None::<&str>,
+            ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+        )
+        .map_err(|error| anyhow!("Unable to connect to the service manager: {}", error))?;
+
+        let service_info = ServiceInfo {
+            name: OsString::from(SERVICE_NAME),
+            display_name: OsString::from(SERVICE_NAME),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: self.executable.clone(),
+            launch_arguments: vec![],
+            dependencies: vec![],
+            account_name: None, // LocalSystem
+            account_password: None,
+        };
+
+        let service = manager
+            .create_service(
+                &service_info,
+                ServiceAccess::CHANGE_CONFIG | ServiceAccess::START,
+            )
+            .map_err(|error| anyhow!("Unable to create the service: {}", error))?;
+
+        service
+            .set_description("Shuts down the computer in response to an external signal.")
+            .map_err(|error| anyhow!("Unable to set the service description: {}", error))?;
+
+        service
+            .start::<&str>(&[])
+            .map_err(|error| anyhow!("Unable to start the service: {}", error))?;
+
+        println!("Installed and started the {SERVICE_NAME} service");
+
+        Ok(())
+    }
+
+    pub fn uninstall(&self) -> Result<()> {
+        use windows_service::service::{ServiceAccess, ServiceState};
+        use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+        if !self.is_live_system() {
+            return Err(anyhow!(
+                "Installing into another root isn't supported on Windows"
+            ));
+        }
+
+        let manager =
+            ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+                .map_err(|error| anyhow!("Unable to connect to the service manager: {}", error))?;
+
+        let service = manager
+            .open_service(
+                SERVICE_NAME,
+                ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+            )
+            .map_err(|error| anyhow!("Unable to open the service: {}", error))?;
+
+        let status = service
+            .query_status()
+            .map_err(|error| anyhow!("Unable to query the service: {}", error))?;
+
+        if status.current_state != ServiceState::Stopped {
+            // The service is deleted once it stops, so a failure here isn't fatal
+            let _ = service.stop();
+        }
+
+        service
+            .delete()
+            .map_err(|error| anyhow!("Unable to delete the service: {}", error))?;
+
+        println!("Removed the {SERVICE_NAME} service");
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::configuration::DEFAULT_SECRET;
+    use crate::configuration_storage::AppConfigurationStorage;
+    use crate::configuration_storage::TomlFile;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    const EXECUTABLE: &str = "/opt/shutdown-on-lan/bin/shutdown-on-lan";
+
+    fn installer(root: &TempDir) -> ServiceInstaller {
+        ServiceInstaller::new(root.path().to_path_buf(), PathBuf::from(EXECUTABLE))
+    }
+
+    #[test]
+    fn installing_writes_the_unit_and_a_default_configuration() {
+        let root = TempDir::new().unwrap();
+        installer(&root).install().unwrap();
+
+        let unit = fs::read_to_string(
+            root.path()
+                .join("etc/systemd/system/shutdown-on-lan.service"),
+        )
+        .unwrap();
+        assert!(unit.contains(&format!("ExecStart={EXECUTABLE} ")));
+
+        let path = root.path().join("etc/shutdown-on-lan.toml");
+        let configuration = TomlFile::new(&path).fetch().unwrap();
+        assert_ne!(configuration.secret, DEFAULT_SECRET);
+        assert_eq!(configuration.secret.len(), 32);
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o600);
+    }
+
+    #[test]
+    fn installing_again_keeps_the_configuration() {
+        let root = TempDir::new().unwrap();
+        installer(&root).install().unwrap();
+
+        let storage = TomlFile::new(root.path().join("etc/shutdown-on-lan.toml"));
+        let configuration = storage.fetch().unwrap();
+
+        installer(&root).install().unwrap();
+
+        assert_eq!(storage.fetch().unwrap(), configuration);
+    }
+
+    #[test]
+    fn uninstalling_removes_the_units_and_keeps_the_configuration() {
+        let root = TempDir::new().unwrap();
+        installer(&root).install().unwrap();
+
+        let socket_unit = root
+            .path()
+            .join("etc/systemd/system/shutdown-on-lan.socket");
+        fs::write(&socket_unit, crate::systemd::socket_unit(53632)).unwrap();
+
+        installer(&root).uninstall().unwrap();
+
+        assert!(!root
+            .path()
+            .join("etc/systemd/system/shutdown-on-lan.service")
+            .exists());
+        assert!(!socket_unit.exists());
+        assert!(root.path().join("etc/shutdown-on-lan.toml").exists());
+    }
+
+    #[test]
+    fn uninstalling_what_was_never_installed_succeeds() {
+        let root = TempDir::new().unwrap();
+
+        installer(&root).uninstall().unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::configuration::AppConfiguration;
+use crate::power::PowerAction;
+
+/// What the running listener is doing right now – reported through the control channel by
+/// `shutdown-on-lan status`.
+pub struct DaemonStatus {
+    started_at: Instant,
+    configuration: Mutex<AppConfiguration>,
+    bound_sockets: Mutex<Vec<SocketAddr>>,
+    scheduled_runs: Mutex<BTreeMap<usize, ScheduledRun>>,
+    pending_action: Mutex<Option<PendingAction>>,
+
+    open_connections: AtomicU64,
+    connections_accepted: AtomicU64,
+    connections_rejected: AtomicU64,
+    authentication_successes: AtomicU64,
+    authentication_failures: AtomicU64,
+    actions_performed: AtomicU64,
+    actions_failed: AtomicU64,
+    actions_rejected: AtomicU64,
+}
+
+/// A snapshot of `DaemonStatus`, as sent over the control channel.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatusReport {
+    pub process_id: u32,
+    pub version: String,
+    pub uptime_seconds: u64,
+    pub bound_sockets: Vec<SocketAddr>,
+    pub open_connections: u64,
+    pub counters: StatusCounters,
+
+    /// When each of the scheduler's entries will next run
+    #[serde(default)]
+    pub scheduled_runs: Vec<ScheduledRun>,
+
+    /// The remote action counting down, if there is one
+    #[serde(default)]
+    pub pending_action: Option<PendingAction>,
+
+    /// The configuration the daemon is running with, with the secret redacted
+    pub configuration: AppConfiguration,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatusCounters {
+    pub connections_accepted: u64,
+    pub connections_rejected: u64,
+    pub authentication_successes: u64,
+    pub authentication_failures: u64,
+    pub actions_performed: u64,
+    pub actions_failed: u64,
+
+    /// Actions refused because an inhibitor check failed
+    #[serde(default)]
+    pub actions_rejected: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduledRun {
+    pub name: String,
+    pub action: PowerAction,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingAction {
+    pub action: PowerAction,
+    pub at: DateTime<Utc>,
+    pub postpones: u32,
+}
+
+/// Counts an open connection for as long as it's alive
+pub struct ConnectionGuard<'a> {
+    status: &'a DaemonStatus,
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.status.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl DaemonStatus {
+    pub fn new(configuration: &AppConfiguration) -> DaemonStatus {
+        DaemonStatus {
+            started_at: Instant::now(),
+            configuration: Mutex::new(configuration.redacted()),
+            bound_sockets: Mutex::new(Vec::new()),
+            scheduled_runs: Mutex::new(BTreeMap::new()),
+            pending_action: Mutex::new(None),
+            open_connections: AtomicU64::new(0),
+            connections_accepted: AtomicU64::new(0),
+            connections_rejected: AtomicU64::new(0),
+            authentication_successes: AtomicU64::new(0),
+            authentication_failures: AtomicU64::new(0),
+            actions_performed: AtomicU64::new(0),
+            actions_failed: AtomicU64::new(0),
+            actions_rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_bound_socket(&self, address: SocketAddr) {
+        self.bound_sockets
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .push(address);
+    }
+
+    /// The listening sockets were replaced, after the port changed
+    pub fn replace_bound_sockets(&self, addresses: Vec<SocketAddr>) {
+        *self
+            .bound_sockets
+            .lock()
+            .unwrap_or_else(|error| error.into_inner()) = addresses;
+    }
+
+    /// The configuration was reloaded
+    pub fn record_configuration(&self, configuration: &AppConfiguration) {
+        *self
+            .configuration
+            .lock()
+            .unwrap_or_else(|error| error.into_inner()) = configuration.redacted();
+    }
+
+    pub fn record_connection_accepted(&self) -> ConnectionGuard<'_> {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { status: self }
+    }
+
+    pub fn record_connection_rejected(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_authentication(&self, success: bool) {
+        let counter = if success {
+            &self.authentication_successes
+        } else {
+            &self.authentication_failures
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_action(&self, success: bool) {
+        let counter = if success {
+            &self.actions_performed
+        } else {
+            &self.actions_failed
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_action_rejected(&self) {
+        self.actions_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records when the scheduler's `index`th entry will next run
+    pub fn record_scheduled_run(
+        &self,
+        index: usize,
+        name: String,
+        action: PowerAction,
+        at: DateTime<Utc>,
+    ) {
+        self.scheduled_runs
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .insert(index, ScheduledRun { name, action, at });
+    }
+
+    pub fn clear_scheduled_run(&self, index: usize) {
+        self.scheduled_runs
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .remove(&index);
+    }
+
+    /// Records when the action counting down will happen
+    pub fn record_pending_action(&self, action: PowerAction, at: DateTime<Utc>, postpones: u32) {
+        *self
+            .pending_action
+            .lock()
+            .unwrap_or_else(|error| error.into_inner()) = Some(PendingAction {
+            action,
+            at,
+            postpones,
+        });
+    }
+
+    pub fn clear_pending_action(&self) {
+        *self
+            .pending_action
+            .lock()
+            .unwrap_or_else(|error| error.into_inner()) = None;
+    }
+
+    pub fn report(&self) -> StatusReport {
+        StatusReport {
+            process_id: std::process::id(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_seconds: self.started_at.elapsed().as_secs(),
+            bound_sockets: self
+                .bound_sockets
+                .lock()
+                .unwrap_or_else(|error| error.into_inner())
+                .clone(),
+            open_connections: self.open_connections.load(Ordering::Relaxed),
+            counters: StatusCounters {
+                connections_accepted: self.connections_accepted.load(Ordering::Relaxed),
+                connections_rejected: self.connections_rejected.load(Ordering::Relaxed),
+                authentication_successes: self.authentication_successes.load(Ordering::Relaxed),
+                authentication_failures: self.authentication_failures.load(Ordering::Relaxed),
+                actions_performed: self.actions_performed.load(Ordering::Relaxed),
+                actions_failed: self.actions_failed.load(Ordering::Relaxed),
+                actions_rejected: self.actions_rejected.load(Ordering::Relaxed),
+            },
+            scheduled_runs: self
+                .scheduled_runs
+                .lock()
+                .unwrap_or_else(|error| error.into_inner())
+                .values()
+                .cloned()
+                .collect(),
+            pending_action: self
+                .pending_action
+                .lock()
+                .unwrap_or_else(|error| error.into_inner())
+                .clone(),
+            configuration: self
+                .configuration
+                .lock()
+                .unwrap_or_else(|error| error.into_inner())
+                .clone(),
+        }
+    }
+}
+
+impl StatusReport {
+    /// Prints the report for a human
+    pub fn print(&self) {
+        println!(
+            "shutdown-on-lan {} is running (pid {})",
+            self.version, self.process_id
+        );
+        println!(
+            "Uptime: {}",
+            format_duration(Duration::from_secs(self.uptime_seconds))
+        );
+
+        let sockets: Vec<String> = self.bound_sockets.iter().map(|s| s.to_string()).collect();
+        println!("Listening on: {}", sockets.join(", "));
+        println!("Open connections: {}", self.open_connections);
+
+        println!(
+            "Connections: {} accepted, {} rejected",
+            self.counters.connections_accepted, self.counters.connections_rejected
+        );
+        println!(
+            "Authentication: {} succeeded, {} failed",
+            self.counters.authentication_successes, self.counters.authentication_failures
+        );
+        println!(
+            "Actions: {} performed, {} failed, {} rejected",
+            self.counters.actions_performed,
+            self.counters.actions_failed,
+            self.counters.actions_rejected
+        );
+
+        for run in &self.scheduled_runs {
+            println!(
+                "Scheduled: {} ({}) at {}",
+                run.action,
+                run.name,
+                run.at
+                    .with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M %Z")
+            );
+        }
+
+        if let Some(pending) = &self.pending_action {
+            println!(
+                "Pending: {} at {} (postponed: {})",
+                pending.action,
+                pending
+                    .at
+                    .with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M:%S %Z"),
+                pending.postpones
+            );
+        }
+
+        println!("Configuration:");
+        match serde_json::to_string_pretty(&self.configuration) {
+            Ok(configuration) => {
+                for line in configuration.lines() {
+                    println!("  {line}");
+                }
+            }
+            Err(error) => println!("  Unable to display the configuration: {error}"),
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (days, hours, minutes, seconds) = (
+        seconds / 86400,
+        (seconds % 86400) / 3600,
+        (seconds % 3600) / 60,
+        seconds % 60,
+    );
+
+    if days > 0 {
+        format!("{days}d {hours}h {minutes}m {seconds}s")
+    } else if hours > 0 {
+        format!("{hours}h {minutes}m {seconds}s")
+    } else if minutes > 0 {
+        format!("{minutes}m {seconds}s")
+    } else {
+        format!("{seconds}s")
+    }
+}
@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use systemstat::{Platform, System};
+
+/// A snapshot of the machine's state, for a controller deciding whether it's a good time to shut
+/// it down. Anything the platform can't report is left out.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SystemInfo {
+    pub hostname: Option<String>,
+    pub uptime_seconds: Option<u64>,
+    pub load_average: Option<LoadAverage>,
+
+    /// The names of the users with a session open, each listed once
+    pub logged_in_users: Option<Vec<String>>,
+
+    pub on_ac_power: Option<bool>,
+    pub battery: Option<Battery>,
+    pub cpu_temperature_celsius: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LoadAverage {
+    pub one: f32,
+    pub five: f32,
+    pub fifteen: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Battery {
+    /// From 0 to 100
+    pub remaining_percent: f32,
+    pub remaining_seconds: u64,
+}
+
+/// How much the information for an `INFO` request is shared
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct InfoConfiguration {
+    pub enabled: bool,
+
+    /// Whether clients have to send the secret along with `INFO`
+    pub require_secret: bool,
+}
+
+impl Default for InfoConfiguration {
+    fn default() -> Self {
+        InfoConfiguration {
+            enabled: true,
+            require_secret: true,
+        }
+    }
+}
+
+impl SystemInfo {
+    pub fn collect() -> SystemInfo {
+        let system = System::new();
+
+        SystemInfo {
+            hostname: whoami::fallible::hostname().ok(),
+            uptime_seconds: system.uptime().ok().map(|uptime| uptime.as_secs()),
+            load_average: system.load_average().ok().map(|load| LoadAverage {
+                one: load.one,
+                five: load.five,
+                fifteen: load.fifteen,
+            }),
+            logged_in_users: logged_in_users(),
+            on_ac_power: system.on_ac_power().ok(),
+            battery: system.battery_life().ok().map(|battery| Battery {
+                remaining_percent: battery.remaining_capacity * 100.0,
+                remaining_seconds: battery.remaining_time.as_secs(),
+            }),
+            cpu_temperature_celsius: system.cpu_temp().ok(),
+        }
+    }
+}
+
+/// The users with a session open, according to `who` (or `query user` on Windows). `None` if
+/// the command couldn't be run, or failed.
+pub fn logged_in_users() -> Option<Vec<String>> {
+    #[cfg(windows)]
+    let output = Command::new("query").arg("user").output().ok()?;
+
+    #[cfg(not(windows))]
+    let output = Command::new("who").output().ok()?;
+
+    // `query user` fails when nobody's logged in, but `who` only fails when it can't tell
+    if cfg!(not(windows)) && !output.status.success() {
+        return None;
+    }
+
+    let output = String::from_utf8_lossy(&output.stdout);
+
+    // `query user` starts with a header row, and marks the current session with `>`
+    let lines = output.lines().skip(if cfg!(windows) { 1 } else { 0 });
+
+    let mut users: Vec<String> = lines
+        .filter_map(|line| line.split_whitespace().next())
+        .map(|user| user.trim_start_matches('>').to_string())
+        .collect();
+
+    users.sort();
+    users.dedup();
+
+    Some(users)
+}
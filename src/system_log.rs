@@ -0,0 +1,532 @@
+use serde::{Deserialize, Serialize};
+use simplelog::{Config, LevelFilter, SharedLogger};
+use std::io;
+use std::net::UdpSocket;
+use std::str::FromStr;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use crate::logging::key_values;
+
+const APP_NAME: &str = "shutdown-on-lan";
+
+/// Forwards the log to the operating system's logging service so it ends up in persistent
+/// storage even when nobody is capturing the terminal.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "target", rename_all = "lowercase")]
+pub enum SystemLogConfiguration {
+    /// Send records to the systemd journal using its native protocol, with structured fields
+    /// such as `PEER_ADDR` and `ACTION` attached.
+    Journald {
+        #[serde(default = "default_journald_socket")]
+        socket: String,
+    },
+
+    /// Send RFC 5424 records to a syslog daemon. `address` is either `unix:<path>` or
+    /// `udp:<host>:<port>`.
+    Syslog {
+        #[serde(default = "default_syslog_address")]
+        address: String,
+
+        #[serde(default = "default_syslog_facility")]
+        facility: String,
+    },
+}
+
+/// Parses the `--system-log` flag: `journald`, `syslog`, or `syslog:<address>`
+impl FromStr for SystemLogConfiguration {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        match string.split_once(':') {
+            None if string == "journald" => Ok(SystemLogConfiguration::Journald {
+                socket: default_journald_socket(),
+            }),
+            None if string == "syslog" => Ok(SystemLogConfiguration::Syslog {
+                address: default_syslog_address(),
+                facility: default_syslog_facility(),
+            }),
+            Some(("syslog", address)) => Ok(SystemLogConfiguration::Syslog {
+                address: address.to_string(),
+                facility: default_syslog_facility(),
+            }),
+            _ => Err(format!(
+                "Unknown system log {string:?} – expected `journald`, `syslog` or `syslog:<address>`"
+            )),
+        }
+    }
+}
+
+fn default_journald_socket() -> String {
+    "/run/systemd/journal/socket".to_string()
+}
+
+#[cfg(target_os = "linux")]
+fn default_syslog_address() -> String {
+    "unix:/dev/log".to_string()
+}
+
+#[cfg(target_os = "macos")]
+fn default_syslog_address() -> String {
+    "unix:/var/run/syslog".to_string()
+}
+
+#[cfg(windows)]
+fn default_syslog_address() -> String {
+    "udp:127.0.0.1:514".to_string()
+}
+
+fn default_syslog_facility() -> String {
+    "daemon".to_string()
+}
+
+/// Creates the logger described by `configuration`.
+pub fn logger(
+    configuration: &SystemLogConfiguration,
+    level: LevelFilter,
+) -> io::Result<Box<dyn SharedLogger>> {
+    match configuration {
+        SystemLogConfiguration::Journald { socket } => journald_logger(socket, level),
+        SystemLogConfiguration::Syslog { address, facility } => {
+            let facility = facility
+                .parse::<SyslogFacility>()
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+
+            Ok(Box::new(SyslogLogger {
+                level,
+                facility,
+                hostname: whoami::fallible::hostname().unwrap_or_else(|_| "-".to_string()),
+                transport: SyslogTransport::connect(address)?,
+            }))
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn journald_logger(socket: &str, level: LevelFilter) -> io::Result<Box<dyn SharedLogger>> {
+    let connection = UnixDatagram::unbound()?;
+    connection.connect(socket)?;
+
+    Ok(Box::new(JournaldLogger { level, connection }))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn journald_logger(_socket: &str, _level: LevelFilter) -> io::Result<Box<dyn SharedLogger>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "journald is only available on Linux",
+    ))
+}
+
+/// The syslog severity for a log level
+fn severity(level: log::Level) -> u8 {
+    match level {
+        log::Level::Error => 3,
+        log::Level::Warn => 4,
+        log::Level::Info => 6,
+        log::Level::Debug | log::Level::Trace => 7,
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct JournaldLogger {
+    level: LevelFilter,
+    connection: UnixDatagram,
+}
+
+#[cfg(target_os = "linux")]
+impl JournaldLogger {
+    fn append_field(buffer: &mut Vec<u8>, name: &str, value: &str) {
+        buffer.extend_from_slice(name.as_bytes());
+
+        // Values containing newlines need the length-prefixed binary form
+        if value.contains('\n') {
+            buffer.push(b'\n');
+            buffer.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            buffer.push(b'=');
+        }
+
+        buffer.extend_from_slice(value.as_bytes());
+        buffer.push(b'\n');
+    }
+
+    /// Journal field names may only contain uppercase letters, digits and underscores, and can't
+    /// start with an underscore (those are reserved for trusted fields).
+    fn field_name(key: &str) -> String {
+        let name: String = key
+            .chars()
+            .map(|character| match character {
+                'a'..='z' => character.to_ascii_uppercase(),
+                'A'..='Z' | '0'..='9' => character,
+                _ => '_',
+            })
+            .collect();
+
+        name.trim_start_matches('_').to_string()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl log::Log for JournaldLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut buffer = Vec::new();
+
+        Self::append_field(&mut buffer, "MESSAGE", &record.args().to_string());
+        Self::append_field(
+            &mut buffer,
+            "PRIORITY",
+            &severity(record.level()).to_string(),
+        );
+        Self::append_field(&mut buffer, "SYSLOG_IDENTIFIER", APP_NAME);
+        Self::append_field(&mut buffer, "TARGET", record.target());
+
+        if let Some(file) = record.file() {
+            Self::append_field(&mut buffer, "CODE_FILE", file);
+        }
+
+        if let Some(line) = record.line() {
+            Self::append_field(&mut buffer, "CODE_LINE", &line.to_string());
+        }
+
+        for (key, value) in key_values(record) {
+            let name = Self::field_name(&key);
+
+            if !name.is_empty() {
+                Self::append_field(&mut buffer, &name, &value);
+            }
+        }
+
+        // There's nowhere left to report a failure to write a log line, so it's dropped
+        let _ = self.connection.send(&buffer);
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(target_os = "linux")]
+impl SharedLogger for JournaldLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn log::Log> {
+        Box::new(*self)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SyslogFacility(u8);
+
+impl FromStr for SyslogFacility {
+    type Err = String;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let code = match string.to_ascii_lowercase().as_str() {
+            "user" => 1,
+            "daemon" => 3,
+            "auth" => 4,
+            "syslog" => 5,
+            "authpriv" => 10,
+            "local0" => 16,
+            "local1" => 17,
+            "local2" => 18,
+            "local3" => 19,
+            "local4" => 20,
+            "local5" => 21,
+            "local6" => 22,
+            "local7" => 23,
+            _ => return Err(format!("Unknown syslog facility {string:?}")),
+        };
+
+        Ok(SyslogFacility(code))
+    }
+}
+
+enum SyslogTransport {
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+}
+
+impl SyslogTransport {
+    fn connect(address: &str) -> io::Result<SyslogTransport> {
+        let invalid_address = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid syslog address {address:?} – expected `unix:<path>` or `udp:<host>:<port>`"),
+            )
+        };
+
+        match address.split_once(':').ok_or_else(invalid_address)? {
+            #[cfg(unix)]
+            ("unix", path) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(PathBuf::from(path))?;
+                Ok(SyslogTransport::Unix(socket))
+            }
+            ("udp", destination) => {
+                let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+                socket.connect(destination)?;
+                Ok(SyslogTransport::Udp(socket))
+            }
+            _ => Err(invalid_address()),
+        }
+    }
+
+    fn send(&self, message: &[u8]) -> io::Result<usize> {
+        match self {
+            #[cfg(unix)]
+            SyslogTransport::Unix(socket) => socket.send(message),
+            SyslogTransport::Udp(socket) => socket.send(message),
+        }
+    }
+}
+
+struct SyslogLogger {
+    level: LevelFilter,
+    facility: SyslogFacility,
+    hostname: String,
+    transport: SyslogTransport,
+}
+
+impl SyslogLogger {
+    /// Formats `record` as an RFC 5424 message, with any structured fields in an SD-ELEMENT
+    fn format(&self, record: &log::Record) -> String {
+        let priority = self.facility.0 * 8 + severity(record.level());
+
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_else(|_| "-".to_string());
+
+        let fields = key_values(record);
+
+        let structured_data = if fields.is_empty() {
+            "-".to_string()
+        } else {
+            let parameters: Vec<String> = fields
+                .iter()
+                .map(|(key, value)| format!("{}=\"{}\"", key, Self::escape(value)))
+                .collect();
+
+            // 32473 is the private enterprise number reserved for documentation and examples
+            format!("[fields@32473 {}]", parameters.join(" "))
+        };
+
+        format!(
+            "<{}>1 {} {} {} {} - {} {}",
+            priority,
+            timestamp,
+            self.hostname,
+            APP_NAME,
+            std::process::id(),
+            structured_data,
+            record.args()
+        )
+    }
+
+    fn escape(value: &str) -> String {
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace(']', "\\]")
+    }
+}
+
+impl log::Log for SyslogLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // There's nowhere left to report a failure to write a log line, so it's dropped
+        let _ = self.transport.send(self.format(record).as_bytes());
+    }
+
+    fn flush(&self) {}
+}
+
+impl SharedLogger for SyslogLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn log::Log> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[cfg(unix)]
+    use tempfile::TempDir;
+
+    fn syslog(address: String, facility: &str) -> Box<dyn SharedLogger> {
+        let configuration = SystemLogConfiguration::Syslog {
+            address,
+            facility: facility.to_string(),
+        };
+
+        logger(&configuration, LevelFilter::Info).unwrap()
+    }
+
+    /// Logs a warning with a couple of structured fields
+    fn log_warning(logger: &dyn SharedLogger) {
+        let fields: &[(&str, &str)] = &[
+            ("peer_addr", "10.0.1.5:40000"),
+            ("reason", "a \"quoted\" ]"),
+        ];
+
+        logger.log(
+            &log::Record::builder()
+                .args(format_args!("Refusing to shutdown"))
+                .level(log::Level::Warn)
+                .target("shutdown_on_lan::listener_service")
+                .key_values(&fields)
+                .build(),
+        );
+    }
+
+    fn log_debug(logger: &dyn SharedLogger) {
+        logger.log(
+            &log::Record::builder()
+                .args(format_args!("Too detailed"))
+                .level(log::Level::Debug)
+                .build(),
+        );
+    }
+
+    /// Checks that `message` is the RFC 5424 framing of the warning from `log_warning`
+    fn assert_framed(message: &str, priority: u8) {
+        let mut parts = message.splitn(7, ' ');
+
+        assert_eq!(parts.next(), Some(format!("<{priority}>1").as_str()));
+
+        let timestamp = parts.next().unwrap();
+        assert!(
+            OffsetDateTime::parse(timestamp, &Rfc3339).is_ok(),
+            "{}",
+            timestamp
+        );
+
+        assert!(!parts.next().unwrap().is_empty());
+        assert_eq!(parts.next(), Some(APP_NAME));
+        assert_eq!(parts.next(), Some(std::process::id().to_string().as_str()));
+        assert_eq!(parts.next(), Some("-"));
+        assert_eq!(
+            parts.next(),
+            Some("[fields@32473 peer_addr=\"10.0.1.5:40000\" reason=\"a \\\"quoted\\\" \\]\"] Refusing to shutdown")
+        );
+    }
+
+    #[test]
+    fn priorities_combine_the_facility_and_severity() {
+        assert_eq!(
+            "local3".parse::<SyslogFacility>().unwrap().0 * 8 + severity(log::Level::Warn),
+            156
+        );
+        assert!("nonsense".parse::<SyslogFacility>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn records_are_sent_to_unix_sockets() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("log");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let logger = syslog(format!("unix:{}", path.display()), "local3");
+        log_debug(logger.as_ref());
+        log_warning(logger.as_ref());
+
+        let mut buffer = [0; 4096];
+        let length = socket.recv(&mut buffer).unwrap();
+
+        assert_framed(std::str::from_utf8(&buffer[..length]).unwrap(), 156);
+    }
+
+    #[test]
+    fn records_are_sent_over_udp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let logger = syslog(format!("udp:{}", socket.local_addr().unwrap()), "daemon");
+        log_debug(logger.as_ref());
+        log_warning(logger.as_ref());
+
+        let mut buffer = [0; 4096];
+        let length = socket.recv(&mut buffer).unwrap();
+
+        assert_framed(std::str::from_utf8(&buffer[..length]).unwrap(), 28);
+    }
+
+    #[test]
+    fn addresses_need_a_transport() {
+        let configuration = SystemLogConfiguration::Syslog {
+            address: "/dev/log".to_string(),
+            facility: default_syslog_facility(),
+        };
+
+        assert!(logger(&configuration, LevelFilter::Info).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn journal_records_carry_the_structured_fields() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("journal");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let configuration = SystemLogConfiguration::Journald {
+            socket: path.display().to_string(),
+        };
+        let logger = logger(&configuration, LevelFilter::Info).unwrap();
+        log_warning(logger.as_ref());
+
+        let mut buffer = [0; 4096];
+        let length = socket.recv(&mut buffer).unwrap();
+        let message = std::str::from_utf8(&buffer[..length]).unwrap();
+
+        assert!(message.contains("MESSAGE=Refusing to shutdown\n"));
+        assert!(message.contains("PRIORITY=4\n"));
+        assert!(message.contains("SYSLOG_IDENTIFIER=shutdown-on-lan\n"));
+        assert!(message.contains("PEER_ADDR=10.0.1.5:40000\n"));
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "linux")]
+use std::os::unix::io::FromRawFd;
+
+#[cfg(target_os = "linux")]
+use std::os::unix::net::UnixDatagram;
+
+const SERVICE_UNIT_TEMPLATE: &str = include_str!("../build/linux/shutdown-on-lan.service");
+const SOCKET_UNIT_TEMPLATE: &str = include_str!("../build/linux/shutdown-on-lan.socket");
+
+/// The path that the bundled unit files expect the binary to be installed at
+const TEMPLATE_EXECUTABLE_PATH: &str = "/usr/local/bin/shutdown-on-lan";
+const TEMPLATE_PORT: &str = "ListenStream=53632";
+
+/// The first file descriptor passed by systemd (`SD_LISTEN_FDS_START`)
+#[cfg(target_os = "linux")]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Adopts any sockets passed to us by systemd socket activation (see `sd_listen_fds(3)`).
+///
+/// Returns an empty list if the process wasn't socket-activated.
+#[cfg(target_os = "linux")]
+pub fn listen_fds() -> Vec<TcpListener> {
+    let pid_matches = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .map(|pid| pid == std::process::id())
+        .unwrap_or(false);
+
+    if !pid_matches {
+        return Vec::new();
+    }
+
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<i32>().ok())
+        .unwrap_or(0);
+
+    // Don't let these leak into any child processes
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    log::info!("Adopting {} socket(s) from systemd", count);
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        // Safety: systemd hands these descriptors to us and nothing else in the process owns them
+        .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn listen_fds() -> Vec<TcpListener> {
+    Vec::new()
+}
+
+/// Tell systemd that the listener is up and accepting connections
+pub fn notify_ready() {
+    notify("READY=1\nSTATUS=Listening for shutdown requests");
+}
+
+/// Tell systemd that the configuration is being reloaded. `notify_ready` says when it's done.
+pub fn notify_reloading() {
+    notify("RELOADING=1");
+}
+
+/// Tell systemd that we're on our way out
+pub fn notify_stopping() {
+    notify("STOPPING=1");
+}
+
+/// How often the listener's long-running loops beat their heartbeat, even when there's nothing
+/// for them to do
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When each of the listener's long-running loops (accepting connections, reloading the
+/// configuration) last showed it was still working
+#[derive(Default)]
+pub struct Heartbeats {
+    beats: Mutex<HashMap<&'static str, Instant>>,
+}
+
+impl Heartbeats {
+    pub fn beat(&self, name: &'static str) {
+        self.beats
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .insert(name, Instant::now());
+    }
+
+    /// The loops that haven't beaten for longer than `limit`, as of `now`. Loops that have never
+    /// beaten aren't running, so they're not counted.
+    pub fn stale(&self, now: Instant, limit: Duration) -> Vec<&'static str> {
+        let beats = self.beats.lock().unwrap_or_else(|error| error.into_inner());
+
+        let mut stale: Vec<&'static str> = beats
+            .iter()
+            .filter(|(_, &beat)| now.saturating_duration_since(beat) > limit)
+            .map(|(&name, _)| name)
+            .collect();
+
+        stale.sort_unstable();
+        stale
+    }
+}
+
+/// If the unit has `WatchdogSec=` set, ping the watchdog at half the requested interval – but
+/// only while every loop in `heartbeats` is still beating, so that systemd restarts us if one of
+/// them dies or the process wedges.
+pub fn spawn_watchdog(heartbeats: Arc<Heartbeats>) {
+    let interval = match watchdog_interval() {
+        Some(interval) => interval,
+        None => return,
+    };
+
+    let period = interval / 2;
+    log::debug!("Pinging the systemd watchdog every {:?}", period);
+
+    thread::spawn(move || {
+        let mut responding = true;
+
+        loop {
+            let stale = heartbeats.stale(Instant::now(), period + HEARTBEAT_INTERVAL);
+
+            if stale.is_empty() {
+                if !responding {
+                    log::info!("The listener is responding again");
+                }
+
+                notify("WATCHDOG=1");
+            } else if responding {
+                log::error!(
+                    "Not pinging the systemd watchdog, so that the service is restarted – {} stopped responding",
+                    stale.join(" and ")
+                );
+            }
+
+            responding = stale.is_empty();
+            thread::sleep(period);
+        }
+    });
+}
+
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+
+    let microseconds = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+
+    match microseconds {
+        0 => None,
+        microseconds => Some(Duration::from_micros(microseconds)),
+    }
+}
+
+/// Sends `state` to the service manager (see `sd_notify(3)`). Does nothing if we weren't
+/// started by systemd.
+#[cfg(target_os = "linux")]
+fn notify(state: &str) {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+
+    let address = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(&path),
+    };
+
+    let result = address.and_then(|address| {
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &address)
+    });
+
+    if let Err(error) = result {
+        log::warn!("Unable to notify systemd at {:?}: {}", path, error);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn notify(_state: &str) {}
+
+/// A systemd service unit that runs `executable`
+pub fn service_unit(executable: &Path) -> String {
+    SERVICE_UNIT_TEMPLATE.replace(TEMPLATE_EXECUTABLE_PATH, &executable.to_string_lossy())
+}
+
+/// A systemd socket unit that listens on `port` and activates the service on demand
+pub fn socket_unit(port: u16) -> String {
+    SOCKET_UNIT_TEMPLATE.replace(TEMPLATE_PORT, &format!("ListenStream={port}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_heartbeats_arent_stale() {
+        let heartbeats = Heartbeats::default();
+        heartbeats.beat("accepting connections");
+
+        assert!(heartbeats
+            .stale(Instant::now(), Duration::from_secs(15))
+            .is_empty());
+    }
+
+    #[test]
+    fn old_heartbeats_are_stale() {
+        let heartbeats = Heartbeats::default();
+        heartbeats.beat("accepting connections");
+        heartbeats.beat("reloading");
+
+        // Reloading beat again since, but accepting connections didn't
+        let later = Instant::now() + Duration::from_secs(20);
+        heartbeats.beats.lock().unwrap().insert("reloading", later);
+
+        assert_eq!(
+            heartbeats.stale(later, Duration::from_secs(15)),
+            vec!["accepting connections"]
+        );
+    }
+
+    #[test]
+    fn loops_that_never_beat_arent_counted() {
+        assert!(Heartbeats::default()
+            .stale(Instant::now(), Duration::ZERO)
+            .is_empty());
+    }
+}
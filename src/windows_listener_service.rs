@@ -2,7 +2,8 @@
 pub mod shutdown_on_lan_service {
     extern crate windows_service;
 
-    use crate::{configuration::AppConfiguration, listener_service};
+    use crate::configuration::ConfigurationError;
+    use crate::{configuration_storage, configuration_validation, listener_service, reload};
 
     use std::{ffi::OsString, sync::mpsc, thread, time::Duration};
 
@@ -54,6 +55,12 @@ pub mod shutdown_on_lan_service {
                 // control manager. Always return NoError even if not implemented.
                 ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
 
+                // Reload the configuration, as SIGHUP does elsewhere
+                ServiceControl::ParamChange => {
+                    reload::request();
+                    ServiceControlHandlerResult::NoError
+                }
+
                 // Handle stop
                 ServiceControl::Stop => {
                     shutdown_tx.send(()).unwrap();
@@ -72,14 +79,43 @@ pub mod shutdown_on_lan_service {
         status_handle.set_service_status(ServiceStatus {
             service_type: SERVICE_TYPE,
             current_state: ServiceState::Running,
-            controls_accepted: ServiceControlAccept::STOP,
+            controls_accepted: ServiceControlAccept::STOP | ServiceControlAccept::PARAM_CHANGE,
             exit_code: ServiceExitCode::Win32(0),
             checkpoint: 0,
             wait_hint: Duration::default(),
             process_id: None,
         })?;
 
-        let config = AppConfiguration::fetch().unwrap();
+        let config = configuration_validation::load(configuration_storage::storage().as_ref())
+            .and_then(|config| {
+                configuration_validation::check_default_secret(&config).map(|_| config)
+            });
+
+        let config = match config {
+            Ok(config) => config,
+            Err(error) => {
+                match error {
+                    ConfigurationError::DefaultSecret => log::error!(
+                        "{}: {}",
+                        configuration_validation::REFUSING_DEFAULT_SECRET,
+                        error
+                    ),
+                    _ => log::error!("Unable to start the listener: {}", error),
+                }
+
+                status_handle.set_service_status(ServiceStatus {
+                    service_type: SERVICE_TYPE,
+                    current_state: ServiceState::Stopped,
+                    controls_accepted: ServiceControlAccept::empty(),
+                    exit_code: ServiceExitCode::ServiceSpecific(error.exit_code() as u32),
+                    checkpoint: 0,
+                    wait_hint: Duration::default(),
+                    process_id: None,
+                })?;
+
+                return Ok(());
+            }
+        };
 
         log::info!("Forking listener service thread");
         thread::spawn(move || {